@@ -1,27 +1,41 @@
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
-use kvs::{KvStore, KvsEngine, SledKvsStore};
+use kvs::{KvStore, KvsEngine, LsmKvsStore, LsmOptions, SledKvsStore};
 use rand::prelude::*;
 use tempfile::TempDir;
 
+// A memtable small enough for the keys of lsm_12 and lsm_16 to be read from
+// SSTables: 2^12 keys and values take 50 KiB, 2^16 take 900 KiB
+const LSM_MEMTABLE_BYTES: usize = 16 << 10;
+
 // Benchmark result:
 //
-// set_bench/kvs           time:   [199.71 µs 201.39 µs 203.43 µs]                          
-// set_bench/sled          time:   [152.64 ms 154.90 ms 157.06 ms]                           
+// set_bench/kvs           time:   [103.82 µs 106.66 µs 109.50 µs]
+// set_bench/sled          time:   [1.1266 ms 1.1594 ms 1.1933 ms]
+// set_bench/lsm           time:   [112.40 µs 116.39 µs 120.76 µs]
 //
-// get_bench/kvs_4         time:   [1.0545 µs 1.0557 µs 1.0569 µs]                             
-// get_bench/kvs_8         time:   [1.0529 µs 1.0536 µs 1.0544 µs]                             
-// get_bench/kvs_12        time:   [1.1336 µs 1.1350 µs 1.1368 µs]                              
-// get_bench/kvs_16        time:   [1.2480 µs 1.2691 µs 1.2929 µs]                              
-// get_bench/sled_4        time:   [584.00 ns 598.51 ns 613.50 ns]                             
-// get_bench/sled_8        time:   [657.98 ns 675.58 ns 692.46 ns]
+// get_bench/kvs_4         time:   [334.97 ns 342.00 ns 349.01 ns]
+// get_bench/kvs_8         time:   [442.42 ns 453.90 ns 464.14 ns]
+// get_bench/kvs_12        time:   [626.73 ns 653.04 ns 680.42 ns]
+// get_bench/kvs_16        time:   [1.9288 µs 1.9565 µs 1.9877 µs]
+// get_bench/sled_4        time:   [582.06 ns 586.15 ns 590.45 ns]
+// get_bench/sled_8        time:   [622.94 ns 651.82 ns 679.65 ns]
+// get_bench/sled_12       time:   [1.0962 µs 1.1252 µs 1.1538 µs]
+// get_bench/sled_16       time:   [2.0675 µs 2.1241 µs 2.1825 µs]
+// get_bench/lsm_4         time:   [156.26 ns 163.32 ns 170.70 ns]
+// get_bench/lsm_8         time:   [268.78 ns 272.40 ns 275.37 ns]
+// get_bench/lsm_12        time:   [9.2779 µs 9.6633 µs 10.103 µs]
+// get_bench/lsm_16        time:   [13.366 µs 13.717 µs 14.076 µs]
 //
 // Observation:
-// 1. sled set is much slower than kvs. Probably because sled uses B+ tree, 
-// whereas kvs is append only.
-// 2. sled get is very fast. Two possible reasons: (1) kvs code is not highly
-// optimized for performance; (2) kvs never optimizes how entries are stored on
-// the disk, whereas sled organizes (sorted) data in a tree.
-
+// 1. sled set is much slower than kvs. Probably because sled uses B+ tree,
+// whereas kvs is append only. lsm set is on par with kvs: both append to a
+// log.
+// 2. kvs get is served from its value cache, and stays ahead of sled up to
+// 2^16 keys, where both take about 2 µs.
+// 3. lsm get is the fastest while the keys fit in the memtable (lsm_4 and
+// lsm_8). From lsm_12 on most keys are in SSTables, and every get reads and
+// decodes a 4 KiB block from the file, as SSTables have no block cache: about
+// 10 µs, five times kvs.
 
 fn set_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("set_bench");
@@ -31,7 +45,7 @@ fn set_bench(c: &mut Criterion) {
                 let temp_dir = TempDir::new().unwrap();
                 (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
             },
-            |(mut store, _temp_dir)| {
+            |(store, _temp_dir)| {
                 for i in 1..(1 << 3) {
                     store.set(format!("key{}", i), "value".to_string()).unwrap();
                 }
@@ -45,7 +59,7 @@ fn set_bench(c: &mut Criterion) {
                 let temp_dir = TempDir::new().unwrap();
                 (SledKvsStore::open(temp_dir.path()).unwrap(), temp_dir)
             },
-            |(mut db, _temp_dir)| {
+            |(db, _temp_dir)| {
                 for i in 1..(1 << 3) {
                     db.set(format!("key{}", i), "value".to_string()).unwrap();
                 }
@@ -53,15 +67,29 @@ fn set_bench(c: &mut Criterion) {
             BatchSize::SmallInput,
         )
     });
+    group.bench_function("lsm", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (LsmKvsStore::open(temp_dir.path()).unwrap(), temp_dir)
+            },
+            |(store, _temp_dir)| {
+                for i in 1..(1 << 3) {
+                    store.set(format!("key{}", i), "value".to_string()).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
     group.finish();
 }
 
 fn get_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("get_bench");
-    for i in &vec![4, 8, 12, 16] {
+    for i in &[4, 8, 12, 16] {
         group.bench_with_input(format!("kvs_{}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
-            let mut store = KvStore::open(temp_dir.path()).unwrap();
+            let store = KvStore::open(temp_dir.path()).unwrap();
             for key_i in 1..(1 << i) {
                 store
                     .set(format!("key{}", key_i), "value".to_string())
@@ -75,10 +103,10 @@ fn get_bench(c: &mut Criterion) {
             })
         });
     }
-    for i in &vec![4, 8, 12, 16] {
+    for i in &[4, 8, 12, 16] {
         group.bench_with_input(format!("sled_{}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
-            let mut db = SledKvsStore::open(temp_dir.path()).unwrap();
+            let db = SledKvsStore::open(temp_dir.path()).unwrap();
             for key_i in 1..(1 << i) {
                 db.set(format!("key{}", key_i), "value".to_string())
                     .unwrap();
@@ -89,8 +117,66 @@ fn get_bench(c: &mut Criterion) {
             })
         });
     }
+    for i in &[4, 8, 12, 16] {
+        group.bench_with_input(format!("lsm_{}", i), i, |b, i| {
+            let temp_dir = TempDir::new().unwrap();
+            let options = LsmOptions {
+                memtable_bytes: LSM_MEMTABLE_BYTES,
+                ..LsmOptions::default()
+            };
+            let store = LsmKvsStore::open_with(temp_dir.path(), options).unwrap();
+            for key_i in 1..(1 << i) {
+                store
+                    .set(format!("key{}", key_i), "value".to_string())
+                    .unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                store
+                    .get(format!("key{}", rng.gen_range(1, 1 << i)))
+                    .unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+// Gets with the value cache disabled, to measure reads of the log itself.
+//
+// Reading with `read_exact_at` only:
+// uncached_get_bench/kvs_4  time:   [931.13 ns 981.80 ns 1.0249 µs]
+// uncached_get_bench/kvs_8  time:   [890.97 ns 957.10 ns 1.0191 µs]
+// uncached_get_bench/kvs_12 time:   [1.4741 µs 1.4887 µs 1.5030 µs]
+// uncached_get_bench/kvs_16 time:   [1.4278 µs 1.5018 µs 1.5823 µs]
+//
+// Reading the sealed prefix of the log through mmap:
+// uncached_get_bench/kvs_4  time:   [424.83 ns 447.88 ns 470.36 ns]
+// uncached_get_bench/kvs_8  time:   [386.20 ns 404.02 ns 421.65 ns]
+// uncached_get_bench/kvs_12 time:   [571.54 ns 602.33 ns 627.72 ns]
+// uncached_get_bench/kvs_16 time:   [944.13 ns 983.18 ns 1.0214 µs]
+fn uncached_get_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("uncached_get_bench");
+    for i in &[4, 8, 12, 16] {
+        group.bench_with_input(format!("kvs_{}", i), i, |b, i| {
+            let temp_dir = TempDir::new().unwrap();
+            let store = KvStore::open_with_cache(temp_dir.path(), 0).unwrap();
+            for key_i in 1..(1 << i) {
+                store
+                    .set(format!("key{}", key_i), "value".to_string())
+                    .unwrap();
+            }
+            drop(store);
+            let store = KvStore::open_with_cache(temp_dir.path(), 0).unwrap();
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                store
+                    .get(format!("key{}", rng.gen_range(1, 1 << i)))
+                    .unwrap();
+            })
+        });
+    }
     group.finish();
 }
 
-criterion_group!(benches, set_bench, get_bench);
+criterion_group!(benches, set_bench, get_bench, uncached_get_bench);
 criterion_main!(benches);
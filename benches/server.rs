@@ -0,0 +1,149 @@
+use criterion::{
+    criterion_group, criterion_main, measurement::WallTime, BenchmarkGroup, Criterion,
+};
+use kvs::thread_pool::{
+    NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool, WorkStealingThreadPool,
+};
+use kvs::{KvClient, KvServer, KvStore, KvsEngine, Protocol, ServerOptions};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Requests sent by each client per iteration, over its own connection
+const REQUESTS: usize = 100;
+
+// Threads of each pool. A connection takes a thread while open, so there are
+// no more long-lived clients than threads.
+const THREADS: u32 = 8;
+
+// Connections opened by each client per iteration, with one request each
+const CONNECTIONS: usize = 20;
+
+// Benchmark result:
+//
+// server_get_bench/naive_1           time:   [1.9348 ms 2.0146 ms 2.0990 ms]
+// server_get_bench/naive_4           time:   [9.7483 ms 10.051 ms 10.352 ms]
+// server_get_bench/naive_8           time:   [23.169 ms 23.915 ms 24.647 ms]
+// server_get_bench/shared_queue_1    time:   [2.0991 ms 2.1706 ms 2.2439 ms]
+// server_get_bench/shared_queue_4    time:   [10.356 ms 10.528 ms 10.711 ms]
+// server_get_bench/shared_queue_8    time:   [16.454 ms 17.100 ms 17.776 ms]
+// server_get_bench/rayon_1           time:   [1.8670 ms 1.9316 ms 1.9992 ms]
+// server_get_bench/rayon_4           time:   [8.4333 ms 8.6253 ms 8.8288 ms]
+// server_get_bench/rayon_8           time:   [18.451 ms 18.924 ms 19.414 ms]
+// server_get_bench/work_stealing_1   time:   [2.1041 ms 2.1893 ms 2.2807 ms]
+// server_get_bench/work_stealing_4   time:   [8.7862 ms 9.0976 ms 9.4174 ms]
+// server_get_bench/work_stealing_8   time:   [22.661 ms 22.847 ms 23.042 ms]
+//
+// Observation:
+// 1. A get takes about 20 µs over loopback whatever the pool, as each pool
+// only hands a connection to a thread once.
+// 2. With more clients, the time is spent waiting for the store and the
+// network, not in the pool. The differences at 8 clients are within what
+// the scheduling of 8 server threads and 8 client threads varies by.
+//
+// server_connect_bench/naive_1           time:   [2.3296 ms 2.4288 ms 2.5401 ms]
+// server_connect_bench/naive_8           time:   [24.218 ms 24.936 ms 25.663 ms]
+// server_connect_bench/naive_32          time:   [132.86 ms 136.62 ms 140.33 ms]
+// server_connect_bench/shared_queue_1    time:   [2.1008 ms 2.1580 ms 2.2156 ms]
+// server_connect_bench/shared_queue_8    time:   [15.423 ms 15.894 ms 16.369 ms]
+// server_connect_bench/shared_queue_32   time:   [63.022 ms 65.309 ms 67.581 ms]
+// server_connect_bench/rayon_1           time:   [1.8316 ms 1.8873 ms 1.9419 ms]
+// server_connect_bench/rayon_8           time:   [15.473 ms 15.641 ms 15.792 ms]
+// server_connect_bench/rayon_32          time:   [65.712 ms 67.814 ms 69.870 ms]
+// server_connect_bench/work_stealing_1   time:   [1.8524 ms 1.9074 ms 1.9616 ms]
+// server_connect_bench/work_stealing_8   time:   [14.626 ms 15.043 ms 15.464 ms]
+// server_connect_bench/work_stealing_32  time:   [69.013 ms 71.250 ms 73.555 ms]
+//
+// 3. With a connection per request, the naive pool spawns a thread per
+// connection, which makes it the slowest from 8 clients on: twice as slow
+// as the others at 32. The pools with fixed threads are within 10% of each
+// other, so the default shared queue costs nothing against rayon or work
+// stealing.
+
+// Gets of `REQUESTS` keys by 1, 4 and 8 clients at once, against a
+// `KvServer` on each thread pool.
+fn server_get_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("server_get_bench");
+    bench_pool::<NaiveThreadPool>(&mut group, "naive");
+    bench_pool::<SharedQueueThreadPool>(&mut group, "shared_queue");
+    bench_pool::<RayonThreadPool>(&mut group, "rayon");
+    bench_pool::<WorkStealingThreadPool>(&mut group, "work_stealing");
+    group.finish();
+}
+
+fn bench_pool<P: ThreadPool + Send + 'static>(group: &mut BenchmarkGroup<WallTime>, name: &str) {
+    for clients in [1, 4, 8] {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        for i in 0..REQUESTS {
+            store.set(format!("key{}", i), "value".to_string()).unwrap();
+        }
+        let pool = P::new(THREADS).unwrap();
+        let handle = KvServer::start(store, pool, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut clients: Vec<KvClient> = (0..clients)
+            .map(|_| KvClient::new(handle.local_addr()).unwrap())
+            .collect();
+
+        group.bench_function(format!("{}_{}", name, clients.len()), |b| {
+            b.iter(|| {
+                thread::scope(|scope| {
+                    for client in clients.iter_mut() {
+                        scope.spawn(move || {
+                            for i in 0..REQUESTS {
+                                client.get(format!("key{}", i)).unwrap();
+                            }
+                        });
+                    }
+                })
+            })
+        });
+
+        drop(clients);
+        handle.shutdown(Duration::from_secs(1)).unwrap();
+    }
+}
+
+// Gets over a new connection each, by 1, 8 and 32 clients at once. Each
+// connection is a job of the pool, and with 32 clients they queue for its
+// threads. Unix domain sockets leave no ports in TIME_WAIT after so many
+// connections.
+fn server_connect_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("server_connect_bench");
+    bench_connect::<NaiveThreadPool>(&mut group, "naive");
+    bench_connect::<SharedQueueThreadPool>(&mut group, "shared_queue");
+    bench_connect::<RayonThreadPool>(&mut group, "rayon");
+    bench_connect::<WorkStealingThreadPool>(&mut group, "work_stealing");
+    group.finish();
+}
+
+fn bench_connect<P: ThreadPool + Send + 'static>(group: &mut BenchmarkGroup<WallTime>, name: &str) {
+    for clients in [1, 8, 32] {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key".to_owned(), "value".to_owned()).unwrap();
+        let path = temp_dir.path().join("kvs.sock");
+        let pool = P::new(THREADS).unwrap();
+        let handle = KvServer::start_unix(store, pool, &path, ServerOptions::default()).unwrap();
+
+        group.bench_function(format!("{}_{}", name, clients), |b| {
+            b.iter(|| {
+                thread::scope(|scope| {
+                    for _ in 0..clients {
+                        scope.spawn(|| {
+                            for _ in 0..CONNECTIONS {
+                                let mut client =
+                                    KvClient::connect_unix(&path, Protocol::Binary).unwrap();
+                                client.get("key".to_owned()).unwrap();
+                            }
+                        });
+                    }
+                })
+            })
+        });
+
+        handle.shutdown(Duration::from_secs(1)).unwrap();
+    }
+}
+
+criterion_group!(benches, server_get_bench, server_connect_bench);
+criterion_main!(benches);
@@ -0,0 +1,140 @@
+use crate::message::{ErrorResponse, JsonReader, Request};
+use crate::protocol::{self, Frame, Hello};
+use crate::{KvStoreError, Result};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::oneshot,
+    task::JoinHandle,
+};
+
+/// A client that queries the KvStore server on the tokio runtime. It has the
+/// same API as `KvClient`, returning futures, and speaks the binary protocol.
+///
+/// Requests are pipelined: calls may run concurrently, and each future
+/// resolves when the response to its request arrives.
+pub struct AsyncKvClient {
+    writer: tokio::sync::Mutex<OwnedWriteHalf>,
+    next_id: AtomicU64,
+    waiting: Arc<Mutex<Waiting>>,
+    read_task: JoinHandle<()>,
+}
+
+// Requests waiting for their response. `None` once the connection is closed.
+type Waiting = Option<HashMap<u64, oneshot::Sender<Frame>>>;
+
+impl AsyncKvClient {
+    /// creates a new client ready to send queries
+    pub async fn new(addr: SocketAddr) -> Result<Self> {
+        let (reader, mut writer) = TcpStream::connect(addr).await?.into_split();
+        let mut reader = BufReader::new(reader);
+        Self::handshake(&mut reader, &mut writer).await?;
+
+        let waiting = Arc::new(Mutex::new(Some(HashMap::new())));
+        let read_task = tokio::spawn(Self::read_responses(reader, waiting.clone()));
+        Ok(AsyncKvClient {
+            writer: tokio::sync::Mutex::new(writer),
+            next_id: AtomicU64::new(1),
+            waiting,
+            read_task,
+        })
+    }
+
+    /// get
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        match self.request(Request::Get { key }).await? {
+            Frame::Value(value) => Ok(value),
+            Frame::Error(err) => Err(err),
+            _ => Err(KvStoreError::InvalidFrame),
+        }
+    }
+
+    /// set
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        match self.request(Request::Set { key, value }).await? {
+            Frame::Done => Ok(()),
+            Frame::Error(err) => Err(err),
+            _ => Err(KvStoreError::InvalidFrame),
+        }
+    }
+
+    /// remove
+    pub async fn remove(&self, key: String) -> Result<()> {
+        match self.request(Request::Remove { key }).await? {
+            Frame::Done => Ok(()),
+            Frame::Error(err) => Err(err),
+            _ => Err(KvStoreError::InvalidFrame),
+        }
+    }
+
+    // as `protocol::connect`
+    async fn handshake(
+        reader: &mut BufReader<OwnedReadHalf>,
+        writer: &mut OwnedWriteHalf,
+    ) -> Result<()> {
+        let mut hello = protocol::MAGIC.to_vec();
+        hello.extend(Frame::Hello(Hello::new()).encode(0)?);
+        writer.write_all(&hello).await?;
+        if reader.fill_buf().await?.first() == Some(&b'{') {
+            return match JsonReader::new(reader).next().await? {
+                Some(ErrorResponse::Err(err)) => Err(err),
+                None => Err(KvStoreError::IoError),
+            };
+        }
+        match Frame::read_async(reader).await? {
+            (_, Frame::Welcome(_)) => Ok(()),
+            (_, Frame::Error(err)) => Err(err),
+            _ => Err(KvStoreError::InvalidFrame),
+        }
+    }
+
+    // Hand each response to the request waiting for it, until the connection
+    // closes
+    async fn read_responses(mut reader: BufReader<OwnedReadHalf>, waiting: Arc<Mutex<Waiting>>) {
+        while let Ok((id, frame)) = Frame::read_async(&mut reader).await {
+            let mut waiting = waiting.lock().unwrap();
+            let waiting = waiting.as_mut().unwrap();
+            if let Frame::Error(err) = frame {
+                if id == 0 {
+                    // about a request that could not be parsed
+                    for (_, tx) in waiting.drain() {
+                        let _ = tx.send(Frame::Error(err));
+                    }
+                    continue;
+                }
+            }
+            if let Some(tx) = waiting.remove(&id) {
+                let _ = tx.send(frame);
+            }
+        }
+        // dropping the senders fails the requests still waiting
+        waiting.lock().unwrap().take();
+    }
+
+    async fn request(&self, req: Request) -> Result<Frame> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        match self.waiting.lock().unwrap().as_mut() {
+            Some(waiting) => waiting.insert(id, tx),
+            None => return Err(KvStoreError::IoError),
+        };
+        let buf = Frame::Request(req).encode(id)?;
+        self.writer.lock().await.write_all(&buf).await?;
+        rx.await.map_err(|_| KvStoreError::IoError)
+    }
+}
+
+impl Drop for AsyncKvClient {
+    fn drop(&mut self) {
+        self.read_task.abort();
+    }
+}
@@ -0,0 +1,279 @@
+use crate::{
+    message::{ErrorResponse, JsonReader, Request, Response},
+    protocol::{self, Frame},
+    KvStoreError, KvsEngine, Result,
+};
+use std::{collections::HashMap, future::Future, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot, oneshot::error::TryRecvError, watch, Semaphore},
+    task::JoinSet,
+};
+use tracing::{debug, warn};
+
+// Bounds of the pause after a failed accept, as in `KvServer`
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+// Requests of a pipelining connection in flight, and responses waiting to
+// be written
+const PIPELINE_DEPTH: usize = 64;
+
+/// A server that serves requests against a `KvsEngine` on the tokio runtime.
+///
+/// Every connection is a task, so idle connections hold no thread. Engine
+/// calls block, and run on tokio's blocking thread pool.
+#[derive(Clone, Debug)]
+pub struct AsyncKvServer<E: KvsEngine> {
+    engine: E,
+    // true once the server shuts down
+    shutdown: watch::Receiver<bool>,
+}
+
+impl<E: KvsEngine> AsyncKvServer<E> {
+    /// serve requests on `addr`. Fails with `KvStoreError::BindError` if
+    /// `addr` cannot be listened on, and otherwise runs forever.
+    pub async fn serve(engine: E, addr: SocketAddr) -> Result<()> {
+        Self::serve_with_shutdown(engine, addr, std::future::pending(), Duration::ZERO).await
+    }
+
+    /// serve requests on `addr` until `signal` completes. Then stop
+    /// accepting connections, and let the requests in flight finish for up
+    /// to `timeout`. Then close the remaining connections, and flush the
+    /// engine.
+    pub async fn serve_with_shutdown<F>(
+        engine: E,
+        addr: SocketAddr,
+        signal: F,
+        timeout: Duration,
+    ) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let server = AsyncKvServer {
+            engine,
+            shutdown: shutdown_rx,
+        };
+        let listener = TcpListener::bind(addr).await.map_err(|err| {
+            warn!("failed to bind {}: {}", addr, err);
+            KvStoreError::BindError
+        })?;
+        let mut connections = JoinSet::new();
+        tokio::pin!(signal);
+        let mut backoff = MIN_ACCEPT_BACKOFF;
+        loop {
+            // forget the connections that closed
+            while connections.try_join_next().is_some() {}
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                () = &mut signal => break,
+            };
+            let (stream, peer) = match accepted {
+                Ok(accepted) => {
+                    backoff = MIN_ACCEPT_BACKOFF;
+                    accepted
+                }
+                Err(err) => {
+                    warn!("accept failed: {}, retrying in {:?}", err, backoff);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                    continue;
+                }
+            };
+            let server = server.clone();
+            connections.spawn(async move {
+                if let Err(err) = server.handle_connection(stream).await {
+                    debug!("connection from {} closed: {}", peer, err);
+                }
+            });
+        }
+
+        // Idle connections close at once, the others after their requests
+        drop(listener);
+        let _ = shutdown.send(true);
+        let drained = tokio::time::timeout(timeout, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            connections.shutdown().await;
+        }
+        let engine = server.engine.clone();
+        tokio::task::spawn_blocking(move || engine.flush())
+            .await
+            .unwrap_or(Err(KvStoreError::IoError))
+    }
+
+    async fn handle_connection(mut self, stream: TcpStream) -> Result<()> {
+        // The first bytes of a connection tell its protocol
+        let mut first = [0u8; 1];
+        let peeked = tokio::select! {
+            peeked = stream.peek(&mut first) => peeked?,
+            _ = self.shutdown.wait_for(|shutdown| *shutdown) => 0,
+        };
+        if peeked == 0 {
+            return Ok(());
+        }
+        if first[0] == protocol::MAGIC[0] {
+            self.serve_binary(stream).await
+        } else {
+            self.serve_json(stream).await
+        }
+    }
+
+    async fn serve_json(mut self, mut stream: TcpStream) -> Result<()> {
+        let (reader, writer) = stream.split();
+        let mut req_reader = JsonReader::new(reader);
+        let mut resp_writer = BufWriter::new(writer);
+
+        loop {
+            // Reading is cancelled at shutdown, unless a request has started
+            let next = tokio::select! {
+                next = req_reader.next::<Request>() => Some(next),
+                _ = self.shutdown.wait_for(|shutdown| *shutdown) => None,
+            };
+            let next = match next {
+                Some(next) => next,
+                None if req_reader.is_idle() => break,
+                None => req_reader.next::<Request>().await,
+            };
+            let req = match next {
+                Ok(Some(req)) => req,
+                Ok(None) => break,
+                Err(KvStoreError::SerdeError) => {
+                    warn!("invalid request");
+                    let resp = ErrorResponse::Err(KvStoreError::InvalidRequest);
+                    resp_writer.write_all(&serde_json::to_vec(&resp)?).await?;
+                    resp_writer.flush().await?;
+                    return Err(KvStoreError::InvalidRequest);
+                }
+                Err(err) => return Err(err),
+            };
+            let resp = Self::apply(self.engine.clone(), req).await;
+            resp_writer.write_all(&serde_json::to_vec(&resp)?).await?;
+            resp_writer.flush().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn serve_binary(mut self, stream: TcpStream) -> Result<()> {
+        let (reader, writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic).await?;
+        let resp = match magic == protocol::MAGIC {
+            true => match Frame::read_async(&mut reader).await {
+                Ok((_, frame)) => protocol::answer_hello(frame),
+                Err(KvStoreError::InvalidFrame) => Frame::Error(KvStoreError::InvalidFrame),
+                Err(err) => return Err(err),
+            },
+            false => Frame::Error(KvStoreError::InvalidFrame),
+        };
+        writer.write_all(&resp.encode(0)?).await?;
+        writer.flush().await?;
+        let pipelined = match resp {
+            Frame::Welcome(welcome) => welcome.features.iter().any(|f| f == protocol::PIPELINING),
+            Frame::Error(err) => return Err(err),
+            _ => unreachable!(),
+        };
+
+        // One task writes the responses, in the order they are ready
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(PIPELINE_DEPTH);
+        let write_task = tokio::spawn(async move {
+            while let Some(buf) = rx.recv().await {
+                writer.write_all(&buf).await?;
+                if rx.is_empty() {
+                    writer.flush().await?;
+                }
+            }
+            Ok::<_, KvStoreError>(())
+        });
+
+        // The last request on each key, which the next one on the key waits
+        // for, so that they apply in the order they were sent. The sender is
+        // dropped when the request is answered.
+        let mut last: HashMap<String, oneshot::Receiver<()>> = HashMap::new();
+
+        // At most `PIPELINE_DEPTH` requests are in flight, and the next frame
+        // is read once one of them is answered
+        let permits = Arc::new(Semaphore::new(PIPELINE_DEPTH));
+
+        // EOF between frames closes the connection, and so does shutdown
+        loop {
+            let permit = permits.clone().acquire_owned().await.expect("never closed");
+            if !Self::wait_for_request(&mut reader, &mut self.shutdown).await? {
+                break;
+            }
+            let (id, req) = match Frame::read_async(&mut reader).await {
+                Ok((id, Frame::Request(req))) => (id, req),
+                Ok(_) | Err(KvStoreError::InvalidFrame) => {
+                    warn!("invalid request");
+                    let resp = Frame::Error(KvStoreError::InvalidFrame);
+                    let _ = tx.send(resp.encode(0)?).await;
+                    return Err(KvStoreError::InvalidFrame);
+                }
+                Err(err) => return Err(err),
+            };
+            let (engine, tx) = (self.engine.clone(), tx.clone());
+            let (done, previous) = match req.key() {
+                Some(key) if pipelined => {
+                    // forget the keys whose requests are all answered
+                    if last.len() >= PIPELINE_DEPTH {
+                        last.retain(|_, answered| {
+                            matches!(answered.try_recv(), Err(TryRecvError::Empty))
+                        });
+                    }
+                    let (done, answered) = oneshot::channel::<()>();
+                    (Some(done), last.insert(key.to_owned(), answered))
+                }
+                _ => (None, None),
+            };
+            let respond = async move {
+                let _permit = permit;
+                let _done = done;
+                if let Some(previous) = previous {
+                    let _ = previous.await;
+                }
+                let resp = Frame::from(Self::apply(engine, req).await);
+                let _ = tx.send(resp.encode(id)?).await;
+                Ok::<_, KvStoreError>(())
+            };
+            if pipelined {
+                tokio::spawn(respond);
+            } else {
+                respond.await?;
+            }
+        }
+
+        drop(tx);
+        write_task.await.expect("write task panicked")
+    }
+
+    // Wait for the first bytes of a request. False at EOF, or at shutdown.
+    async fn wait_for_request<R>(
+        reader: &mut R,
+        shutdown: &mut watch::Receiver<bool>,
+    ) -> Result<bool>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        tokio::select! {
+            buf = reader.fill_buf() => Ok(!buf?.is_empty()),
+            _ = shutdown.wait_for(|shutdown| *shutdown) => Ok(false),
+        }
+    }
+
+    // `self` is not `Sync`, so it cannot be borrowed across an await. An
+    // engine call that panics is answered with an error.
+    async fn apply(engine: E, req: Request) -> Response {
+        let failed = req.error(KvStoreError::IoError);
+        tokio::task::spawn_blocking(move || req.apply(&engine))
+            .await
+            .unwrap_or(failed)
+    }
+}
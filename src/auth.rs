@@ -0,0 +1,303 @@
+// Authentication of clients, and authorization of their requests by key
+// prefix.
+//
+// The principals are configured in a TOML file:
+//
+//     [[token]]
+//     principal = "backup"
+//     token = "..."
+//
+//     [[user]]
+//     name = "alice"
+//     password = "$argon2id$v=19$..."
+//
+//     [[grant]]
+//     principal = "alice"
+//     prefix = "users/alice/"
+//     access = "read-write"
+//
+// Users are principals of their own name. A principal may only access the
+// keys under the prefixes granted to it, and the empty prefix grants every
+// key. A token or user with `admin = true` makes its principal an
+// administrator, which may also send admin requests.
+
+use crate::{Credentials, EngineStats, KvStoreError, KvsEngine, Result};
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, SaltString};
+use argon2::{Argon2, PasswordHasher, PasswordVerifier};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::warn;
+
+// The hash of an empty password with the default parameters of
+// `Auth::hash_password`, verified when a user is unknown
+const DUMMY_HASH: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$HH9vAyQ1BwDbv7JOL4qMMw$/sHrTtO3UtyiffGRbBjTKHqFPO12XqBQveEehdWj1mw";
+
+/// What a grant allows on the keys under its prefix
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Access {
+    /// get and scan
+    Read,
+    /// set and remove
+    Write,
+    /// both
+    ReadWrite,
+}
+
+/// The principals allowed to connect to `KvServer`, and the key prefixes
+/// they may access
+#[derive(Clone, Debug, Default)]
+pub struct Auth {
+    // token, and the principal it authenticates
+    tokens: Vec<(String, Arc<Principal>)>,
+    // password hash of each user, in the PHC string format
+    users: HashMap<String, (String, Arc<Principal>)>,
+}
+
+// An authenticated client, and what it may access
+#[derive(Debug, Default)]
+pub(crate) struct Principal {
+    name: String,
+    grants: Vec<(String, Access)>,
+    admin: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AuthConfig {
+    #[serde(default)]
+    token: Vec<TokenConfig>,
+    #[serde(default)]
+    user: Vec<UserConfig>,
+    #[serde(default)]
+    grant: Vec<GrantConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TokenConfig {
+    principal: String,
+    token: String,
+    #[serde(default)]
+    admin: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserConfig {
+    name: String,
+    password: String,
+    #[serde(default)]
+    admin: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GrantConfig {
+    principal: String,
+    prefix: String,
+    access: Access,
+}
+
+impl Auth {
+    /// Read the principals from a TOML file. Fails with
+    /// `KvStoreError::SerdeError` if it is malformed, or holds an invalid
+    /// password hash.
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::from_toml(&std::fs::read_to_string(path)?)
+    }
+
+    /// parse the principals from TOML
+    pub fn from_toml(s: &str) -> Result<Self> {
+        let config: AuthConfig = toml::from_str(s).map_err(|err| {
+            warn!("invalid auth config: {}", err);
+            KvStoreError::SerdeError
+        })?;
+
+        let mut principals: HashMap<String, Principal> = HashMap::new();
+        let names = config.token.iter().map(|t| (&t.principal, t.admin));
+        for (name, admin) in names.chain(config.user.iter().map(|u| (&u.name, u.admin))) {
+            let principal = principals.entry(name.clone()).or_insert_with(|| Principal {
+                name: name.clone(),
+                ..Principal::default()
+            });
+            principal.admin |= admin;
+        }
+        for grant in config.grant {
+            match principals.get_mut(&grant.principal) {
+                Some(principal) => principal.grants.push((grant.prefix, grant.access)),
+                None => warn!("grant to unknown principal {}", grant.principal),
+            }
+        }
+        for user in config.user.iter() {
+            if PasswordHash::new(&user.password).is_err() {
+                warn!("invalid password hash for {}", user.name);
+                return Err(KvStoreError::SerdeError);
+            }
+        }
+
+        let principals: HashMap<String, Arc<Principal>> = principals
+            .into_iter()
+            .map(|(name, principal)| (name, Arc::new(principal)))
+            .collect();
+        Ok(Auth {
+            tokens: config
+                .token
+                .into_iter()
+                .map(|t| (t.token, principals[&t.principal].clone()))
+                .collect(),
+            users: config
+                .user
+                .into_iter()
+                .map(|u| {
+                    let principal = principals[&u.name].clone();
+                    (u.name, (u.password, principal))
+                })
+                .collect(),
+        })
+    }
+
+    /// Hash a password for the `password` of a user, with Argon2id and a
+    /// random salt
+    pub fn hash_password(password: &str) -> Result<String> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|_| KvStoreError::SerdeError)?;
+        Ok(hash.to_string())
+    }
+
+    // The principal `credentials` authenticate. Fails with
+    // `PermissionDenied` if they are wrong.
+    pub(crate) fn authenticate(&self, credentials: &Credentials) -> Result<Arc<Principal>> {
+        let principal = match credentials {
+            Credentials::Token(token) => {
+                // compared in constant time, so that timing does not tell
+                // how much of a token is right
+                let mut found = None;
+                for (candidate, principal) in self.tokens.iter() {
+                    if constant_time_eq(candidate.as_bytes(), token.as_bytes()) {
+                        found = Some(principal.clone());
+                    }
+                }
+                found
+            }
+            Credentials::Password { user, password } => {
+                // an unknown user is verified against a dummy hash, so that
+                // timing does not tell which users exist
+                let (hash, principal) = match self.users.get(user) {
+                    Some((hash, principal)) => (hash.as_str(), Some(principal)),
+                    None => (DUMMY_HASH, None),
+                };
+                let hash = PasswordHash::new(hash).expect("hashes are checked when loaded");
+                let verified = Argon2::default().verify_password(password.as_bytes(), &hash);
+                principal.filter(|_| verified.is_ok()).cloned()
+            }
+        };
+        principal.ok_or_else(|| {
+            warn!("authentication failed");
+            KvStoreError::PermissionDenied
+        })
+    }
+}
+
+impl Principal {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn can_read(&self, key: &str) -> bool {
+        self.grants
+            .iter()
+            .any(|(prefix, access)| *access != Access::Write && key.starts_with(prefix.as_str()))
+    }
+
+    fn can_write(&self, key: &str) -> bool {
+        self.grants
+            .iter()
+            .any(|(prefix, access)| *access != Access::Read && key.starts_with(prefix.as_str()))
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// A `KvsEngine` restricted to the keys a principal was granted. Without a
+/// principal, every key is accessible. Scans skip the keys that cannot be
+/// read.
+#[derive(Clone, Debug)]
+pub(crate) struct Authorized<E: KvsEngine> {
+    engine: E,
+    principal: Option<Arc<Principal>>,
+}
+
+impl<E: KvsEngine> Authorized<E> {
+    pub fn new(engine: E, principal: Option<Arc<Principal>>) -> Self {
+        Authorized { engine, principal }
+    }
+
+    // the engine, unrestricted
+    pub fn inner(&self) -> &E {
+        &self.engine
+    }
+
+    // Fails with `PermissionDenied` unless the principal is an administrator
+    pub fn check_admin(&self) -> Result<()> {
+        self.check(|p| p.admin)
+    }
+
+    fn check(&self, allowed: impl FnOnce(&Principal) -> bool) -> Result<()> {
+        match &self.principal {
+            Some(principal) if !allowed(principal) => Err(KvStoreError::PermissionDenied),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for Authorized<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.check(|p| p.can_write(&key))?;
+        self.engine.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.check(|p| p.can_read(&key))?;
+        self.engine.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.check(|p| p.can_write(&key))?;
+        self.engine.remove(key)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn scan<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        match &self.principal {
+            Some(principal) => self
+                .engine
+                .scan(|key, value| match principal.can_read(&key) {
+                    true => f(key, value),
+                    false => Ok(()),
+                }),
+            None => self.engine.scan(f),
+        }
+    }
+
+    fn stats(&self) -> EngineStats {
+        self.engine.stats()
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.engine.compact()
+    }
+}
@@ -0,0 +1,128 @@
+use clap::{Parser, Subcommand};
+use kvs::{
+    dump::{self, DumpFormat},
+    migrate, EngineKind, KvStore, KvStoreError, LsmKvsStore, MemoryKvsStore, Result, SledKvsStore,
+};
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::PathBuf,
+};
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)] // Read from Cargo.toml
+#[clap(propagate_version = true)]
+struct Args {
+    /// Data directory of the store. Defaults to the current directory.
+    #[clap(long, global = true, value_parser)]
+    dir: Option<PathBuf>,
+
+    /// Storage engine: 'kvs', 'sled' or 'lsm'. Defaults to the engine recorded in
+    /// the data directory.
+    #[clap(long, global = true, value_parser)]
+    engine: Option<EngineKind>,
+
+    #[clap(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Write every key/value pair to a file or stdout
+    Dump {
+        /// Dump format: 'json' or 'binary'
+        #[clap(long, value_parser, default_value = "json")]
+        format: DumpFormat,
+        /// Output file. Defaults to stdout.
+        #[clap(long, short, value_parser)]
+        output: Option<PathBuf>,
+    },
+    /// Set every key/value pair read from a file or stdin
+    Load {
+        /// Dump format: 'json' or 'binary'
+        #[clap(long, value_parser, default_value = "json")]
+        format: DumpFormat,
+        /// Input file. Defaults to stdin.
+        #[clap(long, short, value_parser)]
+        input: Option<PathBuf>,
+    },
+    /// Copy every key/value pair into another engine, and hand the data
+    /// directory over to it. Resumes an interrupted migration.
+    Migrate {
+        /// Engine to migrate to: 'kvs', 'sled' or 'lsm'
+        #[clap(long, value_parser)]
+        to: EngineKind,
+    },
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let dir = match args.dir {
+        Some(dir) => dir,
+        None => std::env::current_dir()?,
+    };
+
+    // check engine
+    let marked = EngineKind::current(dir.as_path())?;
+    let engine = match (marked, args.engine) {
+        (Some(prev_engine), Some(engine)) if prev_engine != engine => {
+            return Err(KvStoreError::WrongEngine)
+        }
+        (Some(engine), _) => engine,
+        (None, Some(engine)) if !engine.is_persistent() => return Err(KvStoreError::WrongEngine),
+        (None, engine) => engine.unwrap_or(EngineKind::Kvs),
+    };
+    // a dump only reads the directory, and does not take it over
+    let dump = matches!(args.command, Commands::Dump { .. });
+    if marked.is_none() && !dump {
+        engine.write_marker(dir.as_path())?;
+    }
+
+    match args.command {
+        Commands::Dump { format, output } => {
+            // Opening an engine would create its files in a directory it does
+            // not own. An empty one holds no pairs, and any other is refused.
+            let empty = match marked {
+                Some(_) => false,
+                None if fs::read_dir(&dir)?.next().is_none() => true,
+                None => return Err(KvStoreError::WrongEngine),
+            };
+            let writer: Box<dyn Write> = match output {
+                Some(path) => Box::new(fs::File::create(path)?),
+                None => Box::new(io::stdout()),
+            };
+            let count = match engine {
+                _ if empty => dump::dump(&MemoryKvsStore::new(), writer, format),
+                EngineKind::Kvs => dump::dump(&KvStore::open(&dir)?, writer, format),
+                EngineKind::Sled => dump::dump(&SledKvsStore::open(&dir)?, writer, format),
+                EngineKind::Lsm => dump::dump(&LsmKvsStore::open(&dir)?, writer, format),
+                // the directory of a memory store holds nothing to open
+                EngineKind::Memory => Err(KvStoreError::WrongEngine),
+            }?;
+            eprintln!("dumped {} keys", count);
+        }
+        Commands::Load { format, input } => {
+            let reader: Box<dyn Read> = match input {
+                Some(path) => Box::new(fs::File::open(path)?),
+                None => Box::new(io::stdin()),
+            };
+            let count = match engine {
+                EngineKind::Kvs => dump::load(&KvStore::open(&dir)?, reader, format),
+                EngineKind::Sled => dump::load(&SledKvsStore::open(&dir)?, reader, format),
+                EngineKind::Lsm => dump::load(&LsmKvsStore::open(&dir)?, reader, format),
+                EngineKind::Memory => Err(KvStoreError::WrongEngine),
+            }?;
+            eprintln!("loaded {} keys", count);
+        }
+        Commands::Migrate { to } => match migrate::migrate(&dir, to)? {
+            Some(summary) => eprintln!(
+                "migrated {} keys from {} to {}, checksum {:016x}",
+                summary.count, engine, to, summary.checksum
+            ),
+            None => eprintln!("already using {}", to),
+        },
+    }
+
+    Ok(())
+}
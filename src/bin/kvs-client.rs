@@ -1,6 +1,6 @@
 use clap::{Parser, Subcommand};
-use kvs::{KvClient, Result};
-use std::net::SocketAddr;
+use kvs::{ClientTls, Credentials, KvClient, KvStoreError, Protocol, Result, ServerStats};
+use std::{fs, net::SocketAddr, path::PathBuf};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)] // Read from Cargo.toml
@@ -14,21 +14,112 @@ struct Args {
     )]
     addr: SocketAddr,
 
+    /// Connect to the Unix domain socket at this path instead of `--addr`
+    #[clap(
+        long,
+        global = true,
+        value_name = "PATH",
+        value_parser,
+        conflicts_with = "ca"
+    )]
+    unix: Option<PathBuf>,
+
+    /// Wire protocol: 'binary', or 'json' for debugging
+    #[clap(long, global = true, value_parser, default_value = "binary")]
+    protocol: Protocol,
+
+    /// Connect over TLS, trusting servers signed by this PEM CA
+    #[clap(long, global = true, value_parser)]
+    ca: Option<PathBuf>,
+
+    /// PEM certificate chain presented to servers requiring mutual TLS
+    #[clap(long, global = true, value_parser, requires_all = &["ca", "cert-key"])]
+    cert: Option<PathBuf>,
+
+    /// PEM private key of `--cert`
+    #[clap(
+        name = "cert-key",
+        long = "key",
+        value_name = "KEY",
+        global = true,
+        value_parser,
+        requires = "cert"
+    )]
+    cert_key: Option<PathBuf>,
+
+    /// Name the server certificate must be for. The IP address of `--addr`
+    /// by default.
+    #[clap(long, global = true, value_parser, requires = "ca")]
+    server_name: Option<String>,
+
+    /// Authenticate with this token
+    #[clap(long, global = true, value_parser)]
+    token: Option<String>,
+
     #[clap(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
-    Get { key: String },
-    Set { key: String, value: String },
-    Rm { key: String },
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Rm {
+        key: String,
+    },
+    /// Inspect and control the server. Requires an administrator token on
+    /// servers requiring authentication, except for `ping`.
+    Admin {
+        #[clap(subcommand)]
+        command: AdminCommands,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AdminCommands {
+    /// Check that the server answers
+    Ping,
+    /// Print the counters of the server and its store
+    Stats,
+    /// Compact the store now
+    Compact,
+    /// Make the writes received so far durable
+    Flush,
+    /// Print the settings, or the one named, or change it until the server
+    /// restarts. Timeouts are in seconds, and 'none' lifts a limit.
+    Config {
+        name: Option<String>,
+        value: Option<String>,
+    },
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
 
-    let mut cli = KvClient::new(args.addr)?;
+    let mut cli = match (&args.unix, &args.ca) {
+        (Some(path), _) => KvClient::connect_unix(path, args.protocol)?,
+        (None, Some(ca)) => {
+            let identity = match (&args.cert, &args.cert_key) {
+                (Some(cert), Some(key)) => Some((fs::read(cert)?, fs::read(key)?)),
+                _ => None,
+            };
+            let identity = identity.as_ref().map(|(c, k)| (c.as_slice(), k.as_slice()));
+            let mut tls = ClientTls::from_pem(&fs::read(ca)?, identity)?;
+            if let Some(name) = &args.server_name {
+                tls = tls.with_server_name(name)?;
+            }
+            KvClient::with_tls(args.addr, args.protocol, &tls)?
+        }
+        (None, None) => KvClient::with_protocol(args.addr, args.protocol)?,
+    };
+    if let Some(token) = &args.token {
+        cli.authenticate(Credentials::Token(token.to_owned()))?;
+    }
 
     match &args.command {
         Commands::Get { key } => {
@@ -40,13 +131,65 @@ fn main() -> Result<()> {
         }
         Commands::Set { key, value } => cli.set(key.to_owned(), value.to_owned()),
         Commands::Rm { key } => match cli.remove(key.to_owned()) {
-            Ok(()) => {
-                Result::Ok(())
-            }
+            Ok(()) => Result::Ok(()),
             Err(err) => {
                 eprintln!("Key not found"); // test requires stderr
-                return Result::Err(err);
+                Result::Err(err)
             }
         },
+        Commands::Admin { command } => admin(&mut cli, command),
+    }
+}
+
+fn admin(cli: &mut KvClient, command: &AdminCommands) -> Result<()> {
+    match command {
+        AdminCommands::Ping => {
+            cli.ping()?;
+            println!("pong");
+        }
+        AdminCommands::Stats => print_stats(&cli.stats()?),
+        AdminCommands::Compact => cli.compact()?,
+        AdminCommands::Flush => cli.flush()?,
+        AdminCommands::Config {
+            name: Some(name),
+            value: Some(value),
+        } => cli.set_config(name.to_owned(), value.to_owned())?,
+        AdminCommands::Config { name, value: None } => {
+            let mut settings = cli.config()?;
+            if let Some(name) = name {
+                settings.retain(|(setting, _)| setting == name);
+                if settings.is_empty() {
+                    eprintln!("unknown setting: {}", name);
+                    return Err(KvStoreError::InvalidRequest);
+                }
+            }
+            for (setting, value) in settings {
+                println!("{} = {}", setting, value);
+            }
+        }
+        AdminCommands::Config { name: None, .. } => unreachable!("a value comes after a name"),
+    }
+    Ok(())
+}
+
+fn print_stats(stats: &ServerStats) {
+    let engine = &stats.engine;
+    let unknown = |n: Option<u64>| n.map_or("unknown".to_owned(), |n| n.to_string());
+    println!("connections: {}", stats.connections);
+    println!("keys: {}", unknown(engine.keys));
+    println!("size: {}", engine.size);
+    println!("live bytes: {}", unknown(engine.live_bytes));
+    println!(
+        "dead bytes: {}",
+        unknown(
+            engine
+                .live_bytes
+                .map(|live| engine.size.saturating_sub(live))
+        )
+    );
+    println!("compactions: {}", engine.compactions);
+    println!("bytes reclaimed: {}", engine.bytes_reclaimed);
+    for segment in engine.segments.iter() {
+        println!("segment {}: {} bytes", segment.name, segment.size);
     }
 }
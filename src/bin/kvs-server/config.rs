@@ -0,0 +1,359 @@
+// The config file of `kvs-server`, named by `--config` or `KVS_CONFIG`:
+//
+//     data-dir = "/var/lib/kvs"
+//     engine = "lsm"
+//     threads = 32
+//
+//     [listen]
+//     addr = "0.0.0.0:4000"
+//     unix = "/run/kvs.sock"
+//     unix-mode = "660"
+//     metrics-addr = "127.0.0.1:9000"
+//
+//     [storage]
+//     flush-interval = 1
+//
+//     [limits]
+//     max-connections = 1000
+//     idle-timeout = 300
+//     quota = ["users/=1000000"]
+//
+//     [log]
+//     level = "info"
+//
+//     # more listeners, each with its own protocol, TLS and authentication
+//     [[listener]]
+//     addr = "127.0.0.1:4001"
+//     protocol = "kvs"
+//
+//     [[listener]]
+//     addr = "unix:/run/kvs-admin.sock"
+//     unix-mode = "600"
+//     auth = "admins.toml"
+//
+// Keys are named after the flags, which take precedence, as do their
+// environment variables. Relative paths are relative to the file.
+
+use crate::{Args, ThreadPoolKind};
+use clap::{parser::ValueSource, ArgMatches, FromArgMatches};
+use kvs::{EngineKind, EvictionPolicy, ListenAddr, OverflowPolicy, Quota, ServerProtocol};
+use serde::{de, Deserialize, Deserializer};
+use std::{
+    fmt::Display,
+    fs,
+    net::SocketAddr,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+// `--addr` when neither it nor `--unix` is given
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Config {
+    data_dir: Option<PathBuf>,
+    #[serde(default, deserialize_with = "parsed")]
+    engine: Option<EngineKind>,
+    #[serde(default, deserialize_with = "parsed")]
+    thread_pool: Option<ThreadPoolKind>,
+    threads: Option<NonZeroU32>,
+    shutdown_timeout: Option<u64>,
+    #[serde(default)]
+    listen: Listen,
+    #[serde(default)]
+    storage: Storage,
+    #[serde(default)]
+    limits: Limits,
+    #[serde(default)]
+    log: Log,
+    #[serde(default)]
+    listener: Vec<Listener>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Listen {
+    addr: Option<SocketAddr>,
+    unix: Option<PathBuf>,
+    #[serde(default, deserialize_with = "mode")]
+    unix_mode: Option<u32>,
+    #[serde(default, deserialize_with = "parsed")]
+    protocol: Option<ServerProtocol>,
+    resp_addr: Option<SocketAddr>,
+    http_addr: Option<SocketAddr>,
+    metrics_addr: Option<SocketAddr>,
+    #[cfg(feature = "grpc")]
+    grpc_addr: Option<SocketAddr>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    client_ca: Option<PathBuf>,
+    auth: Option<PathBuf>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Storage {
+    cache_capacity: Option<usize>,
+    compaction_threshold: Option<f32>,
+    memtable_bytes: Option<usize>,
+    compaction_trigger: Option<usize>,
+    max_keys: Option<usize>,
+    #[serde(default, deserialize_with = "parsed")]
+    eviction: Option<EvictionPolicy>,
+    #[serde(default, deserialize_with = "secs")]
+    flush_interval: Option<Duration>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Limits {
+    max_connections: Option<usize>,
+    #[serde(default, deserialize_with = "parsed")]
+    overflow: Option<OverflowPolicy>,
+    #[serde(default, deserialize_with = "secs")]
+    idle_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "secs")]
+    read_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "secs")]
+    write_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "secs")]
+    request_timeout: Option<Duration>,
+    connection_ops: Option<u32>,
+    connection_bytes: Option<u64>,
+    principal_ops: Option<u32>,
+    principal_bytes: Option<u64>,
+    #[serde(default, deserialize_with = "parsed_all")]
+    quota: Option<Vec<Quota>>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Log {
+    level: Option<String>,
+    #[serde(default, deserialize_with = "parsed")]
+    format: Option<LogFormat>,
+    #[cfg(feature = "otel")]
+    otlp_endpoint: Option<String>,
+}
+
+/// A `[[listener]]` of the config file, served next to the listeners of the
+/// flags. It has no TLS or authentication unless given.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Listener {
+    #[serde(deserialize_with = "from_str")]
+    pub addr: ListenAddr,
+    #[serde(default, deserialize_with = "parsed")]
+    pub protocol: Option<ServerProtocol>,
+    #[serde(default, deserialize_with = "mode")]
+    pub unix_mode: Option<u32>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub client_ca: Option<PathBuf>,
+    pub auth: Option<PathBuf>,
+}
+
+// checked like `--log-format`
+struct LogFormat(String);
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" | "json" => Ok(LogFormat(s.to_owned())),
+            _ => Err(format!("unknown log format: {}", s)),
+        }
+    }
+}
+
+/// The flags of `matches`, completed by the config file they name. Fails
+/// with a message if the file cannot be read, or the settings are invalid.
+pub fn load(matches: &ArgMatches) -> Result<Args, String> {
+    let mut args = Args::from_arg_matches(matches).map_err(|err| err.to_string())?;
+    if let Some(path) = args.config.clone() {
+        merge_file(&mut args, matches, &path)?;
+    }
+
+    // TCP is only served on request next to a Unix socket
+    if args.addr.is_none() && args.unix.is_none() {
+        args.addr = Some(DEFAULT_ADDR.parse().unwrap());
+    }
+
+    // checked once merged, as the flags may come from either
+    check_tls(&args.tls_cert, &args.tls_key, &args.client_ca)?;
+    for listener in &args.listeners {
+        check_tls(&listener.tls_cert, &listener.tls_key, &listener.client_ca)
+            .map_err(|err| format!("listener {}: {}", listener.addr, err))?;
+    }
+    if (args.principal_ops.is_some() || args.principal_bytes.is_some()) && args.auth.is_none() {
+        return Err("principal rate limits require auth".to_owned());
+    }
+    Ok(args)
+}
+
+fn check_tls(
+    cert: &Option<PathBuf>,
+    key: &Option<PathBuf>,
+    client_ca: &Option<PathBuf>,
+) -> Result<(), String> {
+    if cert.is_some() != key.is_some() {
+        return Err("tls-cert and tls-key go together".to_owned());
+    }
+    if client_ca.is_some() && cert.is_none() {
+        return Err("client-ca requires tls-cert".to_owned());
+    }
+    Ok(())
+}
+
+fn merge_file(args: &mut Args, matches: &ArgMatches, path: &Path) -> Result<(), String> {
+    let invalid = |err: &dyn Display| format!("invalid config file {}: {}", path.display(), err);
+    let config: Config = toml::from_str(&fs::read_to_string(path).map_err(|err| invalid(&err))?)
+        .map_err(|err| invalid(&err))?;
+    let dir = path.parent().unwrap_or(path);
+    let relative = |path: Option<PathBuf>| path.map(|path| Some(dir.join(path)));
+
+    // Takes each value from the file, unless its flag was given. The flags
+    // are named after the fields of `Args`.
+    let given = |id: &str| {
+        matches!(
+            matches.value_source(id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        )
+    };
+    macro_rules! merge {
+        ($($arg:ident = $value:expr;)*) => {$(
+            if let Some(value) = $value {
+                if !given(&stringify!($arg).replace('_', "-")) {
+                    args.$arg = value;
+                }
+            }
+        )*};
+    }
+
+    merge! {
+        data_dir = relative(config.data_dir);
+        engine = config.engine;
+        thread_pool = config.thread_pool;
+        threads = config.threads.map(|threads| Some(threads.get()));
+        shutdown_timeout = config.shutdown_timeout;
+    }
+    let listen = config.listen;
+    merge! {
+        addr = listen.addr.map(Some);
+        unix = relative(listen.unix);
+        unix_mode = listen.unix_mode.map(Some);
+        protocol = listen.protocol;
+        resp_addr = listen.resp_addr.map(Some);
+        http_addr = listen.http_addr.map(Some);
+        metrics_addr = listen.metrics_addr.map(Some);
+        tls_cert = relative(listen.tls_cert);
+        tls_key = relative(listen.tls_key);
+        client_ca = relative(listen.client_ca);
+        auth = relative(listen.auth);
+    }
+    #[cfg(feature = "grpc")]
+    merge! {
+        grpc_addr = listen.grpc_addr.map(Some);
+    }
+    let storage = config.storage;
+    merge! {
+        cache_capacity = storage.cache_capacity;
+        compaction_threshold = storage.compaction_threshold;
+        memtable_bytes = storage.memtable_bytes;
+        compaction_trigger = storage.compaction_trigger;
+        max_keys = storage.max_keys.map(Some);
+        eviction = storage.eviction;
+        flush_interval = storage.flush_interval.map(Some);
+    }
+    let limits = config.limits;
+    merge! {
+        max_connections = limits.max_connections.map(Some);
+        overflow = limits.overflow;
+        idle_timeout = limits.idle_timeout.map(Some);
+        read_timeout = limits.read_timeout.map(Some);
+        write_timeout = limits.write_timeout.map(Some);
+        request_timeout = limits.request_timeout.map(Some);
+        connection_ops = limits.connection_ops.map(Some);
+        connection_bytes = limits.connection_bytes.map(Some);
+        principal_ops = limits.principal_ops.map(Some);
+        principal_bytes = limits.principal_bytes.map(Some);
+        quotas = limits.quota;
+    }
+    let log = config.log;
+    merge! {
+        log_level = log.level.map(Some);
+        log_format = log.format.map(|format| format.0);
+    }
+    #[cfg(feature = "otel")]
+    merge! {
+        otlp_endpoint = log.otlp_endpoint.map(Some);
+    }
+
+    // listeners have no flags
+    let in_dir = |path: Option<PathBuf>| path.map(|path| dir.join(path));
+    for mut listener in config.listener {
+        if let ListenAddr::Unix(path) = &listener.addr {
+            listener.addr = ListenAddr::Unix(dir.join(path));
+        }
+        listener.tls_cert = in_dir(listener.tls_cert);
+        listener.tls_key = in_dir(listener.tls_key);
+        listener.client_ca = in_dir(listener.client_ca);
+        listener.auth = in_dir(listener.auth);
+        args.listeners.push(listener);
+    }
+    Ok(())
+}
+
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(de::Error::custom)
+}
+
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    from_str(deserializer).map(Some)
+}
+
+fn parsed_all<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let all = Vec::<String>::deserialize(deserializer)?;
+    let all: Result<Vec<T>, _> = all.iter().map(|s| s.parse()).collect();
+    all.map(Some).map_err(de::Error::custom)
+}
+
+// an octal string like `--unix-mode`
+fn mode<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    crate::parse_mode(&s).map(Some).map_err(de::Error::custom)
+}
+
+fn secs<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let secs = f64::deserialize(deserializer)?;
+    match Duration::try_from_secs_f64(secs) {
+        Ok(duration) if duration.is_zero() => Err(de::Error::custom("must be more than 0 seconds")),
+        result => result.map(Some).map_err(de::Error::custom),
+    }
+}
@@ -0,0 +1,734 @@
+use clap::{ArgMatches, CommandFactory, ErrorKind, Parser};
+use kvs::{
+    migrate,
+    thread_pool::{
+        NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool, WorkStealingThreadPool,
+    },
+    Auth, EngineKind, EvictionPolicy, KvServer, KvStore, KvStoreError, KvStoreOptions, KvsEngine,
+    ListenAddr, Listener, LsmKvsStore, LsmOptions, MemoryKvsStore, Metrics, OverflowPolicy, Quota,
+    RateLimit, Result, ServerHandle, ServerOptions, ServerProtocol, ServerTls, SledKvsStore,
+};
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use std::{fs, net::SocketAddr, path::PathBuf, str::FromStr, sync::mpsc, thread, time::Duration};
+use tracing::{info, warn};
+use tracing_subscriber::{filter::LevelFilter, prelude::*, reload, EnvFilter, Registry};
+
+mod config;
+
+#[derive(Parser)]
+#[clap(author, version, about, long_about = None)] // Read from Cargo.toml
+#[clap(propagate_version = true)]
+struct Args {
+    /// Read the settings missing from the command line from this TOML file,
+    /// and more listeners from its `[[listener]]` tables. Limits, timeouts,
+    /// rate limits and the log level are read again on SIGHUP.
+    #[clap(long, env = "KVS_CONFIG", value_parser)]
+    config: Option<PathBuf>,
+
+    // the `[[listener]]` tables of `--config`
+    #[clap(skip)]
+    listeners: Vec<config::Listener>,
+
+    /// Directory of the store. The working directory by default.
+    #[clap(long, env = "KVS_DATA_DIR", value_parser)]
+    data_dir: Option<PathBuf>,
+
+    /// addr: IP:port format. 127.0.0.1:4000 unless `--unix` is given.
+    #[clap(long, env = "KVS_ADDR", value_parser)]
+    addr: Option<SocketAddr>,
+
+    /// Serve on this Unix domain socket, next to `--addr` if given. Only
+    /// the users allowed by `--unix-mode` can connect.
+    #[clap(long, env = "KVS_UNIX", value_name = "PATH", value_parser)]
+    unix: Option<PathBuf>,
+
+    /// Permissions of the `--unix` socket file in octal, e.g. 660 to let
+    /// the users of its group connect. Those left by the umask by default.
+    #[clap(long, env = "KVS_UNIX_MODE", value_name = "MODE", value_parser = parse_mode)]
+    unix_mode: Option<u32>,
+
+    /// Protocol spoken on `--addr` and `--unix`: 'kvs', 'resp' (Redis),
+    /// 'http' or 'metrics'
+    #[clap(long, env = "KVS_PROTOCOL", value_parser, default_value = "kvs")]
+    protocol: ServerProtocol,
+
+    /// Also serve the Redis protocol on this address, next to `--addr`
+    #[clap(long, env = "KVS_RESP_ADDR", value_parser)]
+    resp_addr: Option<SocketAddr>,
+
+    /// Also serve the HTTP gateway on this address, next to `--addr`
+    #[clap(long, env = "KVS_HTTP_ADDR", value_parser)]
+    http_addr: Option<SocketAddr>,
+
+    /// Export Prometheus metrics at `/metrics` on this address
+    #[clap(long, env = "KVS_METRICS_ADDR", value_parser)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Also serve gRPC on this address, next to `--addr`. Not allowed with
+    /// `--tls-cert` or `--auth`, which gRPC does not support.
+    #[cfg(feature = "grpc")]
+    #[clap(long, env = "KVS_GRPC_ADDR", value_parser)]
+    grpc_addr: Option<SocketAddr>,
+
+    /// Storage engine: 'kvs', 'sled', 'lsm' or 'memory'
+    #[clap(long, env = "KVS_ENGINE", value_parser, default_value = "kvs")]
+    engine: EngineKind,
+
+    /// Capacity of the 'kvs' engine value cache, in bytes. 0 disables it.
+    #[clap(
+        long,
+        env = "KVS_CACHE_CAPACITY",
+        value_parser,
+        default_value_t = KvStore::DEFAULT_CACHE_CAPACITY
+    )]
+    cache_capacity: usize,
+
+    /// Compact the log of the 'kvs' engine once the live keys are fewer than
+    /// this fraction of the entries written. 0 only compacts on request.
+    #[clap(
+        long,
+        env = "KVS_COMPACTION_THRESHOLD",
+        value_parser,
+        default_value_t = KvStoreOptions::default().compaction_threshold
+    )]
+    compaction_threshold: f32,
+
+    /// Bytes of keys and values the 'lsm' engine holds in memory before
+    /// writing a table
+    #[clap(
+        long,
+        env = "KVS_MEMTABLE_BYTES",
+        value_parser,
+        default_value_t = LsmOptions::default().memtable_bytes
+    )]
+    memtable_bytes: usize,
+
+    /// Number of tables of about the same size that the 'lsm' engine merges
+    /// into one
+    #[clap(
+        long,
+        env = "KVS_COMPACTION_TRIGGER",
+        value_parser,
+        default_value_t = LsmOptions::default().compaction_trigger
+    )]
+    compaction_trigger: usize,
+
+    /// Maximum number of keys held by the 'memory' engine. Unbounded if unset.
+    #[clap(long, env = "KVS_MAX_KEYS", value_parser)]
+    max_keys: Option<usize>,
+
+    /// Eviction policy of the 'memory' engine when full: 'lru' or 'lfu'
+    #[clap(long, env = "KVS_EVICTION", value_parser, default_value = "lru")]
+    eviction: EvictionPolicy,
+
+    /// Migrate a data directory written by this engine to `--engine` before
+    /// serving
+    #[clap(long, env = "KVS_MIGRATE_FROM", value_parser)]
+    migrate_from: Option<EngineKind>,
+
+    /// Seconds between flushes of the writes to disk. Otherwise they are
+    /// flushed at shutdown, and by `kvs-client admin flush`.
+    #[clap(long, env = "KVS_FLUSH_INTERVAL", value_parser = parse_secs)]
+    flush_interval: Option<Duration>,
+
+    /// Thread pool serving the connections of all the listeners, a
+    /// connection taking a thread while open: 'naive' (a thread per
+    /// connection), 'shared-queue', 'rayon' or 'work-stealing'
+    #[clap(
+        long,
+        env = "KVS_THREAD_POOL",
+        value_parser,
+        default_value = "shared-queue"
+    )]
+    thread_pool: ThreadPoolKind,
+
+    /// Threads of the pool, at least 1. Twice the number of CPUs by default.
+    #[clap(long, env = "KVS_THREADS", value_parser = clap::value_parser!(u32).range(1..))]
+    threads: Option<u32>,
+
+    /// Seconds to let requests in flight finish on SIGINT or SIGTERM
+    #[clap(long, env = "KVS_SHUTDOWN_TIMEOUT", value_parser, default_value_t = 5)]
+    shutdown_timeout: u64,
+
+    /// Maximum number of connections open at once. Unbounded if unset.
+    #[clap(long, env = "KVS_MAX_CONNECTIONS", value_parser)]
+    max_connections: Option<usize>,
+
+    /// What to do with connections beyond `--max-connections`: 'queue' or
+    /// 'reject'
+    #[clap(long, env = "KVS_OVERFLOW", value_parser, default_value = "queue")]
+    overflow: OverflowPolicy,
+
+    /// Seconds to wait for the next request before closing a connection
+    #[clap(long, env = "KVS_IDLE_TIMEOUT", value_parser = parse_secs)]
+    idle_timeout: Option<Duration>,
+
+    /// Seconds a read in the middle of a request may block
+    #[clap(long, env = "KVS_READ_TIMEOUT", value_parser = parse_secs)]
+    read_timeout: Option<Duration>,
+
+    /// Seconds writing a response may block
+    #[clap(long, env = "KVS_WRITE_TIMEOUT", value_parser = parse_secs)]
+    write_timeout: Option<Duration>,
+
+    /// Seconds to receive a whole request, from its first bytes
+    #[clap(long, env = "KVS_REQUEST_TIMEOUT", value_parser = parse_secs)]
+    request_timeout: Option<Duration>,
+
+    /// Serve over TLS with this PEM certificate chain
+    #[clap(long, env = "KVS_TLS_CERT", value_parser)]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of `--tls-cert`
+    #[clap(long, env = "KVS_TLS_KEY", value_parser)]
+    tls_key: Option<PathBuf>,
+
+    /// Require clients to present a certificate signed by this PEM CA
+    /// (mutual TLS)
+    #[clap(long, env = "KVS_CLIENT_CA", value_parser)]
+    client_ca: Option<PathBuf>,
+
+    /// Require clients to authenticate with the tokens or users of this TOML
+    /// file, and restrict them to the key prefixes granted to them
+    #[clap(long, env = "KVS_AUTH", value_parser)]
+    auth: Option<PathBuf>,
+
+    /// Requests per second allowed on each connection
+    #[clap(long, env = "KVS_CONNECTION_OPS", value_parser)]
+    connection_ops: Option<u32>,
+
+    /// Bytes of keys and values per second allowed on each connection
+    #[clap(long, env = "KVS_CONNECTION_BYTES", value_parser)]
+    connection_bytes: Option<u64>,
+
+    /// Requests per second allowed to each principal of `--auth`
+    #[clap(long, env = "KVS_PRINCIPAL_OPS", value_parser)]
+    principal_ops: Option<u32>,
+
+    /// Bytes of keys and values per second allowed to each principal of
+    /// `--auth`
+    #[clap(long, env = "KVS_PRINCIPAL_BYTES", value_parser)]
+    principal_bytes: Option<u64>,
+
+    /// Storage quota, as PREFIX=BYTES: sets that would take the keys and
+    /// values under PREFIX beyond BYTES fail. Can be repeated.
+    #[clap(
+        long = "quota",
+        env = "KVS_QUOTA",
+        value_name = "PREFIX=BYTES",
+        value_parser
+    )]
+    quotas: Vec<Quota>,
+
+    /// Serve on the tokio runtime instead of a thread pool. Connection limits
+    /// and timeouts are not supported.
+    #[cfg(feature = "async")]
+    #[clap(long = "async")]
+    use_async: bool,
+
+    /// Log filter, e.g. 'debug' or 'kvs=debug,warn'. Requests are traced at
+    /// the debug level. Defaults to `RUST_LOG`, or 'info'.
+    #[clap(long, env = "KVS_LOG_LEVEL", value_parser = parse_filter)]
+    log_level: Option<String>,
+
+    /// Log format on stderr: 'text' or 'json'
+    #[clap(
+        long,
+        env = "KVS_LOG_FORMAT",
+        value_parser = ["text", "json"],
+        default_value = "text"
+    )]
+    log_format: String,
+
+    /// Export the spans to this OpenTelemetry collector over OTLP/HTTP,
+    /// e.g. http://localhost:4318
+    #[cfg(feature = "otel")]
+    #[clap(long, env = "KVS_OTLP_ENDPOINT", value_parser)]
+    otlp_endpoint: Option<String>,
+}
+
+// Exports the spans left when dropped
+struct LogGuard {
+    filter: reload::Handle<EnvFilter, Registry>,
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+// The implementations of `ThreadPool`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ThreadPoolKind {
+    Naive,
+    SharedQueue,
+    Rayon,
+    WorkStealing,
+}
+
+impl FromStr for ThreadPoolKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "naive" => Ok(ThreadPoolKind::Naive),
+            "shared-queue" => Ok(ThreadPoolKind::SharedQueue),
+            "rayon" => Ok(ThreadPoolKind::Rayon),
+            "work-stealing" => Ok(ThreadPoolKind::WorkStealing),
+            _ => Err(format!("unknown thread pool: {}", s)),
+        }
+    }
+}
+
+// What the signal handlers ask of the main thread
+enum Signal {
+    Shutdown,
+    Reload,
+}
+
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = &self.provider {
+            if let Err(err) = provider.shutdown() {
+                eprintln!("failed to export spans: {}", err);
+            }
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let matches = Args::command().get_matches();
+    let args = match config::load(&matches) {
+        Ok(args) => args,
+        Err(err) => Args::command().error(ErrorKind::InvalidValue, err).exit(),
+    };
+    let log = init_logging(&args);
+
+    info!("Version: {}", env!("CARGO_PKG_VERSION"));
+    if let Some(addr) = args.addr {
+        info!("addr: {}, Engine: {}", addr, args.engine);
+    }
+    if let Some(path) = &args.unix {
+        info!("unix: {}, Engine: {}", path.display(), args.engine);
+    }
+
+    let dir = match &args.data_dir {
+        Some(dir) => {
+            fs::create_dir_all(dir)?;
+            dir.clone()
+        }
+        None => std::env::current_dir()?,
+    };
+
+    if let Some(from) = args.migrate_from {
+        match EngineKind::current(dir.as_path())? {
+            Some(engine) if engine == from || engine == args.engine => {}
+            _ => return Err(KvStoreError::WrongEngine),
+        }
+        if let Some(summary) = migrate::migrate(dir.as_path(), args.engine)? {
+            info!(
+                "Migrated {} keys from {} to {}",
+                summary.count, from, args.engine
+            );
+        }
+    }
+
+    // check engine
+    match EngineKind::current(dir.as_path())? {
+        _ if !args.engine.is_persistent() => {}
+        None => args.engine.write_marker(dir.as_path())?,
+        Some(prev_engine) => {
+            if prev_engine != args.engine {
+                return Err(KvStoreError::WrongEngine);
+            }
+        }
+    }
+
+    let reload = Reload {
+        matches: &matches,
+        log: &log,
+    };
+    match args.engine {
+        EngineKind::Kvs => {
+            let options = KvStoreOptions {
+                cache_capacity: args.cache_capacity,
+                compaction_threshold: args.compaction_threshold,
+            };
+            serve(KvStore::open_with(dir.as_path(), options)?, &args, &reload)
+        }
+        EngineKind::Sled => serve(SledKvsStore::open(dir.as_path())?, &args, &reload),
+        EngineKind::Lsm => {
+            let options = LsmOptions {
+                memtable_bytes: args.memtable_bytes,
+                compaction_trigger: args.compaction_trigger,
+            };
+            serve(
+                LsmKvsStore::open_with(dir.as_path(), options)?,
+                &args,
+                &reload,
+            )
+        }
+        EngineKind::Memory => {
+            let store = match args.max_keys {
+                Some(max_keys) => MemoryKvsStore::with_max_keys(max_keys, args.eviction),
+                None => MemoryKvsStore::new(),
+            };
+            serve(store, &args, &reload)
+        }
+    }?;
+
+    Ok(())
+}
+
+// Log to stderr, and export spans with `--otlp-endpoint`
+fn init_logging(args: &Args) -> LogGuard {
+    let (filter, filter_handle) = reload::Layer::new(log_filter(args));
+    let fmt = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    let fmt = match args.log_format.as_str() {
+        "json" => fmt.json().boxed(),
+        _ => fmt.boxed(),
+    };
+    let subscriber = tracing_subscriber::registry().with(filter).with(fmt);
+
+    #[cfg(feature = "otel")]
+    {
+        use opentelemetry::trace::TracerProvider;
+        use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+        use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+
+        let exporter = args.otlp_endpoint.as_ref().map(|endpoint| {
+            SpanExporter::builder()
+                .with_http()
+                .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+                .build()
+        });
+        let (provider, error) = match exporter {
+            Some(Ok(exporter)) => {
+                let provider = SdkTracerProvider::builder()
+                    .with_batch_exporter(exporter)
+                    .with_resource(Resource::builder().with_service_name("kvs-server").build())
+                    .build();
+                (Some(provider), None)
+            }
+            Some(Err(err)) => (None, Some(err)),
+            None => (None, None),
+        };
+        let layer = provider
+            .as_ref()
+            .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer("kvs")));
+        subscriber.with(layer).init();
+        if let Some(err) = error {
+            tracing::warn!("Spans are not exported: {}", err);
+        }
+        LogGuard {
+            filter: filter_handle,
+            provider,
+        }
+    }
+    #[cfg(not(feature = "otel"))]
+    {
+        subscriber.init();
+        LogGuard {
+            filter: filter_handle,
+        }
+    }
+}
+
+// `--log-level`, else `RUST_LOG`, else info
+fn log_filter(args: &Args) -> EnvFilter {
+    match &args.log_level {
+        Some(filter) => EnvFilter::new(filter),
+        None => EnvFilter::builder()
+            .with_default_directive(LevelFilter::INFO.into())
+            .from_env_lossy(),
+    }
+}
+
+// Applies the config file again on SIGHUP
+struct Reload<'a> {
+    matches: &'a ArgMatches,
+    log: &'a LogGuard,
+}
+
+impl Reload<'_> {
+    // Read the config file again, and apply the settings that can change
+    // while serving. The others need a restart.
+    fn apply(&self, handle: &ServerHandle) {
+        let args = match config::load(self.matches) {
+            Ok(args) => args,
+            Err(err) => {
+                warn!("Configuration not reloaded: {}", err);
+                return;
+            }
+        };
+        if let Err(err) = self.log.filter.reload(log_filter(&args)) {
+            warn!("Log level not reloaded: {}", err);
+        }
+        let (connection_rate, principal_rate) = rate_limits(&args);
+        let options = ServerOptions {
+            max_connections: args.max_connections,
+            idle_timeout: args.idle_timeout,
+            read_timeout: args.read_timeout,
+            write_timeout: args.write_timeout,
+            request_timeout: args.request_timeout,
+            connection_rate,
+            principal_rate,
+            ..ServerOptions::default()
+        };
+        handle.reload(&options);
+        info!("Configuration reloaded");
+    }
+}
+
+fn parse_filter(s: &str) -> std::result::Result<String, String> {
+    EnvFilter::try_new(s).map_err(|err| err.to_string())?;
+    Ok(s.to_owned())
+}
+
+fn parse_mode(s: &str) -> std::result::Result<u32, String> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("invalid mode: {}", s)),
+    }
+}
+
+fn parse_secs(s: &str) -> std::result::Result<Duration, String> {
+    let secs = s.parse::<f64>().map_err(|err| err.to_string())?;
+    match Duration::try_from_secs_f64(secs) {
+        Ok(duration) if duration.is_zero() => Err("must be more than 0 seconds".to_owned()),
+        result => result.map_err(|err| err.to_string()),
+    }
+}
+
+// the rate limits of each connection and of each principal
+fn rate_limits(args: &Args) -> (Option<RateLimit>, Option<RateLimit>) {
+    let limit =
+        |ops_per_sec: Option<u32>, bytes_per_sec: Option<u64>| match (ops_per_sec, bytes_per_sec) {
+            (None, None) => None,
+            _ => Some(RateLimit {
+                ops_per_sec,
+                bytes_per_sec,
+            }),
+        };
+    (
+        limit(args.connection_ops, args.connection_bytes),
+        limit(args.principal_ops, args.principal_bytes),
+    )
+}
+
+fn serve<E: KvsEngine + Sync>(engine: E, args: &Args, reload: &Reload) -> Result<()> {
+    if let Some(interval) = args.flush_interval {
+        let engine = engine.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if let Err(err) = engine.flush() {
+                warn!("Flush failed: {}", err);
+            }
+        });
+    }
+
+    #[cfg(feature = "grpc")]
+    if let Some(addr) = args.grpc_addr {
+        use kvs::grpc::{GrpcKvServer, Watched};
+
+        // gRPC would let clients around the TLS and credentials of the
+        // other listeners
+        if args.tls_cert.is_some() || args.listeners.iter().any(|l| l.tls_cert.is_some()) {
+            tracing::error!("TLS is not supported with --grpc-addr");
+            return Err(KvStoreError::TlsError);
+        }
+        if args.auth.is_some() || args.listeners.iter().any(|l| l.auth.is_some()) {
+            tracing::error!("Authentication is not supported with --grpc-addr");
+            return Err(KvStoreError::PermissionDenied);
+        }
+        if rate_limits(args) != (None, None) || !args.quotas.is_empty() {
+            tracing::warn!("gRPC is served without rate limits or quotas");
+        }
+        // Watches see the changes made through every listener
+        let engine = Watched::new(engine);
+        let runtime = tokio::runtime::Runtime::new()?;
+        let server = runtime.block_on(GrpcKvServer::bind(engine.clone(), addr))?;
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let grpc = runtime.spawn(server.serve_with_shutdown(async {
+            let _ = rx.await;
+        }));
+        let result = serve_listeners(engine, args, reload);
+        let _ = tx.send(());
+        let grpc_result = runtime.block_on(grpc).expect("gRPC server panicked");
+        return result.and(grpc_result);
+    }
+    serve_listeners(engine, args, reload)
+}
+
+// Serve on `--addr`, `--unix`, the RESP, HTTP and metrics addresses and the
+// listeners of the config file, until SIGINT or SIGTERM. Reload on SIGHUP.
+fn serve_listeners<E: KvsEngine>(engine: E, args: &Args, reload: &Reload) -> Result<()> {
+    #[cfg(feature = "async")]
+    if args.use_async {
+        if args.max_connections.is_some()
+            || args.idle_timeout.is_some()
+            || args.read_timeout.is_some()
+            || args.write_timeout.is_some()
+            || args.request_timeout.is_some()
+        {
+            tracing::warn!("Connection limits and timeouts are ignored with --async");
+        }
+        if rate_limits(args) != (None, None) || !args.quotas.is_empty() {
+            tracing::warn!("Rate limits and quotas are ignored with --async");
+        }
+        if args.thread_pool != ThreadPoolKind::SharedQueue || args.threads.is_some() {
+            tracing::warn!("The thread pool settings are ignored with --async");
+        }
+        if args.protocol != ServerProtocol::Kvs
+            || args.resp_addr.is_some()
+            || args.http_addr.is_some()
+            || args.metrics_addr.is_some()
+            || !args.listeners.is_empty()
+        {
+            tracing::warn!("Only the kvs protocol is supported with --async");
+        }
+        if args.tls_cert.is_some() {
+            tracing::error!("TLS is not supported with --async");
+            return Err(KvStoreError::TlsError);
+        }
+        if args.auth.is_some() {
+            tracing::error!("Authentication is not supported with --async");
+            return Err(KvStoreError::PermissionDenied);
+        }
+        let addr = match (args.addr, &args.unix) {
+            (Some(addr), None) => addr,
+            _ => {
+                tracing::error!("Unix domain sockets are not supported with --async");
+                return Err(KvStoreError::BindError);
+            }
+        };
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        thread::spawn(move || {
+            if signals.forever().next().is_some() {
+                info!("Shutting down");
+                let _ = tx.send(());
+            }
+        });
+        let runtime = tokio::runtime::Runtime::new()?;
+        return runtime.block_on(kvs::AsyncKvServer::serve_with_shutdown(
+            engine,
+            addr,
+            async {
+                let _ = rx.await;
+            },
+            Duration::from_secs(args.shutdown_timeout),
+        ));
+    }
+
+    match args.thread_pool {
+        ThreadPoolKind::Naive => serve_pool::<_, NaiveThreadPool>(engine, args, reload),
+        ThreadPoolKind::SharedQueue => serve_pool::<_, SharedQueueThreadPool>(engine, args, reload),
+        ThreadPoolKind::Rayon => serve_pool::<_, RayonThreadPool>(engine, args, reload),
+        ThreadPoolKind::WorkStealing => {
+            serve_pool::<_, WorkStealingThreadPool>(engine, args, reload)
+        }
+    }
+}
+
+// Serve all the listeners with one pool of `P`
+fn serve_pool<E, P>(engine: E, args: &Args, reload: &Reload) -> Result<()>
+where
+    E: KvsEngine,
+    P: ThreadPool + Send + 'static,
+{
+    let options = ServerOptions {
+        max_connections: args.max_connections,
+        overflow: args.overflow,
+        idle_timeout: args.idle_timeout,
+        read_timeout: args.read_timeout,
+        write_timeout: args.write_timeout,
+        request_timeout: args.request_timeout,
+        connection_rate: rate_limits(args).0,
+        principal_rate: rate_limits(args).1,
+        quotas: args.quotas.clone(),
+        metrics: Metrics::new(),
+        // the protocol, TLS and authentication of each listener
+        ..ServerOptions::default()
+    };
+
+    // The listeners of the flags share the TLS and authentication of
+    // `--addr`, but for `--unix`, as local clients need no TLS
+    let tls = server_tls(&args.tls_cert, &args.tls_key, &args.client_ca)?;
+    let auth = args.auth.as_deref().map(Auth::load).transpose()?;
+    let listener = |addr: ListenAddr, protocol| Listener {
+        protocol,
+        tls: tls.clone(),
+        auth: auth.clone(),
+        ..Listener::new(addr)
+    };
+    let mut listeners = Vec::new();
+    if let Some(addr) = args.addr {
+        listeners.push(listener(addr.into(), args.protocol));
+    }
+    if let Some(path) = &args.unix {
+        listeners.push(Listener {
+            tls: None,
+            unix_mode: args.unix_mode,
+            ..listener(ListenAddr::Unix(path.clone()), args.protocol)
+        });
+    }
+    for (addr, protocol) in [
+        (args.resp_addr, ServerProtocol::Resp),
+        (args.http_addr, ServerProtocol::Http),
+        (args.metrics_addr, ServerProtocol::Metrics),
+    ] {
+        if let Some(addr) = addr {
+            listeners.push(listener(addr.into(), protocol));
+        }
+    }
+    for config in &args.listeners {
+        listeners.push(Listener {
+            protocol: config.protocol.unwrap_or_default(),
+            tls: server_tls(&config.tls_cert, &config.tls_key, &config.client_ca)?,
+            auth: config.auth.as_deref().map(Auth::load).transpose()?,
+            unix_mode: config.unix_mode,
+            ..Listener::new(config.addr.clone())
+        });
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            let signal = match signal {
+                SIGHUP => Signal::Reload,
+                _ => Signal::Shutdown,
+            };
+            let _ = tx.send(signal);
+        }
+    });
+
+    let num_threads = args.threads.unwrap_or((num_cpus::get() * 2) as u32);
+    let thread_pool = P::new(num_threads)?;
+    let handle = KvServer::start_listeners(engine, thread_pool, listeners, options)?;
+
+    while let Ok(Signal::Reload) = rx.recv() {
+        reload.apply(&handle);
+    }
+    info!("Shutting down");
+    handle.shutdown(Duration::from_secs(args.shutdown_timeout))
+}
+
+// TLS with a PEM certificate chain and key, if given, and a PEM CA to
+// verify the certificates of clients
+fn server_tls(
+    cert: &Option<PathBuf>,
+    key: &Option<PathBuf>,
+    client_ca: &Option<PathBuf>,
+) -> Result<Option<ServerTls>> {
+    match (cert, key) {
+        (Some(cert), Some(key)) => {
+            let client_ca = client_ca.as_ref().map(fs::read).transpose()?;
+            let tls = ServerTls::from_pem(&fs::read(cert)?, &fs::read(key)?, client_ca.as_deref())?;
+            Ok(Some(tls))
+        }
+        _ => Ok(None),
+    }
+}
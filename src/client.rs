@@ -1,62 +1,284 @@
 use serde::Deserialize;
-use serde_json::de::IoRead;
 
-use crate::message::{GetResponse, RemoveResponse, Request, SetResponse};
-use crate::Result;
+use crate::message::{
+    AdminReply, AdminRequest, AdminResponse, AuthRequest, Credentials, GetResponse, RemoveResponse,
+    Request, Response, ServerStats, SetResponse,
+};
+use crate::protocol::{self, Frame, Protocol};
+use crate::socket::Socket;
+use crate::tls::{ClientTls, Stream};
+use crate::{KvStoreError, Result};
 use serde_json::Deserializer;
+use std::collections::HashMap;
 use std::io::{BufReader, BufWriter, Write};
 use std::net::{SocketAddr, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::Arc;
 
 /// A client that queries the KvStore server.
+///
+/// Requests can be pipelined: `send_get`, `send_set` and `send_remove` send a
+/// request without waiting for its response, which `wait` reads later.
 pub struct KvClient {
-    writer: BufWriter<TcpStream>,
-    deserializer: Deserializer<IoRead<BufReader<TcpStream>>>,
+    protocol: Protocol,
+    writer: BufWriter<Stream>,
+    reader: BufReader<Stream>,
+    next_id: u64,
+    // responses read while waiting for another one
+    responses: HashMap<u64, Frame>,
+}
+
+/// A request sent by `KvClient`, whose response is read by `KvClient::wait`.
+///
+/// The response of a `Pending` that is dropped without being waited for is
+/// kept by the client until the client is dropped, once it has been read
+/// while waiting for another response.
+#[must_use = "the response must be read with `KvClient::wait`"]
+pub struct Pending<T> {
+    id: u64,
+    parse: fn(Frame) -> Result<T>,
 }
 
 impl KvClient {
-    /// creates a new client ready to send queries
+    /// creates a new client ready to send queries, speaking the binary
+    /// protocol
     pub fn new(addr: SocketAddr) -> Result<Self> {
-        let tcp_reader = TcpStream::connect(addr)?;
-        let tcp_writer = tcp_reader.try_clone()?; // Use try_clone()
-        let writer = BufWriter::new(tcp_reader);
-        let deserializer = Deserializer::from_reader(BufReader::new(tcp_writer));
-        Ok(KvClient {
-            writer,
-            deserializer,
-        })
+        Self::with_protocol(addr, Protocol::Binary)
+    }
+
+    /// creates a new client speaking `protocol`. For the binary protocol,
+    /// fails if the server supports no common version.
+    pub fn with_protocol(addr: SocketAddr, protocol: Protocol) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        Self::from_stream(Stream::Plain(Socket::Tcp(stream)), protocol)
+    }
+
+    /// creates a new client speaking `protocol` over the Unix domain socket
+    /// at `path`, for servers on the same host
+    pub fn connect_unix(path: impl AsRef<Path>, protocol: Protocol) -> Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        Self::from_stream(Stream::Plain(Socket::Unix(stream)), protocol)
+    }
+
+    /// creates a new client speaking `protocol` over TLS. Fails with
+    /// `KvStoreError::TlsError` if the server is not trusted by `tls`.
+    pub fn with_tls(addr: SocketAddr, protocol: Protocol, tls: &ClientTls) -> Result<Self> {
+        let stream = tls.connect(addr.ip(), Socket::Tcp(TcpStream::connect(addr)?))?;
+        Self::from_stream(Stream::Tls(Arc::new(stream)), protocol)
+    }
+
+    fn from_stream(stream: Stream, protocol: Protocol) -> Result<Self> {
+        let writer = stream.try_clone()?;
+        let mut client = KvClient {
+            protocol,
+            writer: BufWriter::new(writer),
+            reader: BufReader::new(stream),
+            next_id: 1,
+            responses: HashMap::new(),
+        };
+        if protocol == Protocol::Binary {
+            protocol::connect(&mut client.reader, &mut client.writer)?;
+        }
+        Ok(client)
+    }
+
+    /// Authenticate to a server requiring it, before any other request.
+    /// Fails with `KvStoreError::PermissionDenied` if the credentials are
+    /// wrong, after which the server closes the connection.
+    pub fn authenticate(&mut self, credentials: Credentials) -> Result<()> {
+        match self.protocol {
+            Protocol::Json => {
+                serde_json::to_writer(&mut self.writer, &AuthRequest::Auth(credentials))?;
+                self.writer.flush()?;
+                let resp =
+                    SetResponse::deserialize(&mut Deserializer::from_reader(&mut self.reader))?;
+                match resp {
+                    SetResponse::Ok => Ok(()),
+                    SetResponse::Err(err) => Err(err),
+                }
+            }
+            Protocol::Binary => {
+                let id = self.next_id;
+                self.next_id += 1;
+                self.writer
+                    .write_all(&Frame::Auth(credentials).encode(id)?)?;
+                self.wait(Pending { id, parse: done })
+            }
+        }
     }
 
     /// get
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
-        // Cannot use serde_json::from_reader. It looks for EOF.
-        let resp = GetResponse::deserialize(&mut self.deserializer)?;
-        match resp {
-            GetResponse::Ok(res) => Ok(res),
-            GetResponse::Err(err) => Err(err),
-        }
+        let pending = self.send_get(key)?;
+        self.wait(pending)
     }
 
     /// set
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
-        self.writer.flush()?;
-        let resp = SetResponse::deserialize(&mut self.deserializer)?;
-        match resp {
-            SetResponse::Ok => Ok(()),
-            SetResponse::Err(err) => Err(err),
-        }
+        let pending = self.send_set(key, value)?;
+        self.wait(pending)
     }
 
     /// remove
     pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
+        let pending = self.send_remove(key)?;
+        self.wait(pending)
+    }
+
+    /// send a get, without waiting for its response
+    pub fn send_get(&mut self, key: String) -> Result<Pending<Option<String>>> {
+        Ok(Pending {
+            id: self.send(Request::Get { key })?,
+            parse: |frame| match frame {
+                Frame::Value(value) => Ok(value),
+                Frame::Error(err) => Err(err),
+                _ => Err(KvStoreError::InvalidFrame),
+            },
+        })
+    }
+
+    /// send a set, without waiting for its response
+    pub fn send_set(&mut self, key: String, value: String) -> Result<Pending<()>> {
+        Ok(Pending {
+            id: self.send(Request::Set { key, value })?,
+            parse: done,
+        })
+    }
+
+    /// send a remove, without waiting for its response
+    pub fn send_remove(&mut self, key: String) -> Result<Pending<()>> {
+        Ok(Pending {
+            id: self.send(Request::Remove { key })?,
+            parse: done,
+        })
+    }
+
+    /// check that the server answers
+    pub fn ping(&mut self) -> Result<()> {
+        match self.admin(AdminRequest::Ping)? {
+            AdminReply::Pong => Ok(()),
+            _ => Err(KvStoreError::InvalidFrame),
+        }
+    }
+
+    /// Counters of the server and its store. Like every admin request but
+    /// `ping`, fails with `KvStoreError::PermissionDenied` unless the client
+    /// authenticated as an administrator, on a server requiring
+    /// authentication.
+    pub fn stats(&mut self) -> Result<ServerStats> {
+        match self.admin(AdminRequest::Stats)? {
+            AdminReply::Stats(stats) => Ok(stats),
+            _ => Err(KvStoreError::InvalidFrame),
+        }
+    }
+
+    /// compact the store of the server now
+    pub fn compact(&mut self) -> Result<()> {
+        self.admin(AdminRequest::Compact).and_then(admin_done)
+    }
+
+    /// make every write the server received so far durable
+    pub fn flush(&mut self) -> Result<()> {
+        self.admin(AdminRequest::Flush).and_then(admin_done)
+    }
+
+    /// the settings of the server that `set_config` changes, and their
+    /// values
+    pub fn config(&mut self) -> Result<Vec<(String, String)>> {
+        match self.admin(AdminRequest::GetConfig)? {
+            AdminReply::Config(config) => Ok(config),
+            _ => Err(KvStoreError::InvalidFrame),
+        }
+    }
+
+    /// Change a setting of the server until it restarts. Fails with
+    /// `KvStoreError::InvalidRequest` if the setting does not exist, or the
+    /// value is invalid.
+    pub fn set_config(&mut self, name: String, value: String) -> Result<()> {
+        self.admin(AdminRequest::SetConfig { name, value })
+            .and_then(admin_done)
+    }
+
+    fn admin(&mut self, req: AdminRequest) -> Result<AdminReply> {
+        let pending = Pending {
+            id: self.send(Request::Admin(req))?,
+            parse: |frame| match frame {
+                Frame::Admin(reply) => Ok(reply),
+                Frame::Error(err) => Err(err),
+                _ => Err(KvStoreError::InvalidFrame),
+            },
+        };
+        self.wait(pending)
+    }
+
+    /// Read the response of a request. Responses can be waited for in any
+    /// order. If the server supports it, it answers them in any order too.
+    pub fn wait<T>(&mut self, pending: Pending<T>) -> Result<T> {
+        if let Some(frame) = self.responses.remove(&pending.id) {
+            return (pending.parse)(frame);
+        }
         self.writer.flush()?;
-        let resp = RemoveResponse::deserialize(&mut self.deserializer)?;
-        match resp {
-            RemoveResponse::Ok => Ok(()),
-            RemoveResponse::Err(err) => Err(err),
+        loop {
+            match Frame::read(&mut self.reader)? {
+                (id, frame) if id == pending.id => return (pending.parse)(frame),
+                // errors about a request that could not be parsed
+                (0, Frame::Error(err)) => return Err(err),
+                (id, frame) => {
+                    self.responses.insert(id, frame);
+                }
+            }
+        }
+    }
+
+    // Send a request, returning its id. Binary requests are buffered until
+    // a response is waited for. JSON has no request ids, so JSON requests
+    // are answered right away.
+    fn send(&mut self, req: Request) -> Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        match self.protocol {
+            Protocol::Json => {
+                serde_json::to_writer(&mut self.writer, &req)?;
+                self.writer.flush()?;
+                // Cannot use serde_json::from_reader. It looks for EOF.
+                // The client has to know which response type to read.
+                let mut deserializer = Deserializer::from_reader(&mut self.reader);
+                let resp = match req {
+                    Request::Get { .. } => {
+                        Response::Get(GetResponse::deserialize(&mut deserializer)?)
+                    }
+                    Request::Set { .. } => {
+                        Response::Set(SetResponse::deserialize(&mut deserializer)?)
+                    }
+                    Request::Remove { .. } => {
+                        Response::Remove(RemoveResponse::deserialize(&mut deserializer)?)
+                    }
+                    Request::Admin(_) => {
+                        Response::Admin(AdminResponse::deserialize(&mut deserializer)?)
+                    }
+                };
+                self.responses.insert(id, resp.into());
+            }
+            Protocol::Binary => {
+                self.writer.write_all(&Frame::Request(req).encode(id)?)?;
+            }
         }
+        Ok(id)
+    }
+}
+
+fn admin_done(reply: AdminReply) -> Result<()> {
+    match reply {
+        AdminReply::Done => Ok(()),
+        _ => Err(KvStoreError::InvalidFrame),
+    }
+}
+
+fn done(frame: Frame) -> Result<()> {
+    match frame {
+        Frame::Done => Ok(()),
+        Frame::Error(err) => Err(err),
+        _ => Err(KvStoreError::InvalidFrame),
     }
 }
@@ -0,0 +1,155 @@
+//! Logical dump and load of the key/value pairs of a `KvsEngine`.
+//!
+//! A dump is independent of the engine that produced it, so it can be used to
+//! move data between engines, to seed test fixtures, or to diff two stores.
+//! Two formats are supported.
+//!
+//! # JSON lines
+//!
+//! One JSON object per line, each with a `key` and a `value` string:
+//!
+//! ```text
+//! {"key":"key1","value":"value1"}
+//! {"key":"key2","value":"value2"}
+//! ```
+//!
+//! # Binary
+//!
+//! The 8-byte magic `KVSDUMP1`, followed by one record per pair. A record is
+//! the key length as a big-endian `u32`, the UTF-8 key bytes, the value length
+//! as a big-endian `u32`, and the UTF-8 value bytes. The dump ends at EOF.
+//!
+//! Pairs appear in no particular order in either format.
+use crate::{KvStoreError, KvsEngine, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::{
+    fmt,
+    io::{self, BufRead, Read, Write},
+    str::FromStr,
+};
+
+const BINARY_MAGIC: &[u8; 8] = b"KVSDUMP1";
+
+/// The format of a dump.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DumpFormat {
+    /// One JSON object per line
+    Json,
+    /// Length-prefixed records
+    Binary,
+}
+
+impl FromStr for DumpFormat {
+    type Err = KvStoreError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(Self::Json),
+            "binary" => Ok(Self::Binary),
+            _ => Err(KvStoreError::InvalidDump),
+        }
+    }
+}
+
+impl fmt::Display for DumpFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Json => write!(f, "json"),
+            Self::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Pair {
+    key: String,
+    value: String,
+}
+
+/// Write every pair in `engine` to `writer`. Returns the number of pairs.
+/// Fails with `KvStoreError::InvalidDump` if a key or value is 4 GiB or
+/// longer in the binary format.
+pub fn dump<E: KvsEngine, W: Write>(engine: &E, writer: W, format: DumpFormat) -> Result<usize> {
+    let mut writer = io::BufWriter::new(writer);
+    let mut count = 0;
+
+    if format == DumpFormat::Binary {
+        writer.write_all(BINARY_MAGIC)?;
+    }
+    engine.scan(|key, value| {
+        match format {
+            DumpFormat::Json => {
+                serde_json::to_writer(&mut writer, &Pair { key, value })?;
+                writer.write_all(b"\n")?;
+            }
+            DumpFormat::Binary => {
+                write_field(&mut writer, &key)?;
+                write_field(&mut writer, &value)?;
+            }
+        }
+        count += 1;
+        Ok(())
+    })?;
+    writer.flush()?;
+
+    Ok(count)
+}
+
+/// Set every pair read from `reader` in `engine`. Returns the number of pairs.
+pub fn load<E: KvsEngine, R: Read>(engine: &E, reader: R, format: DumpFormat) -> Result<usize> {
+    let mut reader = io::BufReader::new(reader);
+    let mut count = 0;
+
+    match format {
+        DumpFormat::Json => {
+            for pair in Deserializer::from_reader(reader).into_iter::<Pair>() {
+                let Pair { key, value } = pair?;
+                engine.set(key, value)?;
+                count += 1;
+            }
+        }
+        DumpFormat::Binary => {
+            let mut magic = [0u8; 8];
+            reader.read_exact(&mut magic)?;
+            if &magic != BINARY_MAGIC {
+                return Err(KvStoreError::InvalidDump);
+            }
+            while !reader.fill_buf()?.is_empty() {
+                let key = read_field(&mut reader)?;
+                let value = read_field(&mut reader)?;
+                engine.set(key, value)?;
+                count += 1;
+            }
+        }
+    }
+
+    Ok(count)
+}
+
+// Fails with `InvalidDump` if the field is too long for its length prefix
+fn write_field<W: Write>(writer: &mut W, field: &str) -> Result<()> {
+    let len = u32::try_from(field.len()).map_err(|_| KvStoreError::InvalidDump)?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(field.as_bytes())?;
+    Ok(())
+}
+
+fn read_field<R: Read>(reader: &mut R) -> Result<String> {
+    let mut len = [0u8; 4];
+    reader
+        .read_exact(&mut len)
+        .map_err(|_| KvStoreError::InvalidDump)?;
+    // the length is untrusted, so the buffer grows with the bytes actually
+    // read instead of being allocated up front
+    let len = u32::from_be_bytes(len) as u64;
+    let mut buf = Vec::new();
+    reader
+        .take(len)
+        .read_to_end(&mut buf)
+        .map_err(|_| KvStoreError::InvalidDump)?;
+    if buf.len() as u64 != len {
+        return Err(KvStoreError::InvalidDump);
+    }
+    String::from_utf8(buf).map_err(|_| KvStoreError::InvalidDump)
+}
@@ -0,0 +1,90 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Counters of a `KvStore` value cache.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// gets answered from the cache
+    pub hits: u64,
+    /// gets that had to read the log
+    pub misses: u64,
+    /// values currently cached
+    pub entries: usize,
+    /// bytes of keys and values currently cached
+    pub bytes: usize,
+}
+
+// A bounded LRU cache of values, sized by the bytes of its keys and values.
+#[derive(Debug)]
+pub(super) struct ValueCache {
+    capacity: usize,
+    map: HashMap<String, (String, u64)>,
+    // least recently used first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl ValueCache {
+    // A capacity of 0 disables the cache
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            map: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats.clone()
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<String> {
+        if self.capacity == 0 {
+            return None;
+        }
+        self.tick += 1;
+        match self.map.get_mut(key) {
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+            Some((value, last_used)) => {
+                self.stats.hits += 1;
+                self.order.remove(last_used);
+                *last_used = self.tick;
+                self.order.insert(self.tick, key.to_owned());
+                Some(value.to_owned())
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: String, value: String) {
+        let size = key.len() + value.len();
+        if size > self.capacity {
+            return;
+        }
+        self.invalidate(&key);
+        while self.stats.bytes + size > self.capacity {
+            match self.order.pop_first() {
+                Some((_, victim)) => self.invalidate(&victim),
+                None => break,
+            }
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.map.insert(key, (value, self.tick));
+        self.stats.entries += 1;
+        self.stats.bytes += size;
+    }
+
+    pub fn invalidate(&mut self, key: &str) {
+        if let Some((value, last_used)) = self.map.remove(key) {
+            self.order.remove(&last_used);
+            self.stats.entries -= 1;
+            self.stats.bytes -= key.len() + value.len();
+        }
+    }
+}
@@ -1,7 +1,10 @@
+use super::cache::{CacheStats, ValueCache};
+use super::{EngineStats, Segment};
 use crate::{
     error::{KvStoreError, Result},
     KvsEngine,
 };
+use memmap2::{Mmap, MmapOptions};
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 use std::{
@@ -29,12 +32,18 @@ impl Entry {
 }
 
 /// Position of entry in the log file.
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 struct EntryPos {
     offset: usize,
     size: usize,
 }
 
+// Bytes read at once by `scan`, which visits entries in log order
+const READ_AHEAD: usize = 64 << 10;
+
+// Remap the log once this many bytes were appended past the mapped prefix
+const REMAP_THRESHOLD: usize = 1 << 20;
+
 // Statistics about log entries
 #[derive(Clone, Debug)]
 struct Stat {
@@ -44,8 +53,37 @@ struct Stat {
 #[derive(Debug)]
 struct Shared {
     file: fs::File,
+    // The sealed prefix of `file`, mapped into memory. Entries past its end
+    // are read with `read_exact_at`. Shared with the scans reading it.
+    sealed: Option<Arc<Mmap>>,
     mapping: HashMap<String, EntryPos>,
+    // bytes of the entries in `mapping`
+    live_bytes: usize,
     stat: Stat,
+    cache: ValueCache,
+    compactions: u64,
+    bytes_reclaimed: u64,
+}
+
+/// Tuning knobs of `KvStore`.
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    /// Capacity of the value cache, in bytes of keys and values. 0 disables
+    /// the cache.
+    pub cache_capacity: usize,
+    /// Compact the log once the live keys are fewer than this fraction of
+    /// the entries written since it was opened or compacted. 0 never
+    /// compacts on its own.
+    pub compaction_threshold: f32,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self {
+            cache_capacity: KvStore::DEFAULT_CACHE_CAPACITY,
+            compaction_threshold: 0.4,
+        }
+    }
 }
 
 /// `KvStore` stores key-value pairs, using log-structured hashtable.  
@@ -55,41 +93,103 @@ pub struct KvStore {
     // immutable
     dir_path: Box<path::PathBuf>,
     file_path: Box<path::PathBuf>,
+    compaction_threshold: f32,
 
     // mutable
     shared: Arc<Mutex<Shared>>,
 }
 
 impl KvStore {
+    /// Default capacity of the value cache, in bytes of keys and values
+    pub const DEFAULT_CACHE_CAPACITY: usize = 16 << 20;
+
     /// open a store
     pub fn open(dir_path: &path::Path) -> Result<Self> {
+        Self::open_with_cache(dir_path, Self::DEFAULT_CACHE_CAPACITY)
+    }
+
+    /// open a store whose value cache holds up to `cache_capacity` bytes of
+    /// keys and values. A capacity of 0 disables the cache.
+    pub fn open_with_cache(dir_path: &path::Path, cache_capacity: usize) -> Result<Self> {
+        Self::open_with(
+            dir_path,
+            KvStoreOptions {
+                cache_capacity,
+                ..KvStoreOptions::default()
+            },
+        )
+    }
+
+    /// open a store with the given options
+    pub fn open_with(dir_path: &path::Path, options: KvStoreOptions) -> Result<Self> {
         let dir_path = Box::new(dir_path.to_owned());
         let file_path = Box::new(dir_path.join("data.json"));
         let mut file = Self::open_logfile(&file_path);
         let mapping = Self::mapping_from_log(&mut file)?;
+        let live_bytes = mapping.values().map(|meta| meta.size).sum();
         let stat = Stat { total: 0 };
+        let sealed = Self::map_log(&file);
         let shared = Arc::new(Mutex::new(Shared {
             file,
+            sealed,
             mapping,
+            live_bytes,
             stat,
+            cache: ValueCache::new(options.cache_capacity),
+            compactions: 0,
+            bytes_reclaimed: 0,
         }));
         let store = KvStore {
             dir_path,
             file_path,
+            compaction_threshold: options.compaction_threshold,
             shared,
         };
         Ok(store)
     }
 
-    // parse an `Entry` from a file and metadata
-    fn deserialize(file: &fs::File, meta: &EntryPos) -> Result<Entry> {
-        let EntryPos { offset, size } = meta;
-        let mut buf = vec![0u8; *size];
-        file.read_exact_at(&mut buf, *offset as u64)?;
+    /// hit/miss counters and size of the value cache
+    pub fn cache_stats(&self) -> CacheStats {
+        self.shared.lock().unwrap().cache.stats()
+    }
+
+    // parse an `Entry` from the log, given its metadata
+    fn deserialize(shared: &Shared, meta: &EntryPos) -> Result<Entry> {
+        let EntryPos { offset, size } = *meta;
+        if let Some(bytes) = Self::sealed_slice(&shared.sealed, meta) {
+            return Ok(serde_json::from_slice(bytes)?);
+        }
+        let mut buf = vec![0u8; size];
+        shared.file.read_exact_at(&mut buf, offset as u64)?;
         let entry: Entry = serde_json::from_slice(&buf)?;
         Ok(entry)
     }
 
+    // the bytes of an entry, if it lies in the sealed prefix of the log
+    fn sealed_slice<'a>(sealed: &'a Option<Arc<Mmap>>, meta: &EntryPos) -> Option<&'a [u8]> {
+        let EntryPos { offset, size } = *meta;
+        sealed
+            .as_ref()
+            .and_then(|sealed| sealed.get(offset..offset + size))
+    }
+
+    // Map the current content of the log into memory. `None` if the log is
+    // empty or cannot be mapped, in which case reads fall back to
+    // `read_exact_at`.
+    fn map_log(file: &fs::File) -> Option<Arc<Mmap>> {
+        let len = file.metadata().ok()?.len() as usize;
+        if len == 0 {
+            return None;
+        }
+        // Safety: the log is append-only, and is never truncated or rewritten
+        // in place. Compaction writes a new file and renames it over the old
+        // one, whose mapping stays valid. The mapped bytes therefore never
+        // change, as long as no other process modifies the log.
+        unsafe { MmapOptions::new().len(len).map(file) }
+            .ok()
+            .map(Arc::new)
+    }
+
     // Generate in-memory mapping by replaying a log file.
     fn mapping_from_log(file: &mut fs::File) -> Result<HashMap<String, EntryPos>> {
         let mut mapping = HashMap::new();
@@ -123,8 +223,6 @@ impl KvStore {
             // https://www.reddit.com/r/rust/comments/2pqcgt/while_let_someitem_iteratornext/
             // https://github.com/rust-lang/rust/issues/8372
             while let Some(Ok(entry)) = stream.next() {
-                let entry = entry;
-
                 let new_offset = stream.byte_offset();
                 let size = new_offset - offset;
 
@@ -155,40 +253,56 @@ impl KvStore {
     // append an `Entry` to the log file. May compact. Update stat.
     // Assumes that caller holds the mutex.
     fn append_entry(&self, shared: &mut Shared, entry: Entry) -> Result<(usize, usize)> {
-        // compact
-        if (shared.mapping.len() as f32) / (shared.stat.total as f32) < 0.4 {
-            // write new log file and create new mapping
-            let new_log_path = self.dir_path.join("compacted.json");
-            let mut new_mapping = HashMap::new();
-            {
-                let mut new_log = Self::open_logfile(&new_log_path);
-                for (key, meta) in (shared.mapping).iter() {
-                    let entry = Self::deserialize(&shared.file, meta)?;
-                    let (offset, size) = Self::append_file(&mut new_log, entry)?;
-                    new_mapping.insert(key.to_owned(), EntryPos { offset, size });
-                }
-            }
-
-            // update fields
-            fs::rename(new_log_path, self.file_path.as_path())?;
-            shared.file = Self::open_logfile(self.file_path.as_path());
-            shared.mapping = new_mapping;
-            shared.stat = Stat { total: 0 };
+        let live = shared.mapping.len() as f32;
+        if live / (shared.stat.total as f32) < self.compaction_threshold {
+            self.compact_log(shared)?;
         }
 
         let (offset, size) = Self::append_file(&mut shared.file, entry)?;
 
+        let sealed_len = shared.sealed.as_ref().map_or(0, |sealed| sealed.len());
+        if offset + size - sealed_len >= REMAP_THRESHOLD {
+            shared.sealed = Self::map_log(&shared.file);
+        }
+
         Ok((offset, size))
     }
 
+    // Rewrite the log with only the live entries.
+    // Assumes that caller holds the mutex.
+    fn compact_log(&self, shared: &mut Shared) -> Result<()> {
+        // write new log file and create new mapping
+        let old_len = shared.file.metadata()?.len();
+        let new_log_path = self.dir_path.join("compacted.json");
+        let mut new_mapping = HashMap::new();
+        {
+            let mut new_log = Self::open_logfile(&new_log_path);
+            for (key, meta) in (shared.mapping).iter() {
+                let entry = Self::deserialize(shared, meta)?;
+                let (offset, size) = Self::append_file(&mut new_log, entry)?;
+                new_mapping.insert(key.to_owned(), EntryPos { offset, size });
+            }
+        }
+
+        // update fields
+        fs::rename(new_log_path, self.file_path.as_path())?;
+        shared.file = Self::open_logfile(self.file_path.as_path());
+        shared.sealed = Self::map_log(&shared.file);
+        shared.live_bytes = new_mapping.values().map(|meta| meta.size).sum();
+        shared.mapping = new_mapping;
+        shared.stat = Stat { total: 0 };
+        shared.compactions += 1;
+        shared.bytes_reclaimed += old_len.saturating_sub(shared.file.metadata()?.len());
+        Ok(())
+    }
+
     // open a file to be used a log file, with proper flags
     fn open_logfile(path: &path::Path) -> fs::File {
         fs::OpenOptions::new()
             .create(true) // open if existing, otherwise create
             .read(true)
-            .write(true)
             .append(true)
-            .open(&path)
+            .open(path)
             .unwrap()
     }
 }
@@ -198,6 +312,7 @@ impl Clone for KvStore {
         Self {
             dir_path: self.dir_path.clone(),
             file_path: self.file_path.clone(),
+            compaction_threshold: self.compaction_threshold,
             shared: self.shared.clone(),
         }
     }
@@ -211,30 +326,39 @@ impl KvsEngine for KvStore {
         };
 
         let mut shared = self.shared.lock().unwrap();
+        shared.cache.invalidate(&key);
         let (offset, size) = self.append_entry(&mut shared, entry)?;
         shared.stat.total += 1;
-        shared.mapping.insert(key, EntryPos { offset, size });
+        shared.live_bytes += size;
+        if let Some(old) = shared.mapping.insert(key, EntryPos { offset, size }) {
+            shared.live_bytes -= old.size;
+        }
 
         Ok(())
     }
 
     fn get(&self, key: String) -> Result<Option<String>> {
-        let shared = self.shared.lock().unwrap();
-        match shared.mapping.get(&key) {
-            None => Ok(None),
-            Some(meta) => {
-                let entry = Self::deserialize(&shared.file, meta)?;
-                Ok(entry.value)
-            }
+        let mut shared = self.shared.lock().unwrap();
+        if let Some(value) = shared.cache.get(&key) {
+            return Ok(Some(value));
+        }
+        let value = match shared.mapping.get(&key) {
+            None => None,
+            Some(meta) => Self::deserialize(&shared, meta)?.value,
+        };
+        if let Some(value) = &value {
+            shared.cache.insert(key, value.to_owned());
         }
+        Ok(value)
     }
 
     fn remove(&self, key: String) -> Result<()> {
         let mut shared = self.shared.lock().unwrap();
-        if shared.mapping.get(&key).is_none() {
+        if !shared.mapping.contains_key(&key) {
             return Err(KvStoreError::RemoveNonexistingKey);
         }
 
+        shared.cache.invalidate(&key);
         self.append_entry(
             &mut shared,
             Entry {
@@ -243,7 +367,83 @@ impl KvsEngine for KvStore {
             },
         )?;
         shared.stat.total += 1;
-        shared.mapping.remove(&key);
+        if let Some(old) = shared.mapping.remove(&key) {
+            shared.live_bytes -= old.size;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.shared.lock().unwrap().file.sync_all()?;
+        Ok(())
+    }
+
+    /// Visits the pairs as they were when the scan started. The store is
+    /// only locked to list them, so writes go on during the scan.
+    fn scan<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        // The log is append-only, and compaction writes a new file, so the
+        // listed entries stay readable from this file and mapping
+        let (mut metas, file, sealed, file_len) = {
+            let shared = self.shared.lock().unwrap();
+            let metas: Vec<EntryPos> = shared.mapping.values().copied().collect();
+            let file_len = shared.file.metadata()?.len() as usize;
+            (
+                metas,
+                shared.file.try_clone()?,
+                shared.sealed.clone(),
+                file_len,
+            )
+        };
+        metas.sort_by_key(|meta| meta.offset);
+
+        // Visit entries in log order. Past the sealed prefix, read
+        // `READ_AHEAD` bytes at a time.
+        // Bypasses the value cache, so a scan does not evict hot keys.
+        let mut buf = Vec::new();
+        let mut buf_offset = 0;
+        for meta in metas.iter() {
+            let EntryPos { offset, size } = *meta;
+            let bytes = match Self::sealed_slice(&sealed, meta) {
+                Some(bytes) => bytes,
+                None => {
+                    if offset < buf_offset || offset + size > buf_offset + buf.len() {
+                        buf_offset = offset;
+                        buf.resize(READ_AHEAD.max(size).min(file_len - offset), 0);
+                        file.read_exact_at(&mut buf, offset as u64)?;
+                    }
+                    let start = offset - buf_offset;
+                    &buf[start..start + size]
+                }
+            };
+            let entry: Entry = serde_json::from_slice(bytes)?;
+            if let Some(value) = entry.value {
+                f(entry.key, value)?;
+            }
+        }
         Ok(())
     }
+
+    fn stats(&self) -> EngineStats {
+        let shared = self.shared.lock().unwrap();
+        let size = shared.file.metadata().map_or(0, |m| m.len());
+        EngineStats {
+            compactions: shared.compactions,
+            bytes_reclaimed: shared.bytes_reclaimed,
+            size,
+            keys: Some(shared.mapping.len() as u64),
+            live_bytes: Some(shared.live_bytes as u64),
+            segments: vec![Segment {
+                name: "data.json".to_owned(),
+                size,
+            }],
+        }
+    }
+
+    fn compact(&self) -> Result<()> {
+        let mut shared = self.shared.lock().unwrap();
+        self.compact_log(&mut shared)
+    }
 }
@@ -0,0 +1,58 @@
+// Bloom filter stored in every SSTable, so that most lookups of keys that are
+// not in a table never read a block.
+
+const BITS_PER_KEY: usize = 10;
+const NUM_HASHES: u32 = 7;
+
+#[derive(Debug)]
+pub(super) struct Bloom {
+    bits: Vec<u8>,
+}
+
+impl Bloom {
+    // a filter sized for `keys` keys
+    pub fn new(keys: usize) -> Self {
+        let bytes = (keys * BITS_PER_KEY).div_ceil(8);
+        Self {
+            bits: vec![0; bytes.max(1)],
+        }
+    }
+
+    pub fn from_bytes(bits: Vec<u8>) -> Self {
+        Self { bits }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        for bit in self.bit_positions(key) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    // false if `key` is definitely not in the set
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.bit_positions(key)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    // Double hashing: the i-th position is h1 + i * h2.
+    // The hash is persisted, so it must not depend on the std hasher.
+    fn bit_positions(&self, key: &str) -> impl Iterator<Item = usize> {
+        let hash = fnv1a(key.as_bytes());
+        let (h1, h2) = (hash as u32, (hash >> 32) as u32);
+        let num_bits = self.bits.len() * 8;
+        (0..NUM_HASHES).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) as usize % num_bits)
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
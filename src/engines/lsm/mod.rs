@@ -0,0 +1,452 @@
+use self::sstable::{Item, SsTable};
+use crate::{EngineStats, KvStoreError, KvsEngine, Result, Segment};
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{Read, Write},
+    iter::Peekable,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+mod bloom;
+mod sstable;
+
+const WAL_FILE: &str = "lsm.wal";
+const MANIFEST_FILE: &str = "lsm.manifest";
+
+/// Tuning knobs of `LsmKvsStore`.
+#[derive(Clone, Debug)]
+pub struct LsmOptions {
+    /// Flush the memtable to an SSTable once its keys and values take this
+    /// many bytes.
+    pub memtable_bytes: usize,
+    /// Merge SSTables of about the same size into one once there are this
+    /// many, at least 2. Each merge makes a table this many times larger.
+    pub compaction_trigger: usize,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        Self {
+            memtable_bytes: 4 << 20,
+            compaction_trigger: 4,
+        }
+    }
+}
+
+// Write-ahead log entry. Remove is {key, None}.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    key: String,
+    value: Option<String>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    wal: fs::File,
+    // a `None` value is a tombstone
+    memtable: BTreeMap<String, Option<String>>,
+    memtable_bytes: usize,
+    // oldest first
+    tables: Vec<(u64, SsTable)>,
+    next_id: u64,
+    compactions: u64,
+    bytes_reclaimed: u64,
+}
+
+/// `LsmKvsStore` stores key-value pairs in a log-structured merge tree.
+///
+/// Writes go to a write-ahead log and a sorted in-memory memtable. A full
+/// memtable is flushed to an immutable SSTable, with a block index and a bloom
+/// filter. SSTables are compacted in size tiers: once the newest
+/// `LsmOptions::compaction_trigger` of them are about the same size, they are
+/// merged into one of the next tier. A value is so rewritten once per tier,
+/// and there are at most `compaction_trigger - 1` SSTables in each. The live
+/// SSTables are listed in a manifest, which is replaced atomically.
+///
+/// Unlike `KvStore`, the keys do not need to fit in memory, and `scan` visits
+/// them in order.
+#[derive(Clone, Debug)]
+pub struct LsmKvsStore {
+    dir_path: Arc<PathBuf>,
+    options: LsmOptions,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl LsmKvsStore {
+    /// open a store
+    pub fn open(dir_path: &Path) -> Result<Self> {
+        Self::open_with(dir_path, LsmOptions::default())
+    }
+
+    /// open a store with the given options
+    pub fn open_with(dir_path: &Path, options: LsmOptions) -> Result<Self> {
+        let ids = Self::read_manifest(dir_path)?;
+        Self::remove_unlisted_tables(dir_path, &ids)?;
+
+        let mut tables = Vec::new();
+        for id in ids.iter() {
+            tables.push((*id, SsTable::open(&table_path(dir_path, *id))?));
+        }
+        let next_id = ids.iter().max().map_or(0, |id| id + 1);
+
+        let mut wal = fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(dir_path.join(WAL_FILE))?;
+        // read as bytes, as a torn write may split a character
+        let mut content = Vec::new();
+        wal.read_to_end(&mut content)?;
+        let mut memtable = BTreeMap::new();
+        let mut memtable_bytes = 0;
+        let mut entries = Deserializer::from_slice(&content).into_iter::<Entry>();
+        let mut valid_len = 0;
+        while let Some(Ok(entry)) = entries.next() {
+            valid_len = entries.byte_offset();
+            memtable_bytes += entry_size(&entry.key, entry.value.as_deref());
+            memtable.insert(entry.key, entry.value);
+        }
+        // A torn write at the end of the log is cut off, so that new entries
+        // are not appended after it and lost at the next replay
+        if valid_len < content.len() {
+            wal.set_len(valid_len as u64)?;
+            wal.sync_all()?;
+        }
+
+        Ok(Self {
+            dir_path: Arc::new(dir_path.to_owned()),
+            options,
+            shared: Arc::new(Mutex::new(Shared {
+                wal,
+                memtable,
+                memtable_bytes,
+                tables,
+                next_id,
+                compactions: 0,
+                bytes_reclaimed: 0,
+            })),
+        })
+    }
+
+    // ids of the live tables, oldest first
+    fn read_manifest(dir_path: &Path) -> Result<Vec<u64>> {
+        let path = dir_path.join(MANIFEST_FILE);
+        if !path.try_exists()? {
+            return Ok(Vec::new());
+        }
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    fn write_manifest(&self, shared: &Shared) -> Result<()> {
+        let ids: Vec<u64> = shared.tables.iter().map(|(id, _)| *id).collect();
+        let tmp_path = self.dir_path.join("lsm.manifest.tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(&ids)?)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, self.dir_path.join(MANIFEST_FILE))?;
+        Ok(())
+    }
+
+    // Tables left behind by a flush or compaction that did not finish
+    fn remove_unlisted_tables(dir_path: &Path, ids: &[u64]) -> Result<()> {
+        for dir_entry in fs::read_dir(dir_path)? {
+            let path = dir_entry?.path();
+            let name = path.file_name().and_then(|name| name.to_str());
+            let id = name
+                .and_then(|name| name.strip_prefix("lsm-"))
+                .and_then(|name| name.strip_suffix(".sst"))
+                .and_then(|id| id.parse::<u64>().ok());
+            if let Some(id) = id {
+                if !ids.contains(&id) {
+                    fs::remove_file(path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Append to the WAL and apply to the memtable. May flush.
+    // Assumes that caller holds the mutex.
+    fn write(&self, shared: &mut Shared, entry: Entry) -> Result<()> {
+        shared.wal.write_all(&serde_json::to_vec(&entry)?)?;
+        shared.memtable_bytes += entry_size(&entry.key, entry.value.as_deref());
+        shared.memtable.insert(entry.key, entry.value);
+
+        if shared.memtable_bytes >= self.options.memtable_bytes {
+            self.flush_memtable(shared)?;
+        }
+        Ok(())
+    }
+
+    // Write the memtable to a new table, and start a new WAL. May compact.
+    fn flush_memtable(&self, shared: &mut Shared) -> Result<()> {
+        if shared.memtable.is_empty() {
+            return Ok(());
+        }
+
+        let id = shared.next_id;
+        shared.next_id += 1;
+        // Nothing older can hide behind a tombstone in the first table
+        let keep_tombstones = !shared.tables.is_empty();
+        let items = shared
+            .memtable
+            .iter()
+            .filter(|(_, value)| keep_tombstones || value.is_some())
+            .map(|(key, value)| Ok((key.to_owned(), value.to_owned())));
+        let table = SsTable::write(
+            &table_path(&self.dir_path, id),
+            items,
+            shared.memtable.len(),
+        )?;
+        shared.tables.push((id, table));
+        self.write_manifest(shared)?;
+
+        // Replaying the old WAL after a crash here would only redo the writes
+        shared.wal.set_len(0)?;
+        shared.memtable.clear();
+        shared.memtable_bytes = 0;
+
+        self.compact_tiers(shared)
+    }
+
+    // Merge the newest tables while there are `compaction_trigger` of them in
+    // the same tier. A merge can fill the next tier in turn.
+    fn compact_tiers(&self, shared: &mut Shared) -> Result<()> {
+        let trigger = self.options.compaction_trigger.max(2);
+        while let Some((_, last)) = shared.tables.last() {
+            let tier = self.tier(last);
+            let same_tier = shared
+                .tables
+                .iter()
+                .rev()
+                .take_while(|(_, table)| self.tier(table) == tier)
+                .count();
+            if same_tier < trigger {
+                break;
+            }
+            self.compact_tables(shared, shared.tables.len() - same_tier)?;
+        }
+        Ok(())
+    }
+
+    // The size tier of a table. Tables of tier n hold about
+    // `compaction_trigger^n` memtables.
+    fn tier(&self, table: &SsTable) -> u32 {
+        let trigger = self.options.compaction_trigger.max(2) as u64;
+        let size = table_size(table);
+        let mut bound = (self.options.memtable_bytes.max(1) as u64).saturating_mul(trigger);
+        let mut tier = 0;
+        while size >= bound && bound < u64::MAX {
+            tier += 1;
+            bound = bound.saturating_mul(trigger);
+        }
+        tier
+    }
+
+    // Merge the tables from `start` on into one, dropping overwritten values.
+    // Tombstones are dropped too when no older table is left for them to
+    // hide a value in.
+    fn compact_tables(&self, shared: &mut Shared, start: usize) -> Result<()> {
+        let id = shared.next_id;
+        shared.next_id += 1;
+        let keep_tombstones = start > 0;
+        let table = {
+            let merged = &shared.tables[start..];
+            let sources = merged
+                .iter()
+                .rev()
+                .map(|(_, table)| Box::new(table.iter()) as Box<dyn Iterator<Item = _>>)
+                .collect();
+            let items = Merge::new(sources)
+                .filter(|item| keep_tombstones || !matches!(item, Ok((_, None))));
+            SsTable::write(&table_path(&self.dir_path, id), items, live_keys(merged))?
+        };
+
+        let old_tables = shared.tables.split_off(start);
+        shared.tables.push((id, table));
+        self.write_manifest(shared)?;
+        let old_size = tables_size(&old_tables);
+        for (_, table) in old_tables {
+            fs::remove_file(table.path())?;
+        }
+        shared.compactions += 1;
+        shared.bytes_reclaimed += old_size.saturating_sub(tables_size(&shared.tables[start..]));
+        Ok(())
+    }
+
+    // Memtable first, then tables from newest to oldest
+    fn lookup(shared: &Shared, key: &str) -> Result<Option<String>> {
+        if let Some(value) = shared.memtable.get(key) {
+            return Ok(value.to_owned());
+        }
+        for (_, table) in shared.tables.iter().rev() {
+            if let Some(value) = table.get(key)? {
+                return Ok(value);
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl KvsEngine for LsmKvsStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let mut shared = self.shared.lock().unwrap();
+        self.write(
+            &mut shared,
+            Entry {
+                key,
+                value: Some(value),
+            },
+        )
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let shared = self.shared.lock().unwrap();
+        Self::lookup(&shared, &key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let mut shared = self.shared.lock().unwrap();
+        if Self::lookup(&shared, &key)?.is_none() {
+            return Err(KvStoreError::RemoveNonexistingKey);
+        }
+        self.write(&mut shared, Entry { key, value: None })
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.shared.lock().unwrap().wal.sync_all()?;
+        Ok(())
+    }
+
+    /// Pairs are visited in key order.
+    fn scan<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        let shared = self.shared.lock().unwrap();
+        let mut sources: Vec<Box<dyn Iterator<Item = Result<Item>>>> = vec![Box::new(
+            shared
+                .memtable
+                .iter()
+                .map(|(key, value)| Ok((key.to_owned(), value.to_owned()))),
+        )];
+        for (_, table) in shared.tables.iter().rev() {
+            sources.push(Box::new(table.iter()));
+        }
+
+        for item in Merge::new(sources) {
+            if let (key, Some(value)) = item? {
+                f(key, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Keys and live bytes are unknown without merging the tables
+    fn stats(&self) -> EngineStats {
+        let shared = self.shared.lock().unwrap();
+        let mut segments: Vec<Segment> = shared
+            .tables
+            .iter()
+            .map(|(_, table)| Segment {
+                name: file_name(table.path()),
+                size: table_size(table),
+            })
+            .collect();
+        segments.push(Segment {
+            name: WAL_FILE.to_owned(),
+            size: shared.wal.metadata().map_or(0, |m| m.len()),
+        });
+        EngineStats {
+            compactions: shared.compactions,
+            bytes_reclaimed: shared.bytes_reclaimed,
+            size: segments.iter().map(|segment| segment.size).sum(),
+            segments,
+            ..EngineStats::default()
+        }
+    }
+
+    /// Flushes the memtable, and merges the tables if there are several.
+    fn compact(&self) -> Result<()> {
+        let mut shared = self.shared.lock().unwrap();
+        self.flush_memtable(&mut shared)?;
+        if shared.tables.len() > 1 {
+            self.compact_tables(&mut shared, 0)?;
+        }
+        Ok(())
+    }
+}
+
+// Merges sorted sources into one sorted stream. When several sources hold the
+// same key, the item from the first source wins.
+struct Merge<'a> {
+    sources: Vec<Peekable<Box<dyn Iterator<Item = Result<Item>> + 'a>>>,
+}
+
+impl<'a> Merge<'a> {
+    fn new(sources: Vec<Box<dyn Iterator<Item = Result<Item>> + 'a>>) -> Self {
+        Self {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+impl<'a> Iterator for Merge<'a> {
+    type Item = Result<Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The number of sources is small, a linear scan is enough
+        let mut min: Option<(usize, &str)> = None;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            match source.peek() {
+                None => {}
+                Some(Err(_)) => return source.next(),
+                Some(Ok((key, _))) if min.is_none_or(|(_, min_key)| key.as_str() < min_key) => {
+                    min = Some((i, key));
+                }
+                Some(Ok(_)) => {}
+            }
+        }
+
+        let (winner, key) = min?;
+        let key = key.to_owned();
+        let item = self.sources[winner].next();
+        for source in self.sources[winner + 1..].iter_mut() {
+            if matches!(source.peek(), Some(Ok((k, _))) if *k == key) {
+                source.next();
+            }
+        }
+        item
+    }
+}
+
+fn table_path(dir_path: &Path, id: u64) -> PathBuf {
+    dir_path.join(format!("lsm-{:08}.sst", id))
+}
+
+// bytes of a table on disk
+fn table_size(table: &SsTable) -> u64 {
+    table.path().metadata().map_or(0, |m| m.len())
+}
+
+fn tables_size(tables: &[(u64, SsTable)]) -> u64 {
+    tables.iter().map(|(_, table)| table_size(table)).sum()
+}
+
+// An upper bound on the number of keys of tables, to size bloom filters
+fn live_keys(tables: &[(u64, SsTable)]) -> usize {
+    (tables_size(tables) / 16) as usize
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map_or_else(String::new, |name| name.to_string_lossy().into_owned())
+}
+
+fn entry_size(key: &str, value: Option<&str>) -> usize {
+    key.len() + value.map_or(0, str::len)
+}
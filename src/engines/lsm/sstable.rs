@@ -0,0 +1,269 @@
+// An SSTable is an immutable file of entries sorted by key.
+//
+// Layout:
+// - data blocks of about `BLOCK_SIZE` bytes, each a run of entries
+// - the index: for every block, its first key, offset and length
+// - the bloom filter over all keys
+// - a fixed-size footer locating the index and the filter
+//
+// An entry is the key length (u32), the key, a tag (0 for a tombstone, 1 for
+// a value), and for a value, the value length (u32) and the value. All
+// integers are big-endian.
+use super::bloom::Bloom;
+use crate::{KvStoreError, Result};
+use std::{
+    fs,
+    io::{BufWriter, Write},
+    os::unix::prelude::FileExt,
+    path::{Path, PathBuf},
+};
+
+const BLOCK_SIZE: usize = 4096;
+const MAGIC: &[u8; 8] = b"KVSSST01";
+// index offset, index length, bloom offset, bloom length, magic
+const FOOTER_SIZE: usize = 8 + 8 + 8 + 8 + 8;
+
+// A key mapped to a value, or to a tombstone.
+pub(super) type Item = (String, Option<String>);
+
+#[derive(Debug)]
+struct BlockHandle {
+    first_key: String,
+    offset: u64,
+    len: u64,
+}
+
+#[derive(Debug)]
+pub(super) struct SsTable {
+    path: PathBuf,
+    file: fs::File,
+    index: Vec<BlockHandle>,
+    bloom: Bloom,
+}
+
+impl SsTable {
+    // Write `items`, which must be sorted by key, to a new table at `path`.
+    // `len_hint` sizes the bloom filter.
+    pub fn write<I>(path: &Path, items: I, len_hint: usize) -> Result<Self>
+    where
+        I: Iterator<Item = Result<Item>>,
+    {
+        let file = fs::File::create(path)?;
+        let mut writer = BufWriter::new(&file);
+        let mut index = Vec::new();
+        let mut bloom = Bloom::new(len_hint);
+        let mut block = Vec::new();
+        let mut first_key = String::new();
+        let mut offset = 0;
+
+        for item in items {
+            let (key, value) = item?;
+            bloom.insert(&key);
+            if block.is_empty() {
+                first_key = key.clone();
+            }
+            encode_item(&mut block, &key, value.as_deref());
+
+            if block.len() >= BLOCK_SIZE {
+                writer.write_all(&block)?;
+                index.push(BlockHandle {
+                    first_key: std::mem::take(&mut first_key),
+                    offset,
+                    len: block.len() as u64,
+                });
+                offset += block.len() as u64;
+                block.clear();
+            }
+        }
+        if !block.is_empty() {
+            writer.write_all(&block)?;
+            index.push(BlockHandle {
+                first_key,
+                offset,
+                len: block.len() as u64,
+            });
+            offset += block.len() as u64;
+        }
+
+        let mut index_buf = Vec::new();
+        for handle in index.iter() {
+            put_str(&mut index_buf, &handle.first_key);
+            index_buf.extend_from_slice(&handle.offset.to_be_bytes());
+            index_buf.extend_from_slice(&handle.len.to_be_bytes());
+        }
+        writer.write_all(&index_buf)?;
+        writer.write_all(bloom.as_bytes())?;
+
+        let index_offset = offset;
+        let bloom_offset = index_offset + index_buf.len() as u64;
+        writer.write_all(&index_offset.to_be_bytes())?;
+        writer.write_all(&(index_buf.len() as u64).to_be_bytes())?;
+        writer.write_all(&bloom_offset.to_be_bytes())?;
+        writer.write_all(&(bloom.as_bytes().len() as u64).to_be_bytes())?;
+        writer.write_all(MAGIC)?;
+        writer.flush()?;
+        drop(writer);
+        file.sync_all()?;
+
+        Self::open(path)
+    }
+
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = fs::File::open(path)?;
+        let file_len = file.metadata()?.len();
+        if file_len < FOOTER_SIZE as u64 {
+            return Err(KvStoreError::CorruptedTable);
+        }
+
+        let mut footer = [0u8; FOOTER_SIZE];
+        file.read_exact_at(&mut footer, file_len - FOOTER_SIZE as u64)?;
+        if &footer[32..] != MAGIC {
+            return Err(KvStoreError::CorruptedTable);
+        }
+        let mut pos = 0;
+        let index_offset = get_u64(&footer, &mut pos)?;
+        let index_len = get_u64(&footer, &mut pos)?;
+        let bloom_offset = get_u64(&footer, &mut pos)?;
+        let bloom_len = get_u64(&footer, &mut pos)?;
+        // a corrupt footer must not make `read_at` allocate past the file,
+        // and the filter needs at least one bit
+        let body_len = file_len - FOOTER_SIZE as u64;
+        if !within(index_offset, index_len, body_len)
+            || !within(bloom_offset, bloom_len, body_len)
+            || bloom_len == 0
+        {
+            return Err(KvStoreError::CorruptedTable);
+        }
+
+        let index_buf = read_at(&file, index_offset, index_len)?;
+        let mut index = Vec::new();
+        let mut pos = 0;
+        while pos < index_buf.len() {
+            let handle = BlockHandle {
+                first_key: get_str(&index_buf, &mut pos)?,
+                offset: get_u64(&index_buf, &mut pos)?,
+                len: get_u64(&index_buf, &mut pos)?,
+            };
+            if !within(handle.offset, handle.len, index_offset) {
+                return Err(KvStoreError::CorruptedTable);
+            }
+            index.push(handle);
+        }
+        let bloom = Bloom::from_bytes(read_at(&file, bloom_offset, bloom_len)?);
+
+        Ok(Self {
+            path: path.to_owned(),
+            file,
+            index,
+            bloom,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // `None` if the table knows nothing about `key`, `Some(None)` if it holds
+    // a tombstone for it.
+    pub fn get(&self, key: &str) -> Result<Option<Option<String>>> {
+        if !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        let block = match self
+            .index
+            .partition_point(|handle| handle.first_key.as_str() <= key)
+        {
+            0 => return Ok(None),
+            i => &self.index[i - 1],
+        };
+
+        let buf = read_at(&self.file, block.offset, block.len)?;
+        let mut pos = 0;
+        while pos < buf.len() {
+            let (k, v) = decode_item(&buf, &mut pos)?;
+            if k == key {
+                return Ok(Some(v));
+            }
+            if k.as_str() > key {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    // All items in key order, read one block at a time.
+    pub fn iter(&self) -> impl Iterator<Item = Result<Item>> + '_ {
+        self.index.iter().flat_map(move |handle| {
+            let items = read_at(&self.file, handle.offset, handle.len).and_then(|buf| {
+                let mut items = Vec::new();
+                let mut pos = 0;
+                while pos < buf.len() {
+                    items.push(decode_item(&buf, &mut pos)?);
+                }
+                Ok(items)
+            });
+            match items {
+                Ok(items) => items.into_iter().map(Ok).collect::<Vec<_>>(),
+                Err(err) => vec![Err(err)],
+            }
+        })
+    }
+}
+
+// whether `len` bytes at `offset` end by `end`
+fn within(offset: u64, len: u64, end: u64) -> bool {
+    offset.checked_add(len).is_some_and(|stop| stop <= end)
+}
+
+fn read_at(file: &fs::File, offset: u64, len: u64) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len as usize];
+    file.read_exact_at(&mut buf, offset)?;
+    Ok(buf)
+}
+
+fn encode_item(buf: &mut Vec<u8>, key: &str, value: Option<&str>) {
+    put_str(buf, key);
+    match value {
+        None => buf.push(0),
+        Some(value) => {
+            buf.push(1);
+            put_str(buf, value);
+        }
+    }
+}
+
+fn decode_item(buf: &[u8], pos: &mut usize) -> Result<Item> {
+    let key = get_str(buf, pos)?;
+    let tag = *buf.get(*pos).ok_or(KvStoreError::CorruptedTable)?;
+    *pos += 1;
+    match tag {
+        0 => Ok((key, None)),
+        1 => Ok((key, Some(get_str(buf, pos)?))),
+        _ => Err(KvStoreError::CorruptedTable),
+    }
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn get_bytes<'a>(buf: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8]> {
+    let bytes = buf
+        .get(*pos..*pos + len)
+        .ok_or(KvStoreError::CorruptedTable)?;
+    *pos += len;
+    Ok(bytes)
+}
+
+fn get_str(buf: &[u8], pos: &mut usize) -> Result<String> {
+    let len = u32::from_be_bytes(get_bytes(buf, pos, 4)?.try_into().unwrap());
+    let bytes = get_bytes(buf, pos, len as usize)?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| KvStoreError::CorruptedTable)
+}
+
+fn get_u64(buf: &[u8], pos: &mut usize) -> Result<u64> {
+    Ok(u64::from_be_bytes(
+        get_bytes(buf, pos, 8)?.try_into().unwrap(),
+    ))
+}
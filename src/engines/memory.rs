@@ -0,0 +1,190 @@
+use crate::{EngineStats, KvStoreError, KvsEngine, Result};
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+/// Which key to evict when a bounded `MemoryKvsStore` is full.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EvictionPolicy {
+    /// Least recently used
+    Lru,
+    /// Least frequently used, ties broken by least recently used
+    Lfu,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "lru" => Ok(Self::Lru),
+            "lfu" => Ok(Self::Lfu),
+            _ => Err(format!("unknown eviction policy: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Slot {
+    value: String,
+    rank: (u64, u64),
+}
+
+#[derive(Debug)]
+struct Shared {
+    map: HashMap<String, Slot>,
+    // eviction order: the first key is evicted first
+    ranks: BTreeMap<(u64, u64), String>,
+    tick: u64,
+}
+
+/// `MemoryKvsStore` keeps key-value pairs in memory only, without touching
+/// the filesystem. Nothing survives the store being dropped.
+///
+/// The store can be bounded to a maximum number of keys, in which case a
+/// `set` of a new key evicts another one according to an `EvictionPolicy`.
+///
+/// The pairs are behind a single lock. Reads of an unbounded store share it
+/// and run concurrently. In a bounded store a read also moves its key in the
+/// eviction order, which every key shares, so reads take the lock exclusively
+/// like writes.
+#[derive(Clone, Debug)]
+pub struct MemoryKvsStore {
+    max_keys: Option<(usize, EvictionPolicy)>,
+    shared: Arc<RwLock<Shared>>,
+}
+
+impl MemoryKvsStore {
+    /// creates an unbounded store
+    pub fn new() -> Self {
+        Self {
+            max_keys: None,
+            shared: Arc::new(RwLock::new(Shared {
+                map: HashMap::new(),
+                ranks: BTreeMap::new(),
+                tick: 0,
+            })),
+        }
+    }
+
+    /// creates a store holding at most `max_keys` keys
+    pub fn with_max_keys(max_keys: usize, policy: EvictionPolicy) -> Self {
+        Self {
+            max_keys: Some((max_keys.max(1), policy)),
+            ..Self::new()
+        }
+    }
+
+    // Record an access to `key`, moving it in the eviction order.
+    // `uses` is the number of accesses before this one.
+    // Unbounded stores keep no eviction order.
+    fn touch(&self, shared: &mut Shared, key: &str, uses: u64) -> (u64, u64) {
+        shared.tick += 1;
+        let rank = match self.max_keys {
+            None => return (0, 0),
+            Some((_, EvictionPolicy::Lru)) => (shared.tick, uses + 1),
+            Some((_, EvictionPolicy::Lfu)) => (uses + 1, shared.tick),
+        };
+        shared.ranks.insert(rank, key.to_owned());
+        rank
+    }
+
+    fn uses(&self, rank: (u64, u64)) -> u64 {
+        match self.max_keys {
+            Some((_, EvictionPolicy::Lfu)) => rank.0,
+            _ => rank.1,
+        }
+    }
+}
+
+impl Default for MemoryKvsStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KvsEngine for MemoryKvsStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let mut shared = self.shared.write().unwrap();
+        let uses = match shared.map.remove(&key) {
+            Some(slot) => {
+                shared.ranks.remove(&slot.rank);
+                self.uses(slot.rank)
+            }
+            None => {
+                if let Some((max_keys, _)) = self.max_keys {
+                    if shared.map.len() >= max_keys {
+                        if let Some((_, victim)) = shared.ranks.pop_first() {
+                            shared.map.remove(&victim);
+                        }
+                    }
+                }
+                0
+            }
+        };
+        let rank = self.touch(&mut shared, &key, uses);
+        shared.map.insert(key, Slot { value, rank });
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        if self.max_keys.is_none() {
+            let shared = self.shared.read().unwrap();
+            return Ok(shared.map.get(&key).map(|slot| slot.value.clone()));
+        }
+
+        let mut shared = self.shared.write().unwrap();
+        let (old_rank, value) = match shared.map.get(&key) {
+            None => return Ok(None),
+            Some(slot) => (slot.rank, slot.value.clone()),
+        };
+        shared.ranks.remove(&old_rank);
+        let rank = self.touch(&mut shared, &key, self.uses(old_rank));
+        shared.map.get_mut(&key).unwrap().rank = rank;
+        Ok(Some(value))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let mut shared = self.shared.write().unwrap();
+        match shared.map.remove(&key) {
+            None => Err(KvStoreError::RemoveNonexistingKey),
+            Some(slot) => {
+                shared.ranks.remove(&slot.rank);
+                Ok(())
+            }
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn scan<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        let shared = self.shared.read().unwrap();
+        for (key, slot) in shared.map.iter() {
+            f(key.to_owned(), slot.value.to_owned())?;
+        }
+        Ok(())
+    }
+
+    // the bytes of the keys and values held
+    fn stats(&self) -> EngineStats {
+        let shared = self.shared.read().unwrap();
+        let size = shared
+            .map
+            .iter()
+            .map(|(k, slot)| k.len() + slot.value.len())
+            .sum::<usize>();
+        EngineStats {
+            size: size as u64,
+            keys: Some(shared.map.len() as u64),
+            live_bytes: Some(size as u64),
+            ..EngineStats::default()
+        }
+    }
+}
@@ -1,10 +1,15 @@
-use crate::Result;
+use crate::{KvStoreError, Result};
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, path::Path, str::FromStr};
 
-pub use kv::KvStore;
 pub use crate::engines::sled::SledKvsStore;
+pub use cache::CacheStats;
+pub use kv::{KvStore, KvStoreOptions};
+pub use lsm::{LsmKvsStore, LsmOptions};
+pub use memory::{EvictionPolicy, MemoryKvsStore};
 
 /// A storage engine that can handle get, set and remove.
-/// 
+///
 /// - Why requiring the Clone trait?
 ///   To be passed into multiple threads.
 /// - Why changing from `&mut self` to `&self`?
@@ -17,7 +22,122 @@ pub trait KvsEngine: Clone + Send + 'static {
     fn get(&self, key: String) -> Result<Option<String>>;
     /// remove
     fn remove(&self, key: String) -> Result<()>;
+    /// make every write so far durable
+    fn flush(&self) -> Result<()>;
+    /// call `f` on every key-value pair in the store, in no particular order.
+    /// Stops at the first error returned by `f`.
+    fn scan<F>(&self, f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>;
+    /// compaction counters and size of the store, for monitoring
+    fn stats(&self) -> EngineStats {
+        EngineStats::default()
+    }
+    /// reclaim the space of overwritten and removed values now. Does nothing
+    /// for engines that do not compact.
+    fn compact(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
+/// Counters and size of a store, as reported by `KvsEngine::stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EngineStats {
+    /// compactions run since the store was opened
+    pub compactions: u64,
+    /// bytes freed by those compactions
+    pub bytes_reclaimed: u64,
+    /// bytes the store takes on disk, or in memory for `MemoryKvsStore`
+    pub size: u64,
+    /// number of keys, if the engine knows it without reading the store
+    pub keys: Option<u64>,
+    /// bytes of `size` holding the current values, if the engine knows it.
+    /// The rest is reclaimed by compaction.
+    pub live_bytes: Option<u64>,
+    /// the files of the store, oldest first
+    pub segments: Vec<Segment>,
+}
+
+/// A file of a store, as listed by `EngineStats::segments`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Segment {
+    /// file name in the data directory
+    pub name: String,
+    /// bytes on disk
+    pub size: u64,
+}
+
+/// The kinds of engine that can own a data directory.
+///
+/// The kind is recorded in the `engine` marker file of the directory, so that
+/// a directory written by one engine is never opened by another.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EngineKind {
+    /// `KvStore`
+    Kvs,
+    /// `SledKvsStore`
+    Sled,
+    /// `LsmKvsStore`
+    Lsm,
+    /// `MemoryKvsStore`. It keeps nothing in the directory, and never owns it.
+    Memory,
+}
+
+impl EngineKind {
+    /// read the engine marker in `dir_path`, if any
+    pub fn current(dir_path: &Path) -> Result<Option<Self>> {
+        let engine_file = dir_path.join("engine");
+
+        if !engine_file.try_exists()? {
+            return Ok(None);
+        }
+
+        fs::read_to_string(engine_file)?.parse().map(Some)
+    }
+
+    /// whether the engine keeps its data in the directory
+    pub fn is_persistent(&self) -> bool {
+        *self != Self::Memory
+    }
+
+    /// write the engine marker in `dir_path`, atomically replacing any
+    /// previous one
+    pub fn write_marker(&self, dir_path: &Path) -> Result<()> {
+        let tmp_path = dir_path.join("engine.tmp");
+        fs::write(&tmp_path, self.to_string())?;
+        fs::File::open(&tmp_path)?.sync_all()?;
+        fs::rename(tmp_path, dir_path.join("engine"))?;
+        Ok(())
+    }
+}
+
+impl FromStr for EngineKind {
+    type Err = KvStoreError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "kvs" => Ok(Self::Kvs),
+            "sled" => Ok(Self::Sled),
+            "lsm" => Ok(Self::Lsm),
+            "memory" => Ok(Self::Memory),
+            _ => Err(KvStoreError::WrongEngine),
+        }
+    }
+}
+
+impl fmt::Display for EngineKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Kvs => write!(f, "kvs"),
+            Self::Sled => write!(f, "sled"),
+            Self::Lsm => write!(f, "lsm"),
+            Self::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+mod cache;
 mod kv;
+mod lsm;
+mod memory;
 mod sled;
@@ -1,13 +1,13 @@
-use crate::{KvStoreError, KvsEngine, Result};
+use crate::{EngineStats, KvStoreError, KvsEngine, Result};
 
-///
+/// `SledKvsStore` is a `KvsEngine` backed by the sled database.
 #[derive(Clone)]
 pub struct SledKvsStore {
     store: sled::Db,
 }
 
 impl SledKvsStore {
-    ///
+    /// open a store
     pub fn open(dir_path: &std::path::Path) -> Result<Self> {
         let store = sled::open(dir_path)?;
         Ok(SledKvsStore { store })
@@ -25,7 +25,7 @@ impl KvsEngine for SledKvsStore {
         let v = self
             .store
             .get(key.as_bytes())?
-            .and_then(|v| Some(String::from_utf8(v.to_vec()).unwrap()));
+            .map(|v| String::from_utf8(v.to_vec()).unwrap());
         Ok(v)
     }
 
@@ -37,4 +37,30 @@ impl KvsEngine for SledKvsStore {
             Some(_) => Ok(()),
         }
     }
+
+    fn flush(&self) -> Result<()> {
+        self.store.flush()?;
+        Ok(())
+    }
+
+    fn scan<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        for kv in self.store.iter() {
+            let (k, v) = kv?;
+            let key = String::from_utf8(k.to_vec()).unwrap();
+            let value = String::from_utf8(v.to_vec()).unwrap();
+            f(key, value)?;
+        }
+        Ok(())
+    }
+
+    // sled compacts in the background, without reporting it
+    fn stats(&self) -> EngineStats {
+        EngineStats {
+            size: self.store.size_on_disk().unwrap_or(0),
+            ..EngineStats::default()
+        }
+    }
 }
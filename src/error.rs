@@ -1,23 +1,59 @@
 /// Errors
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
-///
-#[derive(Serialize, Deserialize, Debug)]
+/// Errors returned by the store, the server and the client.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
 pub enum KvStoreError {
-    ///
+    /// I/O failure
     IoError,
-    ///
+    /// Serialization or deserialization failure
     SerdeError,
-    ///
+    /// Removing a key that does not exist
     RemoveNonexistingKey,
-    ///
+    /// Error from the sled engine
     SledError,
-    ///
+    /// The data directory was created by a different engine
     WrongEngine,
-    ///
+    /// Failure building a thread pool
     ThreadPoolError,
+    /// Malformed input while loading a dump, or a key or value too long to
+    /// dump
+    InvalidDump,
+    /// The engines hold different pairs after a migration
+    MigrationMismatch,
+    /// Malformed SSTable in an LSM store
+    CorruptedTable,
+    /// The server could not listen on its address
+    BindError,
+    /// The server received a request it could not parse
+    InvalidRequest,
+    /// The server has too many connections open
+    ServerBusy,
+    /// A request was not received or answered in time
+    Timeout,
+    /// Malformed or unexpected frame in the binary protocol
+    InvalidFrame,
+    /// The client and the server have no protocol version in common
+    UnsupportedVersion,
+    /// Invalid TLS certificate or key, or failed TLS handshake
+    TlsError,
+    /// The client failed to authenticate, or may not access a key
+    PermissionDenied,
+    /// The client exceeded its rate limit, and should retry later
+    Throttled,
+    /// A set would exceed the storage quota of a key prefix
+    QuotaExceeded,
 }
 
+impl fmt::Display for KvStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for KvStoreError {}
+
 impl From<std::io::Error> for KvStoreError {
     fn from(_: std::io::Error) -> Self {
         Self::IoError
@@ -0,0 +1,96 @@
+use super::proto::{
+    kvs_client::KvsClient, operation::Op, BatchRequest, GetRequest, Operation, RemoveRequest,
+    ScanRequest, SetRequest, WatchEvent, WatchRequest,
+};
+use super::{from_status, Change};
+use crate::{KvStoreError, Result};
+use std::net::SocketAddr;
+use tonic::{codec::Streaming, transport::Channel};
+
+/// A client of `GrpcKvServer`, with the API of `AsyncKvClient`.
+///
+/// Clones share the connection, and calls may run concurrently.
+#[derive(Clone, Debug)]
+pub struct GrpcKvClient {
+    inner: KvsClient<Channel>,
+}
+
+/// The changes to the keys watched with `GrpcKvClient::watch`
+#[derive(Debug)]
+pub struct Watch {
+    events: Streaming<WatchEvent>,
+}
+
+impl GrpcKvClient {
+    /// creates a new client connected to `addr`
+    pub async fn connect(addr: SocketAddr) -> Result<Self> {
+        let inner = KvsClient::connect(format!("http://{}", addr))
+            .await
+            .map_err(|_| KvStoreError::IoError)?;
+        Ok(GrpcKvClient { inner })
+    }
+
+    /// get
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        let resp = self.inner.clone().get(GetRequest { key }).await;
+        Ok(resp.map_err(from_status)?.into_inner().value)
+    }
+
+    /// set
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        let resp = self.inner.clone().set(SetRequest { key, value }).await;
+        resp.map_err(from_status)?;
+        Ok(())
+    }
+
+    /// remove
+    pub async fn remove(&self, key: String) -> Result<()> {
+        let resp = self.inner.clone().remove(RemoveRequest { key }).await;
+        resp.map_err(from_status)?;
+        Ok(())
+    }
+
+    /// the pairs whose key starts with `prefix`, sorted by key
+    pub async fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        let resp = self.inner.clone().scan(ScanRequest { prefix }).await;
+        let mut stream = resp.map_err(from_status)?.into_inner();
+        let mut pairs = Vec::new();
+        while let Some(pair) = stream.message().await.map_err(from_status)? {
+            pairs.push((pair.key, pair.value));
+        }
+        Ok(pairs)
+    }
+
+    /// Apply `changes` in order, stopping at the first failure. The changes
+    /// before a failure stay applied.
+    pub async fn batch(&self, changes: Vec<Change>) -> Result<()> {
+        let operations = changes
+            .into_iter()
+            .map(|Change { key, value }| Operation {
+                op: Some(match value {
+                    Some(value) => Op::Set(SetRequest { key, value }),
+                    None => Op::Remove(RemoveRequest { key }),
+                }),
+            })
+            .collect();
+        let resp = self.inner.clone().batch(BatchRequest { operations }).await;
+        resp.map_err(from_status)?;
+        Ok(())
+    }
+
+    /// watch the changes to keys starting with `prefix`, from now on
+    pub async fn watch(&self, prefix: String) -> Result<Watch> {
+        let resp = self.inner.clone().watch(WatchRequest { prefix }).await;
+        Ok(Watch {
+            events: resp.map_err(from_status)?.into_inner(),
+        })
+    }
+}
+
+impl Watch {
+    /// the next change, or `None` once the server closed the stream
+    pub async fn next(&mut self) -> Result<Option<Change>> {
+        let event = self.events.message().await.map_err(from_status)?;
+        Ok(event.map(|WatchEvent { key, value }| Change { key, value }))
+    }
+}
@@ -0,0 +1,41 @@
+//! The gRPC service of `proto/kvs.proto`.
+//!
+//! `GrpcKvServer` serves a `Watched` engine, whose changes feed the `Watch`
+//! streams. Errors travel as a status whose message names the `KvStoreError`
+//! variant, which `GrpcKvClient` turns back into the error.
+
+pub use client::{GrpcKvClient, Watch};
+pub use server::GrpcKvServer;
+pub use watch::{Change, Watched};
+
+use crate::KvStoreError;
+use tonic::{Code, Status};
+
+mod client;
+mod server;
+mod watch;
+
+/// Messages and stubs generated from `proto/kvs.proto`
+#[allow(missing_docs, clippy::all)]
+pub mod proto {
+    include!("kvs.v1.rs");
+}
+
+fn to_status(err: KvStoreError) -> Status {
+    let code = match err {
+        KvStoreError::RemoveNonexistingKey => Code::NotFound,
+        KvStoreError::InvalidRequest => Code::InvalidArgument,
+        KvStoreError::ServerBusy => Code::Unavailable,
+        KvStoreError::Timeout => Code::DeadlineExceeded,
+        KvStoreError::PermissionDenied => Code::PermissionDenied,
+        KvStoreError::Throttled | KvStoreError::QuotaExceeded => Code::ResourceExhausted,
+        _ => Code::Internal,
+    };
+    Status::new(code, err.to_string())
+}
+
+// Statuses not sent by `to_status`, e.g. about the transport, are `IoError`s
+fn from_status(status: Status) -> KvStoreError {
+    let name = serde_json::Value::String(status.message().to_owned());
+    serde_json::from_value(name).unwrap_or(KvStoreError::IoError)
+}
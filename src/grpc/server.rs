@@ -0,0 +1,178 @@
+use super::proto::{
+    kvs_server::{Kvs, KvsServer},
+    operation::Op,
+    BatchRequest, BatchResponse, GetRequest, GetResponse, KeyValue, RemoveRequest, RemoveResponse,
+    ScanRequest, SetRequest, SetResponse, WatchEvent, WatchRequest,
+};
+use super::{to_status, Watched};
+use crate::{KvStoreError, KvsEngine, Result};
+use std::{future::Future, net::SocketAddr, pin::Pin};
+use tokio::net::TcpListener;
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, TcpListenerStream},
+    Stream, StreamExt,
+};
+use tonic::{transport::Server, Request, Response, Status};
+use tracing::warn;
+
+/// A gRPC server that serves the `Kvs` service of `proto/kvs.proto` against
+/// a `KvsEngine`, on the tokio runtime.
+pub struct GrpcKvServer<E: KvsEngine + Sync> {
+    service: Service<E>,
+    listener: TcpListener,
+}
+
+// The `Kvs` service. Engine calls block, and run on tokio's blocking thread
+// pool. A call that panics is answered with an internal error.
+struct Service<E: KvsEngine> {
+    engine: Watched<E>,
+}
+
+impl<E: KvsEngine + Sync> GrpcKvServer<E> {
+    /// listen on `addr`. Fails with `KvStoreError::BindError` if `addr`
+    /// cannot be listened on.
+    pub async fn bind(engine: Watched<E>, addr: SocketAddr) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await.map_err(|err| {
+            warn!("failed to bind {}: {}", addr, err);
+            KvStoreError::BindError
+        })?;
+        Ok(GrpcKvServer {
+            service: Service { engine },
+            listener,
+        })
+    }
+
+    /// the address the server listens on
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// serve requests until `signal` completes
+    pub async fn serve_with_shutdown<F>(self, signal: F) -> Result<()>
+    where
+        F: Future<Output = ()> + Send,
+    {
+        Server::builder()
+            .add_service(KvsServer::new(self.service))
+            .serve_with_incoming_shutdown(TcpListenerStream::new(self.listener), signal)
+            .await
+            .map_err(|err| {
+                warn!("gRPC server failed: {}", err);
+                KvStoreError::IoError
+            })
+    }
+}
+
+impl<E: KvsEngine> Service<E> {
+    async fn call<T, F>(&self, f: F) -> std::result::Result<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(Watched<E>) -> Result<T> + Send + 'static,
+    {
+        let engine = self.engine.clone();
+        tokio::task::spawn_blocking(move || f(engine))
+            .await
+            .unwrap_or_else(|err| {
+                warn!("engine call failed: {}", err);
+                Err(KvStoreError::IoError)
+            })
+            .map_err(to_status)
+    }
+}
+
+type ResponseStream<T> = Pin<Box<dyn Stream<Item = std::result::Result<T, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl<E: KvsEngine + Sync> Kvs for Service<E> {
+    async fn get(
+        &self,
+        request: Request<GetRequest>,
+    ) -> std::result::Result<Response<GetResponse>, Status> {
+        let GetRequest { key } = request.into_inner();
+        let value = self.call(move |engine| engine.get(key)).await?;
+        Ok(Response::new(GetResponse { value }))
+    }
+
+    async fn set(
+        &self,
+        request: Request<SetRequest>,
+    ) -> std::result::Result<Response<SetResponse>, Status> {
+        let SetRequest { key, value } = request.into_inner();
+        self.call(move |engine| engine.set(key, value)).await?;
+        Ok(Response::new(SetResponse {}))
+    }
+
+    async fn remove(
+        &self,
+        request: Request<RemoveRequest>,
+    ) -> std::result::Result<Response<RemoveResponse>, Status> {
+        let RemoveRequest { key } = request.into_inner();
+        self.call(move |engine| engine.remove(key)).await?;
+        Ok(Response::new(RemoveResponse {}))
+    }
+
+    type ScanStream = ResponseStream<KeyValue>;
+
+    async fn scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> std::result::Result<Response<Self::ScanStream>, Status> {
+        let ScanRequest { prefix } = request.into_inner();
+        let mut pairs = self
+            .call(move |engine| {
+                let mut pairs = Vec::new();
+                engine.scan(|key, value| {
+                    if key.starts_with(&prefix) {
+                        pairs.push(KeyValue { key, value });
+                    }
+                    Ok(())
+                })?;
+                Ok(pairs)
+            })
+            .await?;
+        pairs.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        Ok(Response::new(Box::pin(tokio_stream::iter(
+            pairs.into_iter().map(Ok),
+        ))))
+    }
+
+    async fn batch(
+        &self,
+        request: Request<BatchRequest>,
+    ) -> std::result::Result<Response<BatchResponse>, Status> {
+        let BatchRequest { operations } = request.into_inner();
+        self.call(move |engine| {
+            for operation in operations {
+                match operation.op.ok_or(KvStoreError::InvalidRequest)? {
+                    Op::Set(SetRequest { key, value }) => engine.set(key, value)?,
+                    Op::Remove(RemoveRequest { key }) => engine.remove(key)?,
+                }
+            }
+            Ok(())
+        })
+        .await?;
+        Ok(Response::new(BatchResponse {}))
+    }
+
+    type WatchStream = ResponseStream<WatchEvent>;
+
+    async fn watch(
+        &self,
+        request: Request<WatchRequest>,
+    ) -> std::result::Result<Response<Self::WatchStream>, Status> {
+        let WatchRequest { prefix } = request.into_inner();
+        let changes =
+            BroadcastStream::new(self.engine.subscribe()).filter_map(move |change| match change {
+                Ok(change) if change.key.starts_with(&prefix) => Some(Ok(WatchEvent {
+                    key: change.key,
+                    value: change.value,
+                })),
+                Ok(_) => None,
+                Err(BroadcastStreamRecvError::Lagged(n)) => Some(Err(Status::data_loss(format!(
+                    "watcher fell behind, {} changes dropped",
+                    n
+                )))),
+            });
+        Ok(Response::new(Box::pin(changes)))
+    }
+}
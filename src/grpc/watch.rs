@@ -0,0 +1,88 @@
+use crate::{EngineStats, KvsEngine, Result};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+// Changes kept for watchers that fall behind
+const WATCH_BUFFER: usize = 1024;
+
+/// A `KvsEngine` that publishes every change made through it.
+///
+/// Serving one `Watched` engine from every listener lets `Watch` streams see
+/// the changes made over any protocol.
+#[derive(Clone, Debug)]
+pub struct Watched<E: KvsEngine> {
+    engine: E,
+    changes: broadcast::Sender<Change>,
+    // held across a write and its publication, so that watchers see the
+    // changes in the order they were applied
+    writes: Arc<Mutex<()>>,
+}
+
+/// A change to a key
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Change {
+    /// the key
+    pub key: String,
+    /// its new value, or `None` if it was removed
+    pub value: Option<String>,
+}
+
+impl<E: KvsEngine> Watched<E> {
+    /// wraps `engine`
+    pub fn new(engine: E) -> Self {
+        let (changes, _) = broadcast::channel(WATCH_BUFFER);
+        Watched {
+            engine,
+            changes,
+            writes: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// receive the changes made from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Change> {
+        self.changes.subscribe()
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for Watched<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let _writes = self.writes.lock().unwrap();
+        self.engine.set(key.clone(), value.clone())?;
+        // fails only when nobody is watching
+        let _ = self.changes.send(Change {
+            key,
+            value: Some(value),
+        });
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.engine.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let _writes = self.writes.lock().unwrap();
+        self.engine.remove(key.clone())?;
+        let _ = self.changes.send(Change { key, value: None });
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn scan<F>(&self, f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        self.engine.scan(f)
+    }
+
+    fn stats(&self) -> EngineStats {
+        self.engine.stats()
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.engine.compact()
+    }
+}
@@ -0,0 +1,377 @@
+// An HTTP/1.1 gateway, for services without a kvs client.
+//
+//     GET    /v1/keys/{key}        {"key": .., "value": ..}
+//     PUT    /v1/keys/{key}        body {"value": ..}, answered with 204
+//     DELETE /v1/keys/{key}        204
+//     GET    /v1/keys?prefix=..    {"keys": [..]}, sorted
+//     GET    /health               {"status": "ok"}
+//     GET    /metrics              Prometheus text format
+//
+// Keys are percent-encoded in the path. Errors are `{"error": ..}`, naming
+// the `KvStoreError` variant where there is one, with a status code mapped
+// from it. Request bodies need a Content-Length.
+//
+// A server requiring authentication expects an `Authorization` header with a
+// `Bearer` token or `Basic` user and password on every request but
+// `/health`, and answers 401 without one. Throttled requests are answered
+// 429 with a `Retry-After` header.
+
+use crate::auth::{Auth, Authorized, Principal};
+use crate::limit::ConnectionLimits;
+use crate::metrics::{Measured, Metrics};
+use crate::protocol::read_error;
+use crate::{Credentials, KvStoreError, KvsEngine, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use serde_json::json;
+use std::io::{BufRead, Read, Write};
+use std::sync::Arc;
+
+// Longest request line and headers, and longest body
+const MAX_HEAD_LEN: usize = 64 << 10;
+const MAX_BODY_LEN: usize = 64 << 20;
+const MAX_HEADERS: usize = 64;
+
+#[derive(Debug)]
+pub struct HttpRequest {
+    method: String,
+    pub path: String,
+    query: Option<String>,
+    body: Vec<u8>,
+    // the Authorization header
+    authorization: Option<String>,
+    // whether the client wants the connection kept open
+    pub keep_alive: bool,
+}
+
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+    // an extra header, such as Allow for 405
+    header: Option<(&'static str, &'static str)>,
+}
+
+#[derive(Deserialize)]
+struct PutBody {
+    value: String,
+}
+
+// Read a request. Fails with `InvalidRequest` if it cannot be parsed or is
+// too large.
+pub fn read_request<R: BufRead>(reader: &mut R) -> Result<HttpRequest> {
+    let mut head = Vec::new();
+    while !(head.ends_with(b"\r\n\r\n") || head.ends_with(b"\n\n")) {
+        if head.len() >= MAX_HEAD_LEN {
+            return Err(KvStoreError::InvalidRequest);
+        }
+        let n = reader
+            .take((MAX_HEAD_LEN - head.len()) as u64)
+            .read_until(b'\n', &mut head)
+            .map_err(read_error)?;
+        if n == 0 {
+            return Err(KvStoreError::IoError);
+        }
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    match req.parse(&head) {
+        Ok(httparse::Status::Complete(_)) => {}
+        _ => return Err(KvStoreError::InvalidRequest),
+    }
+    let (method, target) = match (req.method, req.path) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return Err(KvStoreError::InvalidRequest),
+    };
+
+    let mut len = 0;
+    let mut connection = None;
+    let mut authorization = None;
+    for header in req.headers.iter() {
+        let value = std::str::from_utf8(header.value).map_err(|_| KvStoreError::InvalidRequest)?;
+        if header.name.eq_ignore_ascii_case("content-length") {
+            len = value
+                .trim()
+                .parse()
+                .map_err(|_| KvStoreError::InvalidRequest)?;
+        } else if header.name.eq_ignore_ascii_case("transfer-encoding") {
+            // chunked bodies are not supported
+            return Err(KvStoreError::InvalidRequest);
+        } else if header.name.eq_ignore_ascii_case("connection") {
+            connection = Some(value.trim().to_ascii_lowercase());
+        } else if header.name.eq_ignore_ascii_case("authorization") {
+            authorization = Some(value.trim().to_owned());
+        }
+    }
+    if len > MAX_BODY_LEN {
+        return Err(KvStoreError::InvalidRequest);
+    }
+    // Content-Length is untrusted, so the body grows with the bytes read
+    let mut body = Vec::new();
+    reader
+        .take(len as u64)
+        .read_to_end(&mut body)
+        .map_err(read_error)?;
+    if body.len() != len {
+        return Err(KvStoreError::IoError);
+    }
+
+    let keep_alive = match req.version {
+        Some(1) => connection.as_deref() != Some("close"),
+        _ => connection.as_deref() == Some("keep-alive"),
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_owned())),
+        None => (target, None),
+    };
+    Ok(HttpRequest {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        query,
+        body,
+        authorization,
+        keep_alive,
+    })
+}
+
+// The Authorization header last verified on a connection, and the principal
+// it authenticates
+pub type Verified = Option<(String, Arc<Principal>)>;
+
+// Answer a request. `healthy` is false once the server shuts down. With
+// `auth`, the client may only access the keys granted to it.
+pub fn handle<E: KvsEngine>(
+    engine: &E,
+    auth: Option<&Auth>,
+    verified: &mut Verified,
+    limits: &ConnectionLimits,
+    metrics: &Metrics,
+    req: &HttpRequest,
+    healthy: bool,
+) -> HttpResponse {
+    let principal = match auth {
+        Some(auth) if req.path != "/health" => match authenticate(auth, verified, req) {
+            Ok(principal) => Some(principal),
+            Err(err) => {
+                metrics.count_error(err);
+                return HttpResponse {
+                    header: Some(("WWW-Authenticate", "Bearer, Basic")),
+                    ..HttpResponse::json(401, json!({ "error": err }))
+                };
+            }
+        },
+        _ => None,
+    };
+    let limited = limits.engine(engine.clone(), principal.as_deref());
+    let guarded = &Measured::new(Authorized::new(limited, principal), metrics);
+
+    let method = req.method.as_str();
+    let result = match req.path.as_str() {
+        "/health" if method == "GET" => match healthy {
+            true => Ok(HttpResponse::json(200, json!({"status": "ok"}))),
+            false => Ok(HttpResponse::json(503, json!({"status": "shutting down"}))),
+        },
+        "/metrics" if method == "GET" => Ok(HttpResponse {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body: metrics.render(&engine.stats()).into_bytes(),
+            header: None,
+        }),
+        "/v1/keys" if method == "GET" => list(guarded, req.query.as_deref()),
+        "/health" | "/metrics" | "/v1/keys" => Ok(HttpResponse::not_allowed("GET")),
+        path => match path.strip_prefix("/v1/keys/") {
+            Some(key) if !key.is_empty() => match decode(key) {
+                Ok(key) => key_request(guarded, method, key, &req.body),
+                Err(err) => Err(err),
+            },
+            _ => Ok(HttpResponse::not_found()),
+        },
+    };
+    result.unwrap_or_else(HttpResponse::error)
+}
+
+// The principal of the Authorization header. A password takes Argon2 to
+// verify, so the header verified last on the connection is not verified
+// again.
+fn authenticate(auth: &Auth, verified: &mut Verified, req: &HttpRequest) -> Result<Arc<Principal>> {
+    let authorization = req.authorization.as_deref().unwrap_or_default();
+    if let Some((header, principal)) = verified {
+        if header == authorization {
+            return Ok(principal.clone());
+        }
+    }
+    let principal = auth.authenticate(&credentials(req)?)?;
+    *verified = Some((authorization.to_owned(), principal.clone()));
+    Ok(principal)
+}
+
+// the credentials of the Authorization header
+fn credentials(req: &HttpRequest) -> Result<Credentials> {
+    let authorization = req.authorization.as_deref().unwrap_or_default();
+    if let Some(token) = authorization.strip_prefix("Bearer ") {
+        return Ok(Credentials::Token(token.trim().to_owned()));
+    }
+    let basic = authorization
+        .strip_prefix("Basic ")
+        .and_then(|basic| STANDARD.decode(basic.trim()).ok())
+        .and_then(|basic| String::from_utf8(basic).ok());
+    match basic.as_deref().and_then(|basic| basic.split_once(':')) {
+        Some((user, password)) => Ok(Credentials::Password {
+            user: user.to_owned(),
+            password: password.to_owned(),
+        }),
+        None => Err(KvStoreError::PermissionDenied),
+    }
+}
+
+fn key_request<E: KvsEngine>(
+    engine: &E,
+    method: &str,
+    key: String,
+    body: &[u8],
+) -> Result<HttpResponse> {
+    match method {
+        "GET" => match engine.get(key.clone())? {
+            Some(value) => Ok(HttpResponse::json(200, json!({"key": key, "value": value}))),
+            None => Ok(HttpResponse::json(404, json!({"error": "KeyNotFound"}))),
+        },
+        "PUT" => {
+            let body: PutBody =
+                serde_json::from_slice(body).map_err(|_| KvStoreError::InvalidRequest)?;
+            engine.set(key, body.value)?;
+            Ok(HttpResponse::no_content())
+        }
+        "DELETE" => {
+            engine.remove(key)?;
+            Ok(HttpResponse::no_content())
+        }
+        _ => Ok(HttpResponse::not_allowed("GET, PUT, DELETE")),
+    }
+}
+
+fn list<E: KvsEngine>(engine: &E, query: Option<&str>) -> Result<HttpResponse> {
+    let mut prefix = String::new();
+    for param in query.unwrap_or_default().split('&') {
+        if let Some(value) = param.strip_prefix("prefix=") {
+            prefix = decode(&value.replace('+', " "))?;
+        }
+    }
+    let mut keys = Vec::new();
+    engine.scan(|key, _| {
+        if key.starts_with(&prefix) {
+            keys.push(key);
+        }
+        Ok(())
+    })?;
+    keys.sort_unstable();
+    Ok(HttpResponse::json(200, json!({ "keys": keys })))
+}
+
+fn decode(s: &str) -> Result<String> {
+    percent_decode_str(s)
+        .decode_utf8()
+        .map(String::from)
+        .map_err(|_| KvStoreError::InvalidRequest)
+}
+
+// The status code of an error
+fn status(err: KvStoreError) -> u16 {
+    match err {
+        KvStoreError::RemoveNonexistingKey => 404,
+        KvStoreError::InvalidRequest
+        | KvStoreError::InvalidFrame
+        | KvStoreError::UnsupportedVersion => 400,
+        KvStoreError::PermissionDenied => 403,
+        KvStoreError::Timeout => 408,
+        KvStoreError::Throttled => 429,
+        KvStoreError::QuotaExceeded => 507,
+        KvStoreError::ServerBusy => 503,
+        _ => 500,
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        429 => "Too Many Requests",
+        503 => "Service Unavailable",
+        507 => "Insufficient Storage",
+        _ => "Internal Server Error",
+    }
+}
+
+impl HttpResponse {
+    fn json(status: u16, body: serde_json::Value) -> Self {
+        HttpResponse {
+            status,
+            content_type: "application/json",
+            body: body.to_string().into_bytes(),
+            header: None,
+        }
+    }
+
+    fn no_content() -> Self {
+        HttpResponse {
+            status: 204,
+            content_type: "application/json",
+            body: Vec::new(),
+            header: None,
+        }
+    }
+
+    fn not_allowed(allow: &'static str) -> Self {
+        HttpResponse {
+            header: Some(("Allow", allow)),
+            ..Self::json(405, json!({"error": "MethodNotAllowed"}))
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Self::json(404, json!({"error": "NotFound"}))
+    }
+
+    pub fn error(err: KvStoreError) -> Self {
+        let header = match err {
+            KvStoreError::Throttled => Some(("Retry-After", "1")),
+            _ => None,
+        };
+        HttpResponse {
+            header,
+            ..Self::json(status(err), json!({ "error": err }))
+        }
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W, keep_alive: bool) -> Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason(self.status)
+        )?;
+        if !self.body.is_empty() {
+            write!(writer, "Content-Type: {}\r\n", self.content_type)?;
+        }
+        if self.status != 204 {
+            write!(writer, "Content-Length: {}\r\n", self.body.len())?;
+        }
+        if let Some((name, value)) = self.header {
+            write!(writer, "{}: {}\r\n", name, value)?;
+        }
+        if !keep_alive {
+            writer.write_all(b"Connection: close\r\n")?;
+        }
+        writer.write_all(b"\r\n")?;
+        writer.write_all(&self.body)?;
+        Ok(())
+    }
+}
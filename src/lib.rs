@@ -2,14 +2,66 @@
 
 //! A simple key/value store
 
-pub use crate::client::KvClient;
-pub use crate::engines::{KvStore, KvsEngine, SledKvsStore};
+#[cfg(feature = "async")]
+pub use crate::async_client::AsyncKvClient;
+#[cfg(feature = "async")]
+pub use crate::async_server::AsyncKvServer;
+#[cfg(feature = "blocking")]
+pub use crate::auth::{Access, Auth};
+#[cfg(feature = "blocking")]
+pub use crate::client::{KvClient, Pending};
+pub use crate::engines::{
+    CacheStats, EngineKind, EngineStats, EvictionPolicy, KvStore, KvStoreOptions, KvsEngine,
+    LsmKvsStore, LsmOptions, MemoryKvsStore, Segment, SledKvsStore,
+};
 pub use crate::error::{KvStoreError, Result};
-pub use crate::server::KvServer;
+#[cfg(feature = "blocking")]
+pub use crate::limit::{Quota, RateLimit};
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub use crate::message::{Credentials, ServerStats};
+#[cfg(feature = "blocking")]
+pub use crate::metrics::Metrics;
+#[cfg(any(feature = "blocking", feature = "async"))]
+pub use crate::protocol::Protocol;
+#[cfg(feature = "blocking")]
+pub use crate::server::{
+    KvServer, Listener, OverflowPolicy, ServerHandle, ServerOptions, ServerProtocol,
+};
+#[cfg(feature = "blocking")]
+pub use crate::socket::ListenAddr;
+#[cfg(feature = "blocking")]
+pub use crate::tls::{ClientTls, ServerTls};
 
+#[cfg(feature = "async")]
+mod async_client;
+#[cfg(feature = "async")]
+mod async_server;
+#[cfg(feature = "blocking")]
+mod auth;
+#[cfg(feature = "blocking")]
 mod client;
+pub mod dump;
 mod engines;
 mod error;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "blocking")]
+mod http;
+#[cfg(feature = "blocking")]
+mod limit;
+#[cfg(any(feature = "blocking", feature = "async"))]
 mod message;
+#[cfg(feature = "blocking")]
+mod metrics;
+pub mod migrate;
+#[cfg(any(feature = "blocking", feature = "async"))]
+mod protocol;
+#[cfg(feature = "blocking")]
+mod resp;
+#[cfg(feature = "blocking")]
 mod server;
+#[cfg(feature = "blocking")]
+mod socket;
 pub mod thread_pool;
+#[cfg(feature = "blocking")]
+mod tls;
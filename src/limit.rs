@@ -0,0 +1,379 @@
+// Rate limits on the requests of clients, and storage quotas on key
+// prefixes.
+//
+// Rates are enforced with token buckets, refilled continuously and holding at
+// most one second of requests. A request is throttled if its connection or
+// its principal has no operation left, or is in debt of bytes. The bytes of a
+// response are only known once it is answered, so they are charged
+// afterwards, and a bucket in debt throttles the next requests until it
+// refills.
+//
+// Quotas count the bytes of the keys and values under their prefix, from a
+// scan at startup and then from the writes going through `Limited`. Writes
+// made around the server, e.g. over gRPC, are not counted.
+
+use crate::auth::Principal;
+use crate::{EngineStats, KvStoreError, KvsEngine, Result};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+use tracing::debug;
+
+/// The rate at which a client may send requests. `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Requests per second
+    pub ops_per_sec: Option<u32>,
+    /// Bytes of keys and values sent and received per second
+    pub bytes_per_sec: Option<u64>,
+}
+
+/// A limit on the bytes of the keys and values under a prefix
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Quota {
+    /// Keys the quota applies to
+    pub prefix: String,
+    /// Total length of the keys and values under `prefix`
+    pub max_bytes: u64,
+}
+
+impl FromStr for Quota {
+    type Err = String;
+
+    // PREFIX=BYTES
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.rsplit_once('=') {
+            Some((prefix, max_bytes)) => Ok(Quota {
+                prefix: prefix.to_owned(),
+                max_bytes: max_bytes
+                    .parse()
+                    .map_err(|_| format!("invalid quota size: {}", max_bytes))?,
+            }),
+            None => Err(format!("quota is not PREFIX=BYTES: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    limit: RateLimit,
+    ops: f64,
+    bytes: f64,
+    last: Instant,
+}
+
+type Bucket = Arc<Mutex<TokenBucket>>;
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Bucket {
+        Arc::new(Mutex::new(TokenBucket {
+            limit,
+            ops: limit.ops_per_sec.unwrap_or_default() as f64,
+            bytes: limit.bytes_per_sec.unwrap_or_default() as f64,
+            last: Instant::now(),
+        }))
+    }
+
+    // Whether a request may be sent now
+    fn ready(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        if let Some(rate) = self.limit.ops_per_sec {
+            self.ops = (self.ops + rate as f64 * elapsed).min(rate as f64);
+        }
+        if let Some(rate) = self.limit.bytes_per_sec {
+            self.bytes = (self.bytes + rate as f64 * elapsed).min(rate as f64);
+        }
+        (self.limit.ops_per_sec.is_none() || self.ops >= 1.0)
+            && (self.limit.bytes_per_sec.is_none() || self.bytes > 0.0)
+    }
+
+    fn take(&mut self, bytes: usize) {
+        if self.limit.ops_per_sec.is_some() {
+            self.ops -= 1.0;
+        }
+        self.charge(bytes);
+    }
+
+    fn charge(&mut self, bytes: usize) {
+        if self.limit.bytes_per_sec.is_some() {
+            self.bytes -= bytes as f64;
+        }
+    }
+}
+
+// The rate limits and quotas of a server, shared by its connections
+#[derive(Debug, Default)]
+pub(crate) struct Limits {
+    // the rates of each connection and of each principal
+    rates: Mutex<(Option<RateLimit>, Option<RateLimit>)>,
+    // the bucket of each principal that sent requests
+    principals: Mutex<HashMap<String, Bucket>>,
+    quotas: Option<Arc<Quotas>>,
+}
+
+// Each quota, and the bytes used under its prefix. A write reads the old
+// length of its key and updates the usage under the lock of the key, so that
+// writes of other keys go on concurrently.
+#[derive(Debug)]
+struct Quotas {
+    usage: Vec<(Quota, AtomicU64)>,
+    keys: Vec<Mutex<()>>,
+}
+
+// the number of locks the keys under quota are spread over
+const KEY_LOCKS: usize = 64;
+
+impl Limits {
+    pub fn new<E: KvsEngine>(
+        engine: &E,
+        connection: Option<RateLimit>,
+        principal: Option<RateLimit>,
+        quotas: &[Quota],
+    ) -> Result<Self> {
+        let quotas = match quotas.is_empty() {
+            true => None,
+            false => {
+                let mut usage: Vec<(Quota, u64)> = quotas.iter().map(|q| (q.clone(), 0)).collect();
+                engine.scan(|key, value| {
+                    for (quota, used) in usage.iter_mut() {
+                        if key.starts_with(quota.prefix.as_str()) {
+                            *used += (key.len() + value.len()) as u64;
+                        }
+                    }
+                    Ok(())
+                })?;
+                Some(Arc::new(Quotas::new(usage)))
+            }
+        };
+        Ok(Limits {
+            rates: Mutex::new((connection, principal)),
+            principals: Mutex::new(HashMap::new()),
+            quotas,
+        })
+    }
+
+    // the limits of a new connection
+    pub fn connection(self: &Arc<Self>) -> ConnectionLimits {
+        let connection = self.rates.lock().unwrap().0;
+        ConnectionLimits {
+            limits: self.clone(),
+            bucket: connection.map(TokenBucket::new),
+        }
+    }
+
+    // Change the rates. The connections open keep their bucket, and the
+    // principals get a new one.
+    pub fn set_rates(&self, connection: Option<RateLimit>, principal: Option<RateLimit>) {
+        *self.rates.lock().unwrap() = (connection, principal);
+        self.principals.lock().unwrap().clear();
+    }
+}
+
+// The limits of a connection, whose requests share a bucket
+#[derive(Clone, Debug)]
+pub(crate) struct ConnectionLimits {
+    limits: Arc<Limits>,
+    bucket: Option<Bucket>,
+}
+
+impl ConnectionLimits {
+    // `engine`, limited for the requests of `principal` on this connection
+    pub fn engine<E: KvsEngine>(&self, engine: E, principal: Option<&Principal>) -> Limited<E> {
+        let mut buckets: Vec<Bucket> = self.bucket.iter().cloned().collect();
+        let limit = self.limits.rates.lock().unwrap().1;
+        if let (Some(limit), Some(principal)) = (limit, principal) {
+            let mut principals = self.limits.principals.lock().unwrap();
+            let bucket = principals
+                .entry(principal.name().to_owned())
+                .or_insert_with(|| TokenBucket::new(limit));
+            buckets.push(bucket.clone());
+        }
+        Limited {
+            engine,
+            buckets,
+            quotas: self.limits.quotas.clone(),
+        }
+    }
+}
+
+/// A `KvsEngine` failing requests beyond the rate limits of a client with
+/// `KvStoreError::Throttled`, and sets beyond a quota with
+/// `KvStoreError::QuotaExceeded`
+#[derive(Clone, Debug)]
+pub(crate) struct Limited<E: KvsEngine> {
+    engine: E,
+    // the buckets of the connection and of the principal
+    buckets: Vec<Bucket>,
+    quotas: Option<Arc<Quotas>>,
+}
+
+impl<E: KvsEngine> Limited<E> {
+    // Take a request sending `bytes` from every bucket, if they all allow it
+    fn take(&self, bytes: usize) -> Result<()> {
+        let mut buckets: Vec<_> = self.buckets.iter().map(|b| b.lock().unwrap()).collect();
+        if !buckets.iter_mut().all(|bucket| bucket.ready()) {
+            debug!("request throttled");
+            return Err(KvStoreError::Throttled);
+        }
+        for bucket in buckets.iter_mut() {
+            bucket.take(bytes);
+        }
+        Ok(())
+    }
+
+    fn charge(&self, bytes: usize) {
+        for bucket in self.buckets.iter() {
+            bucket.lock().unwrap().charge(bytes);
+        }
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for Limited<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.take(key.len() + value.len())?;
+        match &self.quotas {
+            Some(quotas) => quotas.set(&self.engine, key, value),
+            None => self.engine.set(key, value),
+        }
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.take(key.len())?;
+        let value = self.engine.get(key)?;
+        self.charge(value.as_ref().map_or(0, String::len));
+        Ok(value)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.take(key.len())?;
+        match &self.quotas {
+            Some(quotas) => quotas.remove(&self.engine, key),
+            None => self.engine.remove(key),
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn scan<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        self.take(0)?;
+        self.engine.scan(|key, value| {
+            self.charge(key.len() + value.len());
+            f(key, value)
+        })
+    }
+
+    fn stats(&self) -> EngineStats {
+        self.engine.stats()
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.engine.compact()
+    }
+}
+
+impl Quotas {
+    fn new(quotas: Vec<(Quota, u64)>) -> Self {
+        Quotas {
+            usage: quotas
+                .into_iter()
+                .map(|(quota, used)| (quota, AtomicU64::new(used)))
+                .collect(),
+            keys: (0..KEY_LOCKS).map(|_| Mutex::new(())).collect(),
+        }
+    }
+
+    // the lock of `key`, held while its old length is read and it is written
+    fn lock(&self, key: &str) -> MutexGuard<'_, ()> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        self.keys[hasher.finish() as usize % KEY_LOCKS]
+            .lock()
+            .unwrap()
+    }
+
+    // the usage of the quotas `key` falls under
+    fn matching<'a>(&'a self, key: &'a str) -> impl Iterator<Item = (&'a Quota, &'a AtomicU64)> {
+        self.usage
+            .iter()
+            .filter(move |(q, _)| key.starts_with(q.prefix.as_str()))
+            .map(|(q, used)| (q, used))
+    }
+
+    // Add `bytes` to the usage of the quotas of `key`, unless one would go
+    // beyond its maximum
+    fn reserve(&self, key: &str, bytes: u64) -> Result<()> {
+        let mut reserved = Vec::new();
+        for (quota, used) in self.matching(key) {
+            let fits = used.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                Some(used + bytes).filter(|&used| used <= quota.max_bytes)
+            });
+            if fits.is_err() {
+                debug!("quota of {:?} exceeded", quota.prefix);
+                self.release(reserved, bytes);
+                return Err(KvStoreError::QuotaExceeded);
+            }
+            reserved.push(used);
+        }
+        Ok(())
+    }
+
+    fn release<'a>(&self, usage: impl IntoIterator<Item = &'a AtomicU64>, bytes: u64) {
+        for used in usage {
+            let _ = used.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                Some(used.saturating_sub(bytes))
+            });
+        }
+    }
+
+    // the bytes `key` takes in `engine`
+    fn size<E: KvsEngine>(engine: &E, key: &str) -> Result<u64> {
+        let old = engine.get(key.to_owned())?;
+        Ok(old.map_or(0, |old| key.len() + old.len()) as u64)
+    }
+
+    // Set `key` unless a quota it falls under would be exceeded. Writes that
+    // do not grow the usage are allowed over quota.
+    fn set<E: KvsEngine>(&self, engine: &E, key: String, value: String) -> Result<()> {
+        if self.matching(&key).next().is_none() {
+            return engine.set(key, value);
+        }
+
+        let _key = self.lock(&key);
+        let old = Self::size(engine, &key)?;
+        let new = (key.len() + value.len()) as u64;
+        if new > old {
+            self.reserve(&key, new - old)?;
+            if let Err(err) = engine.set(key.clone(), value) {
+                self.release(self.matching(&key).map(|(_, used)| used), new - old);
+                return Err(err);
+            }
+        } else {
+            engine.set(key.clone(), value)?;
+            self.release(self.matching(&key).map(|(_, used)| used), old - new);
+        }
+        Ok(())
+    }
+
+    fn remove<E: KvsEngine>(&self, engine: &E, key: String) -> Result<()> {
+        if self.matching(&key).next().is_none() {
+            return engine.remove(key);
+        }
+
+        let _key = self.lock(&key);
+        let old = Self::size(engine, &key)?;
+        engine.remove(key.clone())?;
+        self.release(self.matching(&key).map(|(_, used)| used), old);
+        Ok(())
+    }
+}
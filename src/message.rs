@@ -1,4 +1,5 @@
 use crate::error::KvStoreError;
+use crate::{EngineStats, KvsEngine};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -6,6 +7,22 @@ pub enum Request {
     Get { key: String },
     Set { key: String, value: String },
     Remove { key: String },
+    Admin(AdminRequest),
+}
+
+// A request about the server rather than a key, answered by `KvServer`
+// itself. If the server requires authentication, all but `Ping` need an
+// administrator.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum AdminRequest {
+    Ping,
+    Stats,
+    // compact the store now
+    Compact,
+    Flush,
+    // the settings that `SetConfig` changes, and their values
+    GetConfig,
+    SetConfig { name: String, value: String },
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -25,3 +42,220 @@ pub enum RemoveResponse {
     Ok,
     Err(KvStoreError),
 }
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum AdminResponse {
+    Ok(AdminReply),
+    Err(KvStoreError),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum AdminReply {
+    Pong,
+    Done,
+    Stats(ServerStats),
+    Config(Vec<(String, String)>),
+}
+
+/// Counters of a running server and its store, as answered to
+/// `KvClient::stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerStats {
+    /// open connections, over the listeners sharing the server metrics
+    pub connections: u64,
+    /// the store
+    pub engine: EngineStats,
+}
+
+/// Credentials presented by a client
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Credentials {
+    /// A static token
+    Token(String),
+    /// A user and its password
+    Password {
+        /// the user name
+        user: String,
+        /// the password
+        password: String,
+    },
+}
+
+// Sent instead of a response when a request cannot be parsed. It has the
+// same representation as the `Err` variant of every response type.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum ErrorResponse {
+    Err(KvStoreError),
+}
+
+// The first request of a JSON connection to a server requiring
+// authentication, answered like a set
+#[cfg(feature = "blocking")]
+#[derive(Debug, Deserialize, Serialize)]
+pub enum AuthRequest {
+    Auth(Credentials),
+}
+
+// The response to any request. It is serialized as the response type of the
+// request, which is what the client expects to read.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Response {
+    Get(GetResponse),
+    Set(SetResponse),
+    Remove(RemoveResponse),
+    Admin(AdminResponse),
+}
+
+impl Request {
+    // the key the request reads or writes, if any
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Request::Get { key } | Request::Set { key, .. } | Request::Remove { key } => Some(key),
+            Request::Admin(_) => None,
+        }
+    }
+
+    // the response that reports `err` for this request
+    #[cfg(feature = "async")]
+    pub fn error(&self, err: KvStoreError) -> Response {
+        match self {
+            Request::Get { .. } => Response::Get(GetResponse::Err(err)),
+            Request::Set { .. } => Response::Set(SetResponse::Err(err)),
+            Request::Remove { .. } => Response::Remove(RemoveResponse::Err(err)),
+            Request::Admin(_) => Response::Admin(AdminResponse::Err(err)),
+        }
+    }
+
+    // run the request against `engine`
+    pub fn apply<E: KvsEngine>(self, engine: &E) -> Response {
+        match self {
+            Request::Get { key } => Response::Get(match engine.get(key) {
+                Ok(res) => GetResponse::Ok(res),
+                Err(err) => GetResponse::Err(err),
+            }),
+            Request::Set { key, value } => Response::Set(match engine.set(key, value) {
+                Ok(()) => SetResponse::Ok,
+                Err(err) => SetResponse::Err(err),
+            }),
+            Request::Remove { key } => Response::Remove(match engine.remove(key) {
+                Ok(()) => RemoveResponse::Ok,
+                Err(err) => RemoveResponse::Err(err),
+            }),
+            // answered by `KvServer`, which knows its connections and
+            // settings
+            Request::Admin(_) => Response::Admin(AdminResponse::Err(KvStoreError::InvalidRequest)),
+        }
+    }
+}
+
+// Reads a stream of concatenated JSON values from an async reader.
+// serde_json can only stream from a blocking reader, so bytes are buffered
+// until they hold a complete value. The buffer is scanned as it fills for the
+// end of the value, so that it is only parsed once.
+#[cfg(feature = "async")]
+pub struct JsonReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+    // how far `buf` was scanned, and the state of the scan there
+    scanned: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+// Longest value, beyond which the stream is invalid
+#[cfg(feature = "async")]
+const MAX_VALUE_LEN: usize = 64 << 20;
+
+#[cfg(feature = "async")]
+impl<R: tokio::io::AsyncRead + Unpin> JsonReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buf: Vec::new(),
+            scanned: 0,
+            depth: 0,
+            in_string: false,
+            escaped: false,
+        }
+    }
+
+    // Whether no value has started since the last one
+    pub fn is_idle(&self) -> bool {
+        self.buf.iter().all(u8::is_ascii_whitespace)
+    }
+
+    // The next value, or `None` at the end of the stream
+    pub async fn next<T: serde::de::DeserializeOwned>(&mut self) -> crate::Result<Option<T>> {
+        use tokio::io::AsyncReadExt;
+
+        loop {
+            if self.scan() {
+                let mut stream = serde_json::Deserializer::from_slice(&self.buf).into_iter::<T>();
+                match stream.next() {
+                    Some(Ok(value)) => {
+                        let consumed = stream.byte_offset();
+                        self.buf.drain(..consumed);
+                        self.scanned = self.scanned.saturating_sub(consumed);
+                        return Ok(Some(value));
+                    }
+                    Some(Err(err)) if !err.is_eof() => return Err(err.into()),
+                    // a bare literal, which may go on
+                    _ => {}
+                }
+            }
+            if self.buf.len() >= MAX_VALUE_LEN {
+                return Err(KvStoreError::SerdeError);
+            }
+
+            let mut chunk = [0u8; 4096];
+            let n = self.reader.read(&mut chunk).await?;
+            if n == 0 {
+                return match self.is_idle() {
+                    true => Ok(None),
+                    false => Err(KvStoreError::SerdeError),
+                };
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    // Scan the bytes read since the last scan. True once the first value
+    // may be complete: its closing bracket or quote was read, or it is a
+    // bare literal, whose end only parsing tells.
+    fn scan(&mut self) -> bool {
+        while self.scanned < self.buf.len() {
+            let byte = self.buf[self.scanned];
+            self.scanned += 1;
+            if self.in_string {
+                match byte {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => {
+                        self.in_string = false;
+                        if self.depth == 0 {
+                            return true;
+                        }
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' => {
+                    self.depth = self.depth.saturating_sub(1);
+                    if self.depth == 0 {
+                        return true;
+                    }
+                }
+                _ if byte.is_ascii_whitespace() => {}
+                _ if self.depth == 0 => return true,
+                _ => {}
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,316 @@
+// Metrics of `KvServer`, rendered in the Prometheus text format.
+//
+// The engine calls are timed by `Measured`, which wraps the engine of every
+// connection outside of authorization and rate limits, so that denied and
+// throttled requests are counted too. Errors that happen before a request
+// reaches the engine, e.g. unparsable requests, are counted by the server.
+//
+// `Measured` also traces each call in a `request` span at the debug level,
+// with its operation, key, latency and result. Values are never recorded.
+
+use crate::{EngineStats, KvStoreError, KvsEngine, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, debug_span, field, Span};
+
+// Upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+/// The metrics recorded by `KvServer`. Clones share the same counters, so
+/// servers started with clones of the same `ServerOptions` report together.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics(Arc<Registry>);
+
+#[derive(Debug, Default)]
+struct Registry {
+    connections: AtomicU64,
+    // connections accepted but not picked up by a thread of the pool yet
+    queued: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    // latency of the requests, by operation
+    requests: Mutex<BTreeMap<&'static str, Histogram>>,
+    errors: Mutex<BTreeMap<String, u64>>,
+    http_responses: Mutex<BTreeMap<u16, u64>>,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    // not cumulative, unlike the rendered buckets
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Metrics {
+    /// creates an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn connection_opened(&self) {
+        self.0.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_closed(&self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // open connections, over every server sharing these metrics
+    pub(crate) fn connections(&self) -> u64 {
+        self.0.connections.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn job_queued(&self) {
+        self.0.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn job_started(&self) {
+        self.0.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_error(&self, err: KvStoreError) {
+        *self
+            .0
+            .errors
+            .lock()
+            .unwrap()
+            .entry(err.to_string())
+            .or_default() += 1;
+    }
+
+    pub(crate) fn count_http(&self, status: u16) {
+        *self
+            .0
+            .http_responses
+            .lock()
+            .unwrap()
+            .entry(status)
+            .or_default() += 1;
+    }
+
+    fn observe<T>(&self, op: &'static str, latency: Duration, result: &Result<T>) {
+        let secs = latency.as_secs_f64();
+        {
+            let mut requests = self.0.requests.lock().unwrap();
+            let histogram = requests.entry(op).or_default();
+            if let Some(i) = LATENCY_BUCKETS.iter().position(|le| secs <= *le) {
+                histogram.buckets[i] += 1;
+            }
+            histogram.count += 1;
+            histogram.sum += secs;
+        }
+        if let Err(err) = result {
+            self.count_error(*err);
+        }
+    }
+
+    // the metrics, with the counters and size of `engine`
+    pub(crate) fn render(&self, engine: &EngineStats) -> String {
+        let registry = &self.0;
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, samples: &[(String, String)]| {
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+        };
+        let value = |v: u64| vec![(String::new(), v.to_string())];
+
+        metric(
+            "kvs_connections",
+            "gauge",
+            &value(registry.connections.load(Ordering::Relaxed)),
+        );
+        metric(
+            "kvs_thread_pool_queued",
+            "gauge",
+            &value(registry.queued.load(Ordering::Relaxed)),
+        );
+
+        let mut samples = Vec::new();
+        for (op, histogram) in registry.requests.lock().unwrap().iter() {
+            let mut cumulative = 0;
+            for (le, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                samples.push((
+                    format!("_bucket{{op=\"{}\",le=\"{}\"}}", op, le),
+                    cumulative.to_string(),
+                ));
+            }
+            samples.push((
+                format!("_bucket{{op=\"{}\",le=\"+Inf\"}}", op),
+                histogram.count.to_string(),
+            ));
+            samples.push((format!("_sum{{op=\"{}\"}}", op), histogram.sum.to_string()));
+            samples.push((
+                format!("_count{{op=\"{}\"}}", op),
+                histogram.count.to_string(),
+            ));
+        }
+        metric("kvs_request_duration_seconds", "histogram", &samples);
+
+        metric(
+            "kvs_bytes_received_total",
+            "counter",
+            &value(registry.bytes_received.load(Ordering::Relaxed)),
+        );
+        metric(
+            "kvs_bytes_sent_total",
+            "counter",
+            &value(registry.bytes_sent.load(Ordering::Relaxed)),
+        );
+
+        let samples: Vec<_> = registry
+            .errors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(err, count)| (format!("{{error=\"{}\"}}", err), count.to_string()))
+            .collect();
+        metric("kvs_errors_total", "counter", &samples);
+
+        let samples: Vec<_> = registry
+            .http_responses
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(status, count)| (format!("{{status=\"{}\"}}", status), count.to_string()))
+            .collect();
+        metric("kvs_http_responses_total", "counter", &samples);
+
+        metric(
+            "kvs_compactions_total",
+            "counter",
+            &value(engine.compactions),
+        );
+        metric(
+            "kvs_compaction_reclaimed_bytes_total",
+            "counter",
+            &value(engine.bytes_reclaimed),
+        );
+        metric("kvs_store_size_bytes", "gauge", &value(engine.size));
+        out
+    }
+}
+
+// A `KvsEngine` recording the latency, bytes and errors of the requests made
+// through it
+#[derive(Clone, Debug)]
+pub(crate) struct Measured<E: KvsEngine> {
+    engine: E,
+    metrics: Metrics,
+}
+
+impl<E: KvsEngine> Measured<E> {
+    pub fn new(engine: E, metrics: &Metrics) -> Self {
+        Measured {
+            engine,
+            metrics: metrics.clone(),
+        }
+    }
+
+    pub fn inner(&self) -> &E {
+        &self.engine
+    }
+
+    fn received(&self, bytes: usize) {
+        let bytes = bytes as u64;
+        self.metrics
+            .0
+            .bytes_received
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn sent(&self, bytes: usize) {
+        let bytes = bytes as u64;
+        self.metrics
+            .0
+            .bytes_sent
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    // Run the call `op` in `span`, recording its latency and result
+    fn measure<T, F>(&self, op: &'static str, span: Span, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>,
+    {
+        let _enter = span.enter();
+        let start = Instant::now();
+        let result = f();
+        let latency = start.elapsed();
+        span.record("latency_us", latency.as_micros() as u64);
+        match &result {
+            Ok(_) => span.record("result", "ok"),
+            Err(err) => span.record("result", field::display(err)),
+        };
+        debug!("done");
+        self.metrics.observe(op, latency, &result);
+        result
+    }
+}
+
+// The span of a request. Created before the key moves into the engine.
+fn request_span(op: &'static str, key: Option<&str>) -> Span {
+    debug_span!(
+        "request",
+        op,
+        key,
+        latency_us = field::Empty,
+        result = field::Empty
+    )
+}
+
+impl<E: KvsEngine> KvsEngine for Measured<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.received(key.len() + value.len());
+        let span = request_span("set", Some(&key));
+        self.measure("set", span, || self.engine.set(key, value))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.received(key.len());
+        let span = request_span("get", Some(&key));
+        let result = self.measure("get", span, || self.engine.get(key));
+        if let Ok(Some(value)) = &result {
+            self.sent(value.len());
+        }
+        result
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.received(key.len());
+        let span = request_span("remove", Some(&key));
+        self.measure("remove", span, || self.engine.remove(key))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn scan<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        self.measure("scan", request_span("scan", None), || {
+            self.engine.scan(|key, value| {
+                self.sent(key.len() + value.len());
+                f(key, value)
+            })
+        })
+    }
+
+    fn stats(&self) -> EngineStats {
+        self.engine.stats()
+    }
+
+    fn compact(&self) -> Result<()> {
+        let span = request_span("compact", None);
+        self.measure("compact", span, || self.engine.compact())
+    }
+}
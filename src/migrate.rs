@@ -0,0 +1,131 @@
+//! In-place migration of a data directory from one engine to another.
+//!
+//! Both engines keep their files side by side in the data directory, so a
+//! migration opens the old engine, copies every pair into the new one, and
+//! verifies that both hold the same pairs before the `engine` marker is
+//! rewritten.
+//!
+//! The `engine` marker is only rewritten, atomically, once the copy has been
+//! verified. If a migration is interrupted before that, the directory still
+//! belongs to the old engine, and running the migration again resumes it:
+//! pairs already copied are skipped. A `migration` marker records the
+//! migration in progress, so an interruption right after the `engine` marker
+//! was rewritten is cleaned up as well.
+use crate::{EngineKind, KvStore, KvStoreError, KvsEngine, LsmKvsStore, Result, SledKvsStore};
+use std::{fs, path::Path};
+
+/// Number of pairs in a store, and an order-independent checksum over them.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Summary {
+    /// number of pairs
+    pub count: usize,
+    /// wrapping sum of the FNV-1a hash of every pair
+    pub checksum: u64,
+}
+
+impl Summary {
+    fn add(&mut self, key: &str, value: &str) {
+        self.count += 1;
+        self.checksum = self.checksum.wrapping_add(hash_pair(key, value));
+    }
+}
+
+/// Summarize the pairs in `engine`.
+pub fn summarize<E: KvsEngine>(engine: &E) -> Result<Summary> {
+    let mut summary = Summary::default();
+    engine.scan(|key, value| {
+        summary.add(&key, &value);
+        Ok(())
+    })?;
+    Ok(summary)
+}
+
+/// Make `dst` hold exactly the pairs of `src`, then check their summaries.
+///
+/// Pairs that `dst` already holds are not written again, and pairs that only
+/// `dst` holds are removed, so this can be called again after a failure.
+pub fn copy_all<S: KvsEngine, D: KvsEngine>(src: &S, dst: &D) -> Result<Summary> {
+    let mut summary = Summary::default();
+    src.scan(|key, value| {
+        summary.add(&key, &value);
+        if dst.get(key.clone())?.as_ref() != Some(&value) {
+            dst.set(key, value)?;
+        }
+        Ok(())
+    })?;
+
+    let mut stale = Vec::new();
+    dst.scan(|key, _| {
+        if src.get(key.clone())?.is_none() {
+            stale.push(key);
+        }
+        Ok(())
+    })?;
+    for key in stale {
+        dst.remove(key)?;
+    }
+
+    if summarize(dst)? != summary {
+        return Err(KvStoreError::MigrationMismatch);
+    }
+    Ok(summary)
+}
+
+/// Migrate the data directory `dir_path` to the engine `to`.
+///
+/// Returns `None` if the directory already belongs to `to`.
+pub fn migrate(dir_path: &Path, to: EngineKind) -> Result<Option<Summary>> {
+    let marker = dir_path.join("migration");
+    let from = match (EngineKind::current(dir_path)?, marker.try_exists()?) {
+        // interrupted after the engine marker was rewritten
+        (Some(current), true) if current == to => {
+            fs::remove_file(marker)?;
+            return Ok(None);
+        }
+        (Some(current), _) if current != to => current,
+        _ => return Ok(None),
+    };
+
+    if !to.is_persistent() {
+        return Err(KvStoreError::WrongEngine);
+    }
+
+    fs::write(&marker, format!("{}->{}", from, to))?;
+    let summary = match from {
+        EngineKind::Kvs => copy_into(&KvStore::open(dir_path)?, dir_path, to),
+        EngineKind::Sled => copy_into(&SledKvsStore::open(dir_path)?, dir_path, to),
+        EngineKind::Lsm => copy_into(&LsmKvsStore::open(dir_path)?, dir_path, to),
+        EngineKind::Memory => Err(KvStoreError::WrongEngine),
+    }?;
+    to.write_marker(dir_path)?;
+    fs::remove_file(marker)?;
+
+    Ok(Some(summary))
+}
+
+fn copy_into<S: KvsEngine>(src: &S, dir_path: &Path, to: EngineKind) -> Result<Summary> {
+    match to {
+        EngineKind::Kvs => copy_durably(src, &KvStore::open(dir_path)?),
+        EngineKind::Sled => copy_durably(src, &SledKvsStore::open(dir_path)?),
+        EngineKind::Lsm => copy_durably(src, &LsmKvsStore::open(dir_path)?),
+        EngineKind::Memory => Err(KvStoreError::WrongEngine),
+    }
+}
+
+// Copy, then flush `dst`, so that the engine marker never points at data
+// that did not reach the disk
+fn copy_durably<S: KvsEngine, D: KvsEngine>(src: &S, dst: &D) -> Result<Summary> {
+    let summary = copy_all(src, dst)?;
+    dst.flush()?;
+    Ok(summary)
+}
+
+// FNV-1a over the key, a separator, and the value
+fn hash_pair(key: &str, value: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in key.bytes().chain(Some(0xff)).chain(value.bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
@@ -0,0 +1,407 @@
+// The binary protocol.
+//
+// A client opens a connection with `MAGIC`, followed by a `Hello` frame. The
+// server answers with a `Welcome` frame holding the negotiated version and
+// features, or with an `Error` frame after which it closes the connection.
+// A server requiring authentication then expects an `Auth` frame, answered
+// by `Done`, or by an `Error` after which it closes the connection. Then
+// every request frame is answered by a response frame with the same
+// request id.
+//
+// A frame is
+//
+//     length: u32 | opcode: u8 | request id: u64 | body
+//
+// in big endian, where `length` counts the bytes after itself. The body is
+// encoded with bincode.
+//
+// JSON requests never start with the first byte of `MAGIC`, so the server
+// tells the two protocols apart on the same port. A server rejecting a
+// connection before reading from it answers in JSON, which the client
+// recognizes in place of `Welcome`.
+
+use crate::message::{
+    AdminReply, AdminResponse, Credentials, GetResponse, RemoveResponse, Request, Response,
+    SetResponse,
+};
+use crate::{KvStoreError, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io;
+use std::str::FromStr;
+#[cfg(feature = "blocking")]
+use {
+    crate::message::{AuthRequest, ErrorResponse},
+    serde_json::Deserializer,
+    std::io::{BufRead, Read, Write},
+};
+
+/// Wire protocol spoken by a client
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    /// Length-prefixed binary frames, after a handshake negotiating the
+    /// protocol version
+    #[default]
+    Binary,
+    /// Concatenated JSON objects, for debugging
+    Json,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "binary" => Ok(Protocol::Binary),
+            "json" => Ok(Protocol::Json),
+            _ => Err(format!("unknown protocol: {}", s)),
+        }
+    }
+}
+
+pub const MAGIC: [u8; 4] = *b"KVSB";
+
+// Versions of the binary protocol this side speaks
+const MIN_VERSION: u16 = 1;
+const MAX_VERSION: u16 = 1;
+
+// Requests may be answered out of order, matched by their request ids.
+// Requests on the same key still apply in the order they were sent.
+pub const PIPELINING: &str = "pipelining";
+
+// Optional features this side supports. `Welcome` lists the ones both sides
+// support.
+const FEATURES: &[&str] = &[PIPELINING];
+
+// opcode and request id
+const HEADER_LEN: usize = 9;
+const MAX_FRAME_LEN: usize = 64 << 20;
+
+const HELLO: u8 = 0x01;
+const WELCOME: u8 = 0x02;
+const AUTH: u8 = 0x03;
+const GET: u8 = 0x10;
+const SET: u8 = 0x11;
+const REMOVE: u8 = 0x12;
+const ADMIN: u8 = 0x13;
+const VALUE: u8 = 0x20;
+const DONE: u8 = 0x21;
+const ADMIN_REPLY: u8 = 0x22;
+const ERROR: u8 = 0x7f;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Hello {
+    pub min_version: u16,
+    pub max_version: u16,
+    pub features: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Welcome {
+    pub version: u16,
+    pub features: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum Frame {
+    Hello(Hello),
+    Welcome(Welcome),
+    // sent after `Welcome` to a server requiring authentication, and
+    // answered with `Done`
+    Auth(Credentials),
+    Request(Request),
+    // response to a get
+    Value(Option<String>),
+    // response to a set or a remove
+    Done,
+    // response to an admin request
+    Admin(AdminReply),
+    Error(KvStoreError),
+}
+
+impl Hello {
+    // the versions and features of this side
+    pub fn new() -> Self {
+        Hello {
+            min_version: MIN_VERSION,
+            max_version: MAX_VERSION,
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    // The highest version and the features both sides support
+    pub fn negotiate(&self) -> Result<Welcome> {
+        let version = self.max_version.min(MAX_VERSION);
+        if version < self.min_version.max(MIN_VERSION) {
+            return Err(KvStoreError::UnsupportedVersion);
+        }
+        let features = self
+            .features
+            .iter()
+            .filter(|f| FEATURES.contains(&f.as_str()))
+            .cloned()
+            .collect();
+        Ok(Welcome { version, features })
+    }
+}
+
+impl From<Response> for Frame {
+    fn from(resp: Response) -> Self {
+        match resp {
+            Response::Get(GetResponse::Ok(value)) => Frame::Value(value),
+            Response::Set(SetResponse::Ok) | Response::Remove(RemoveResponse::Ok) => Frame::Done,
+            Response::Admin(AdminResponse::Ok(reply)) => Frame::Admin(reply),
+            Response::Get(GetResponse::Err(err))
+            | Response::Set(SetResponse::Err(err))
+            | Response::Remove(RemoveResponse::Err(err))
+            | Response::Admin(AdminResponse::Err(err)) => Frame::Error(err),
+        }
+    }
+}
+
+impl Frame {
+    // the whole frame, length prefix included
+    pub fn encode(&self, id: u64) -> Result<Vec<u8>> {
+        let (opcode, body) = match self {
+            Frame::Hello(hello) => (HELLO, serialize(hello)?),
+            Frame::Welcome(welcome) => (WELCOME, serialize(welcome)?),
+            Frame::Auth(credentials) => (AUTH, serialize(credentials)?),
+            Frame::Request(Request::Get { key }) => (GET, serialize(key)?),
+            Frame::Request(Request::Set { key, value }) => (SET, serialize(&(key, value))?),
+            Frame::Request(Request::Remove { key }) => (REMOVE, serialize(key)?),
+            Frame::Request(Request::Admin(req)) => (ADMIN, serialize(req)?),
+            Frame::Value(value) => (VALUE, serialize(value)?),
+            Frame::Done => (DONE, Vec::new()),
+            Frame::Admin(reply) => (ADMIN_REPLY, serialize(reply)?),
+            Frame::Error(err) => (ERROR, serialize(err)?),
+        };
+        let len = HEADER_LEN + body.len();
+        if len > MAX_FRAME_LEN {
+            return Err(KvStoreError::InvalidFrame);
+        }
+        let mut buf = Vec::with_capacity(4 + len);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+        buf.push(opcode);
+        buf.extend_from_slice(&id.to_be_bytes());
+        buf.extend_from_slice(&body);
+        Ok(buf)
+    }
+
+    // The length of a frame, after its length prefix
+    pub fn len(prefix: [u8; 4]) -> Result<usize> {
+        let len = u32::from_be_bytes(prefix) as usize;
+        if !(HEADER_LEN..=MAX_FRAME_LEN).contains(&len) {
+            return Err(KvStoreError::InvalidFrame);
+        }
+        Ok(len)
+    }
+
+    // Parse a frame, without its length prefix. Returns the request id and
+    // the frame.
+    pub fn decode(buf: &[u8]) -> Result<(u64, Frame)> {
+        let (header, body) = buf.split_at(HEADER_LEN);
+        let id = u64::from_be_bytes(header[1..].try_into().unwrap());
+        let frame = match header[0] {
+            HELLO => Frame::Hello(deserialize(body)?),
+            WELCOME => Frame::Welcome(deserialize(body)?),
+            AUTH => Frame::Auth(deserialize(body)?),
+            GET => Frame::Request(Request::Get {
+                key: deserialize(body)?,
+            }),
+            SET => {
+                let (key, value) = deserialize(body)?;
+                Frame::Request(Request::Set { key, value })
+            }
+            REMOVE => Frame::Request(Request::Remove {
+                key: deserialize(body)?,
+            }),
+            ADMIN => Frame::Request(Request::Admin(deserialize(body)?)),
+            VALUE => Frame::Value(deserialize(body)?),
+            DONE if body.is_empty() => Frame::Done,
+            ADMIN_REPLY => Frame::Admin(deserialize(body)?),
+            ERROR => Frame::Error(deserialize(body)?),
+            _ => return Err(KvStoreError::InvalidFrame),
+        };
+        Ok((id, frame))
+    }
+
+    // read a frame from a blocking reader
+    #[cfg(feature = "blocking")]
+    pub fn read<R: Read>(reader: &mut R) -> Result<(u64, Frame)> {
+        let mut prefix = [0u8; 4];
+        reader.read_exact(&mut prefix).map_err(read_error)?;
+        let len = Self::len(prefix)?;
+        // the length is untrusted, so the buffer grows with the bytes read
+        let mut buf = Vec::new();
+        reader
+            .take(len as u64)
+            .read_to_end(&mut buf)
+            .map_err(read_error)?;
+        if buf.len() != len {
+            return Err(KvStoreError::IoError);
+        }
+        Self::decode(&buf)
+    }
+
+    // read a frame from an async reader
+    #[cfg(feature = "async")]
+    pub async fn read_async<R>(reader: &mut R) -> Result<(u64, Frame)>
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        use tokio::io::AsyncReadExt;
+
+        let mut prefix = [0u8; 4];
+        reader.read_exact(&mut prefix).await.map_err(read_error)?;
+        let len = Self::len(prefix)?;
+        // the length is untrusted, so the buffer grows with the bytes read
+        let mut buf = Vec::new();
+        (&mut *reader)
+            .take(len as u64)
+            .read_to_end(&mut buf)
+            .await
+            .map_err(read_error)?;
+        if buf.len() != len {
+            return Err(KvStoreError::IoError);
+        }
+        Self::decode(&buf)
+    }
+}
+
+// Reads the magic and the `Hello` frame of a client, and answers it
+#[cfg(feature = "blocking")]
+pub fn accept<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<Welcome> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic).map_err(read_error)?;
+    let resp = match magic == MAGIC {
+        true => match Frame::read(reader) {
+            Ok((_, frame)) => answer_hello(frame),
+            Err(KvStoreError::InvalidFrame) => Frame::Error(KvStoreError::InvalidFrame),
+            Err(err) => return Err(err),
+        },
+        false => Frame::Error(KvStoreError::InvalidFrame),
+    };
+    writer.write_all(&resp.encode(0)?)?;
+    writer.flush()?;
+    match resp {
+        Frame::Welcome(welcome) => Ok(welcome),
+        Frame::Error(err) => Err(err),
+        _ => unreachable!(),
+    }
+}
+
+// The answer to the first frame of a client, which is a `Welcome` frame if
+// the handshake succeeded
+pub fn answer_hello(frame: Frame) -> Frame {
+    match frame {
+        Frame::Hello(hello) => match hello.negotiate() {
+            Ok(welcome) => Frame::Welcome(welcome),
+            Err(err) => Frame::Error(err),
+        },
+        _ => Frame::Error(KvStoreError::InvalidFrame),
+    }
+}
+
+// Sends the magic and the `Hello` frame of this side, and reads the answer
+#[cfg(feature = "blocking")]
+pub fn connect<R: BufRead, W: Write>(reader: &mut R, writer: &mut W) -> Result<Welcome> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&Frame::Hello(Hello::new()).encode(0)?)?;
+    writer.flush()?;
+    if reader.fill_buf().map_err(read_error)?.first() == Some(&b'{') {
+        let ErrorResponse::Err(err) =
+            ErrorResponse::deserialize(&mut Deserializer::from_reader(reader))?;
+        return Err(err);
+    }
+    match Frame::read(reader)? {
+        (_, Frame::Welcome(welcome)) => Ok(welcome),
+        (_, Frame::Error(err)) => Err(err),
+        _ => Err(KvStoreError::InvalidFrame),
+    }
+}
+
+// Reads a request. JSON requests have id 0.
+#[cfg(feature = "blocking")]
+pub fn read_request<R: BufRead>(protocol: Protocol, reader: &mut R) -> Result<(u64, Request)> {
+    match protocol {
+        Protocol::Json => Request::deserialize(&mut Deserializer::from_reader(reader))
+            .map(|req| (0, req))
+            .map_err(|err| match err.io_error_kind() {
+                Some(kind) => read_error(kind.into()),
+                None if err.is_eof() => KvStoreError::IoError,
+                None => KvStoreError::InvalidRequest,
+            }),
+        Protocol::Binary => match Frame::read(reader)? {
+            (id, Frame::Request(req)) => Ok((id, req)),
+            _ => Err(KvStoreError::InvalidFrame),
+        },
+    }
+}
+
+// Reads the first request of a client, for a server requiring
+// authentication. Returns its id, and its credentials or `None` if it is not
+// an authentication request.
+#[cfg(feature = "blocking")]
+pub fn read_auth<R: BufRead>(
+    protocol: Protocol,
+    reader: &mut R,
+) -> Result<(u64, Option<Credentials>)> {
+    match protocol {
+        Protocol::Json => match AuthRequest::deserialize(&mut Deserializer::from_reader(reader)) {
+            Ok(AuthRequest::Auth(credentials)) => Ok((0, Some(credentials))),
+            Err(err) => match err.io_error_kind() {
+                Some(kind) => Err(read_error(kind.into())),
+                None if err.is_eof() => Err(KvStoreError::IoError),
+                None => Ok((0, None)),
+            },
+        },
+        Protocol::Binary => match Frame::read(reader)? {
+            (id, Frame::Auth(credentials)) => Ok((id, Some(credentials))),
+            (id, Frame::Request(_)) => Ok((id, None)),
+            _ => Err(KvStoreError::InvalidFrame),
+        },
+    }
+}
+
+#[cfg(feature = "blocking")]
+pub fn write_response<W: Write>(
+    protocol: Protocol,
+    writer: &mut W,
+    id: u64,
+    resp: Response,
+) -> Result<()> {
+    match protocol {
+        Protocol::Json => serde_json::to_writer(writer, &resp)?,
+        Protocol::Binary => writer.write_all(&Frame::from(resp).encode(id)?)?,
+    }
+    Ok(())
+}
+
+#[cfg(feature = "blocking")]
+pub fn write_error<W: Write>(
+    protocol: Protocol,
+    writer: &mut W,
+    id: u64,
+    err: KvStoreError,
+) -> Result<()> {
+    match protocol {
+        Protocol::Json => serde_json::to_writer(writer, &ErrorResponse::Err(err))?,
+        Protocol::Binary => writer.write_all(&Frame::Error(err).encode(id)?)?,
+    }
+    Ok(())
+}
+
+// A read blocking past a timeout is a `Timeout`
+pub fn read_error(err: io::Error) -> KvStoreError {
+    match err.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => KvStoreError::Timeout,
+        _ => KvStoreError::IoError,
+    }
+}
+
+fn serialize<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    bincode::serialize(value).map_err(|_| KvStoreError::InvalidFrame)
+}
+
+fn deserialize<T: DeserializeOwned>(body: &[u8]) -> Result<T> {
+    bincode::deserialize(body).map_err(|_| KvStoreError::InvalidFrame)
+}
@@ -0,0 +1,583 @@
+// The Redis serialization protocol (RESP), for Redis tools and clients.
+//
+// Commands are arrays of bulk strings, or inline commands split on
+// whitespace. Replies use RESP2, or RESP3 once a client sends `HELLO 3`.
+//
+// `KvsEngine` has no expiry, so the TTLs set by EXPIRE and SET EX are kept in
+// memory by the RESP server. They are enforced when the key is accessed over
+// RESP, and are lost on restart.
+//
+// A server requiring authentication answers NOAUTH until the client sends
+// `AUTH token`, `AUTH user password` or `HELLO 3 AUTH user password`, where
+// the user `default` stands for a token.
+
+use crate::auth::{Auth, Authorized, Principal};
+use crate::limit::ConnectionLimits;
+use crate::metrics::{Measured, Metrics};
+use crate::protocol::read_error;
+use crate::{Credentials, KvStoreError, KvsEngine, Result};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BinaryHeap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Longest inline command or bulk string header, and longest bulk string
+const MAX_LINE_LEN: usize = 64 << 10;
+const MAX_BULK_LEN: usize = 64 << 20;
+const MAX_ARGS: usize = 1 << 20;
+
+// Page size of SCAN without COUNT
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+// A RESP reply
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    // `None` is null
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+    // an array of pairs in RESP2
+    Map(Vec<(Reply, Reply)>),
+}
+
+// The state of a connection
+#[derive(Debug)]
+pub struct Session {
+    // 2 or 3
+    version: u8,
+    // set by QUIT
+    pub closing: bool,
+    // set by AUTH
+    principal: Option<Arc<Principal>>,
+}
+
+// Expiry times of keys, shared by the connections of a server
+#[derive(Clone, Debug, Default)]
+pub struct Expiries(Arc<Mutex<HashMap<String, Instant>>>);
+
+impl Session {
+    pub fn new() -> Self {
+        Session {
+            version: 2,
+            closing: false,
+            principal: None,
+        }
+    }
+}
+
+impl Reply {
+    fn ok() -> Self {
+        Reply::Simple("OK")
+    }
+
+    fn bulk(s: impl Into<Vec<u8>>) -> Self {
+        Reply::Bulk(Some(s.into()))
+    }
+
+    fn err(msg: impl Into<String>) -> Self {
+        Reply::Error(format!("ERR {}", msg.into()))
+    }
+
+    fn arity(cmd: &str) -> Self {
+        Self::err(format!("wrong number of arguments for '{}' command", cmd))
+    }
+
+    fn syntax() -> Self {
+        Self::err("syntax error")
+    }
+
+    fn not_integer() -> Self {
+        Self::err("value is not an integer or out of range")
+    }
+
+    // write the reply in the protocol version of `session`
+    pub fn write<W: Write>(&self, writer: &mut W, session: &Session) -> Result<()> {
+        match self {
+            Reply::Simple(s) => write!(writer, "+{}\r\n", s)?,
+            Reply::Error(msg) => write!(writer, "-{}\r\n", msg)?,
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n)?,
+            Reply::Bulk(Some(bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")?;
+            }
+            Reply::Bulk(None) if session.version == 3 => writer.write_all(b"_\r\n")?,
+            Reply::Bulk(None) => writer.write_all(b"$-1\r\n")?,
+            Reply::Array(items) => {
+                write!(writer, "*{}\r\n", items.len())?;
+                for item in items {
+                    item.write(writer, session)?;
+                }
+            }
+            Reply::Map(pairs) => {
+                match session.version {
+                    3 => write!(writer, "%{}\r\n", pairs.len())?,
+                    _ => write!(writer, "*{}\r\n", pairs.len() * 2)?,
+                }
+                for (key, value) in pairs {
+                    key.write(writer, session)?;
+                    value.write(writer, session)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Expiries {
+    fn set(&self, key: &str, at: Instant) {
+        self.0.lock().unwrap().insert(key.to_owned(), at);
+    }
+
+    fn clear(&self, key: &str) {
+        self.0.lock().unwrap().remove(key);
+    }
+
+    // Remove `key` from `engine` if it expired
+    fn check<E: KvsEngine>(&self, engine: &E, key: &str) -> Result<()> {
+        let mut expiries = self.0.lock().unwrap();
+        if let Some(at) = expiries.get(key) {
+            if *at <= Instant::now() {
+                expiries.remove(key);
+                match engine.remove(key.to_owned()) {
+                    Ok(()) | Err(KvStoreError::RemoveNonexistingKey) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Read a command. Empty commands are skipped.
+pub fn read_command<R: BufRead>(reader: &mut R) -> Result<Vec<Vec<u8>>> {
+    loop {
+        let line = read_line(reader)?;
+        let args = match line.strip_prefix(b"*") {
+            Some(count) => {
+                // The counts and lengths are untrusted, so the arguments
+                // grow with the bytes read
+                let count = parse_len(count, MAX_ARGS)?;
+                let mut args = Vec::new();
+                for _ in 0..count.unwrap_or(0) {
+                    let header = read_line(reader)?;
+                    let len = match header.strip_prefix(b"$") {
+                        Some(len) => parse_len(len, MAX_BULK_LEN)?,
+                        None => return Err(KvStoreError::InvalidRequest),
+                    };
+                    let len = len.ok_or(KvStoreError::InvalidRequest)? + 2;
+                    let mut arg = Vec::new();
+                    reader
+                        .take(len as u64)
+                        .read_to_end(&mut arg)
+                        .map_err(read_error)?;
+                    if arg.len() != len {
+                        return Err(KvStoreError::IoError);
+                    }
+                    if !arg.ends_with(b"\r\n") {
+                        return Err(KvStoreError::InvalidRequest);
+                    }
+                    arg.truncate(arg.len() - 2);
+                    args.push(arg);
+                }
+                args
+            }
+            None => line
+                .split(u8::is_ascii_whitespace)
+                .filter(|arg| !arg.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect(),
+        };
+        if !args.is_empty() {
+            return Ok(args);
+        }
+    }
+}
+
+// A line without its terminator. Inline commands may end with a bare `\n`.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut line = Vec::new();
+    let n = reader
+        .take(MAX_LINE_LEN as u64)
+        .read_until(b'\n', &mut line)
+        .map_err(read_error)?;
+    if n == 0 {
+        return Err(KvStoreError::IoError);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(KvStoreError::InvalidRequest);
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(line)
+}
+
+// A length, or `None` for a null (-1)
+fn parse_len(bytes: &[u8], max: usize) -> Result<Option<usize>> {
+    let len: i64 = std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or(KvStoreError::InvalidRequest)?;
+    match len {
+        -1 => Ok(None),
+        0.. if len as usize <= max => Ok(Some(len as usize)),
+        _ => Err(KvStoreError::InvalidRequest),
+    }
+}
+
+// Run a command against `engine`. With `auth`, the client must authenticate
+// first, and may only access the keys granted to it.
+pub fn execute<E: KvsEngine>(
+    engine: &E,
+    auth: Option<&Auth>,
+    limits: &ConnectionLimits,
+    metrics: &Metrics,
+    expiries: &Expiries,
+    session: &mut Session,
+    args: Vec<Vec<u8>>,
+) -> Reply {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+    let args: Vec<String> = match args[1..]
+        .iter()
+        .map(|arg| String::from_utf8(arg.clone()))
+        .collect()
+    {
+        Ok(args) => args,
+        Err(_) => return Reply::err("keys and values must be UTF-8"),
+    };
+    if let Some(auth) = auth {
+        match (name.as_str(), args.len()) {
+            ("auth", 1 | 2) => return authenticate(auth, session, &args),
+            ("hello", _) => {
+                if let Some(i) = args.iter().position(|arg| arg.eq_ignore_ascii_case("auth")) {
+                    match args.get(i + 1..i + 3) {
+                        Some(credentials) => {
+                            let reply = authenticate(auth, session, credentials);
+                            if reply != Reply::ok() {
+                                return reply;
+                            }
+                        }
+                        None => return Reply::syntax(),
+                    }
+                }
+            }
+            ("quit", _) => {}
+            _ if session.principal.is_none() => {
+                return Reply::Error("NOAUTH Authentication required.".to_owned())
+            }
+            _ => {}
+        }
+    } else if name == "auth" {
+        return Reply::err("AUTH called without any password configured");
+    }
+
+    let principal = session.principal.clone();
+    let engine = limits.engine(engine.clone(), principal.as_deref());
+    let engine = Measured::new(Authorized::new(engine, principal), metrics);
+    match run(&engine, expiries, session, &name, args) {
+        Ok(reply) => reply,
+        Err(KvStoreError::PermissionDenied) => Reply::Error(
+            "NOPERM this user has no permissions to access one of the keys used as arguments"
+                .to_owned(),
+        ),
+        Err(err) => Reply::err(err.to_string()),
+    }
+}
+
+// AUTH token, or AUTH user password
+fn authenticate(auth: &Auth, session: &mut Session, args: &[String]) -> Reply {
+    let credentials = match args {
+        [token] => Credentials::Token(token.clone()),
+        [user, token] if user == "default" => Credentials::Token(token.clone()),
+        [user, password] => Credentials::Password {
+            user: user.clone(),
+            password: password.clone(),
+        },
+        _ => return Reply::arity("auth"),
+    };
+    match auth.authenticate(&credentials) {
+        Ok(principal) => {
+            session.principal = Some(principal);
+            Reply::ok()
+        }
+        Err(_) => {
+            Reply::Error("WRONGPASS invalid username-password pair or user is disabled.".to_owned())
+        }
+    }
+}
+
+fn run<E: KvsEngine>(
+    engine: &Measured<Authorized<E>>,
+    expiries: &Expiries,
+    session: &mut Session,
+    name: &str,
+    mut args: Vec<String>,
+) -> Result<Reply> {
+    let get = |key: &str| -> Result<Option<String>> {
+        expiries.check(engine.inner().inner(), key)?;
+        engine.get(key.to_owned())
+    };
+    let reply = match (name, args.len()) {
+        ("ping", 0) => Reply::Simple("PONG"),
+        ("ping", 1) => Reply::bulk(args.remove(0)),
+        ("echo", 1) => Reply::bulk(args.remove(0)),
+        ("quit", _) => {
+            session.closing = true;
+            Reply::ok()
+        }
+        ("hello", _) => hello(session, &args),
+        ("select", 1) if args[0] == "0" => Reply::ok(),
+        ("select", 1) => Reply::err("DB index is out of range"),
+        ("command", _) => Reply::Array(Vec::new()),
+        ("info", 0 | 1) => Reply::bulk(format!(
+            "# Server\r\nredis_version:7.0.0\r\nkvs_version:{}\r\nredis_mode:standalone\r\n",
+            env!("CARGO_PKG_VERSION")
+        )),
+        ("get", 1) => Reply::Bulk(get(&args[0])?.map(String::into_bytes)),
+        ("mget", 1..) => {
+            let mut values = Vec::new();
+            for key in args.iter() {
+                values.push(Reply::Bulk(get(key)?.map(String::into_bytes)));
+            }
+            Reply::Array(values)
+        }
+        ("exists", 1..) => {
+            let mut count = 0;
+            for key in args.iter() {
+                count += get(key)?.is_some() as i64;
+            }
+            Reply::Integer(count)
+        }
+        ("set", 2..) => {
+            let mut expiry = None;
+            let mut condition = None;
+            let mut options = args[2..].iter();
+            while let Some(option) = options.next() {
+                match option.to_ascii_lowercase().as_str() {
+                    unit @ ("ex" | "px") => {
+                        let n = match options.next().and_then(|n| n.parse::<u64>().ok()) {
+                            Some(n) if n > 0 => n,
+                            _ => return Ok(Reply::err("invalid expire time in 'set' command")),
+                        };
+                        expiry = Some(match unit {
+                            "ex" => Duration::from_secs(n),
+                            _ => Duration::from_millis(n),
+                        });
+                    }
+                    flag @ ("nx" | "xx") if condition.is_none() => condition = Some(flag == "nx"),
+                    _ => return Ok(Reply::syntax()),
+                }
+            }
+            if let Some(if_missing) = condition {
+                if get(&args[0])?.is_some() == if_missing {
+                    return Ok(Reply::Bulk(None));
+                }
+            }
+            let at = match expiry.map(|ttl| Instant::now().checked_add(ttl)) {
+                Some(None) => return Ok(Reply::err("invalid expire time in 'set' command")),
+                Some(at) => at,
+                None => None,
+            };
+            let (key, value) = (args.swap_remove(0), args.swap_remove(0));
+            match at {
+                Some(at) => expiries.set(&key, at),
+                None => expiries.clear(&key),
+            }
+            engine.set(key, value)?;
+            Reply::ok()
+        }
+        ("mset", n) if n > 0 && n % 2 == 0 => {
+            let mut args = args.into_iter();
+            while let (Some(key), Some(value)) = (args.next(), args.next()) {
+                expiries.clear(&key);
+                engine.set(key, value)?;
+            }
+            Reply::ok()
+        }
+        ("del", 1..) => {
+            let mut count = 0;
+            for key in args {
+                expiries.check(engine.inner().inner(), &key)?;
+                expiries.clear(&key);
+                match engine.remove(key) {
+                    Ok(()) => count += 1,
+                    Err(KvStoreError::RemoveNonexistingKey) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            Reply::Integer(count)
+        }
+        ("expire", 2) => {
+            let secs = match args[1].parse::<i64>() {
+                Ok(secs) => secs,
+                Err(_) => return Ok(Reply::not_integer()),
+            };
+            let at = match secs {
+                ..=0 => None,
+                _ => match Instant::now().checked_add(Duration::from_secs(secs as u64)) {
+                    Some(at) => Some(at),
+                    None => return Ok(Reply::err("invalid expire time in 'expire' command")),
+                },
+            };
+            if get(&args[0])?.is_none() {
+                return Ok(Reply::Integer(0));
+            }
+            match at {
+                Some(at) => expiries.set(&args[0], at),
+                None => {
+                    expiries.clear(&args[0]);
+                    engine.remove(args.swap_remove(0))?;
+                }
+            }
+            Reply::Integer(1)
+        }
+        ("scan", 1..) => scan(engine, expiries, &args)?,
+        (
+            "ping" | "echo" | "select" | "info" | "get" | "mget" | "exists" | "set" | "mset"
+            | "del" | "expire" | "scan",
+            _,
+        ) => Reply::arity(name),
+        _ => Reply::err(format!("unknown command '{}'", name)),
+    };
+    Ok(reply)
+}
+
+// HELLO [protover [AUTH username password] [SETNAME clientname]]
+fn hello(session: &mut Session, args: &[String]) -> Reply {
+    if let Some(version) = args.first() {
+        match version.as_str() {
+            "2" => session.version = 2,
+            "3" => session.version = 3,
+            _ => return Reply::Error("NOPROTO unsupported protocol version".to_owned()),
+        }
+    }
+    let field = |name: &str, value: Reply| (Reply::bulk(name), value);
+    Reply::Map(vec![
+        field("server", Reply::bulk("kvs")),
+        field("version", Reply::bulk(env!("CARGO_PKG_VERSION"))),
+        field("proto", Reply::Integer(session.version as i64)),
+        field("mode", Reply::bulk("standalone")),
+        field("role", Reply::bulk("master")),
+        field("modules", Reply::Array(Vec::new())),
+    ])
+}
+
+// SCAN cursor [MATCH pattern] [COUNT count]
+//
+// The cursor is a position in the order of the keys' hashes, so a call only
+// keeps the `count` keys after it rather than every key. Keys added or removed
+// between calls may be missed or returned twice, as Redis allows, and so may
+// a key whose hash equals that of the last key of a page.
+fn scan<E: KvsEngine>(
+    engine: &Measured<Authorized<E>>,
+    expiries: &Expiries,
+    args: &[String],
+) -> Result<Reply> {
+    let cursor: u64 = match args[0].parse() {
+        Ok(cursor) => cursor,
+        Err(_) => return Ok(Reply::err("invalid cursor")),
+    };
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    let mut options = args[1..].iter();
+    while let Some(option) = options.next() {
+        match (option.to_ascii_lowercase().as_str(), options.next()) {
+            ("match", Some(p)) => pattern = Some(p.as_bytes()),
+            ("count", Some(n)) => match n.parse() {
+                Ok(n) if n > 0 => count = n,
+                _ => return Ok(Reply::not_integer()),
+            },
+            _ => return Ok(Reply::syntax()),
+        }
+    }
+
+    // the `count` keys from the cursor on, largest first
+    let mut keys = BinaryHeap::with_capacity(count + 1);
+    let mut more = false;
+    engine.scan(|key, _| {
+        let position = scan_position(&key);
+        if position >= cursor {
+            keys.push((position, key));
+            if keys.len() > count {
+                keys.pop();
+                more = true;
+            }
+        }
+        Ok(())
+    })?;
+    let next = match keys.peek() {
+        Some((last, _)) if more => last + 1,
+        _ => 0,
+    };
+    let mut page = Vec::new();
+    for (_, key) in keys.into_sorted_vec() {
+        if pattern.is_some_and(|p| !glob_match(p, key.as_bytes())) {
+            continue;
+        }
+        expiries.check(engine.inner().inner(), &key)?;
+        if engine.get(key.to_owned())?.is_some() {
+            page.push(Reply::bulk(key.as_str()));
+        }
+    }
+    Ok(Reply::Array(vec![
+        Reply::bulk(next.to_string()),
+        Reply::Array(page),
+    ]))
+}
+
+// The position of a key for SCAN. It takes 63 bits, so the cursor after the
+// last one is never 0, the cursor that ends a scan.
+fn scan_position(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() >> 1
+}
+
+// Match a glob pattern with `*`, `?` and `\` escapes, in O(pattern * s):
+// on a mismatch, the last `*` is retried one byte further, and earlier ones
+// never need to be.
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // the pattern after the last `*`, and where in `s` it is tried
+    let mut star = None;
+    while i < s.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                star = Some((p, i));
+                continue;
+            }
+            Some(b'?') => {
+                p += 1;
+                i += 1;
+                continue;
+            }
+            Some(&c) => {
+                let (c, len) = match (c, pattern.get(p + 1)) {
+                    (b'\\', Some(&escaped)) => (escaped, 2),
+                    _ => (c, 1),
+                };
+                if s[i] == c {
+                    p += len;
+                    i += 1;
+                    continue;
+                }
+            }
+            None => {}
+        }
+        match star {
+            Some((after, from)) => {
+                p = after;
+                i = from + 1;
+                star = Some((after, i));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
@@ -1,75 +1,1155 @@
 use crate::{
-    message::{GetResponse, RemoveResponse, Request, SetResponse},
+    auth::{Auth, Authorized, Principal},
+    http::{self, HttpResponse},
+    limit::{ConnectionLimits, Limits, Quota, RateLimit},
+    message::{
+        AdminReply, AdminRequest, AdminResponse, ErrorResponse, Request, Response, ServerStats,
+        SetResponse,
+    },
+    metrics::{Measured, Metrics},
+    protocol::{self, Protocol},
+    resp::{self, Expiries, Reply, Session},
+    socket::{self, ListenAddr, Socket},
     thread_pool::ThreadPool,
-    KvsEngine, Result,
+    tls::{ServerTls, Stream},
+    KvStoreError, KvsEngine, Result,
 };
-use serde_json::Deserializer;
-use std::io::Write;
+use std::io::{BufRead, Read, Write};
 use std::{
-    io::{BufReader, BufWriter},
-    net::{SocketAddr, TcpListener, TcpStream},
+    collections::HashMap,
+    io::{self, BufReader, BufWriter},
+    net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream},
+    os::unix::net::UnixStream,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
+use tracing::{debug, field, info_span, warn, Span};
 
-///
+// Bounds of the pause after a failed accept, which doubles while accepts
+// keep failing, e.g. when the process is out of file descriptors
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+// Threads answering the requests of a pipelining connection, and requests
+// read ahead of them
+const PIPELINE_WORKERS: usize = 4;
+const PIPELINE_DEPTH: usize = 64;
+
+// How long the handlers of the connections closed at shutdown get to return,
+// before the shutdown gives up on those stuck in the engine
+const CLOSE_GRACE: Duration = Duration::from_secs(1);
+
+// Rejected connections waiting to be closed, and how long the client of one
+// gets to close its end
+const REJECTED_BACKLOG: usize = 64;
+const REJECTED_LINGER: Duration = Duration::from_millis(100);
+
+/// A server that serves requests against a `KvsEngine`.
 #[derive(Clone, Debug)]
 pub struct KvServer<E: KvsEngine> {
     engine: E,
+    options: ServerOptions,
+    // the listener this accept loop or connection belongs to
+    listener: Arc<Listener>,
+    state: Arc<State>,
+}
+
+/// Protocol and limits of `KvServer`. `None` means unlimited, and so does a
+/// zero timeout.
+///
+/// The protocol, TLS, authentication and socket permissions are those of
+/// the listener of `KvServer::start_with` and `KvServer::start_unix`. With
+/// `KvServer::start_listeners`, each `Listener` has its own.
+#[derive(Clone, Debug, Default)]
+pub struct ServerOptions {
+    /// Protocol spoken by clients
+    pub protocol: ServerProtocol,
+    /// Maximum number of connections open at once
+    pub max_connections: Option<usize>,
+    /// What to do with connections beyond `max_connections`
+    pub overflow: OverflowPolicy,
+    /// Close a connection after waiting this long for a request
+    pub idle_timeout: Option<Duration>,
+    /// Close a connection if a read in the middle of a request blocks this long
+    pub read_timeout: Option<Duration>,
+    /// Close a connection if writing a response blocks this long
+    pub write_timeout: Option<Duration>,
+    /// Fail a request with `KvStoreError::Timeout` if it is not fully
+    /// received this long after its first bytes. The engine call itself is
+    /// not interrupted.
+    pub request_timeout: Option<Duration>,
+    /// Serve over TLS. Clients must complete the handshake within
+    /// `idle_timeout`.
+    pub tls: Option<ServerTls>,
+    /// Permissions of the socket file of `KvServer::start_unix`, e.g. 0o660
+    /// to let the users of its group connect. Those left by the umask if
+    /// unset.
+    pub unix_mode: Option<u32>,
+    /// Require clients to authenticate, and restrict them to the keys
+    /// granted to them
+    pub auth: Option<Auth>,
+    /// Throttle the requests of each connection beyond this rate
+    pub connection_rate: Option<RateLimit>,
+    /// Throttle the requests of each authenticated principal, over all its
+    /// connections, beyond this rate
+    pub principal_rate: Option<RateLimit>,
+    /// Fail the sets that would take the keys and values under a prefix
+    /// beyond its quota with `KvStoreError::QuotaExceeded`. The usage is
+    /// scanned at startup, then counted from the writes of this server: a
+    /// key past its RESP `EXPIRE` counts until it is next accessed, and the
+    /// writes of a gRPC server on the same store are not counted.
+    pub quotas: Vec<Quota>,
+    /// Where the server records its metrics, rendered at `/metrics`
+    pub metrics: Metrics,
+}
+
+/// An address `KvServer` listens on, and the protocol, TLS and
+/// authentication of its clients. The listeners of a server share its
+/// engine, thread pool, limits and metrics.
+#[derive(Clone, Debug)]
+pub struct Listener {
+    /// Where to listen
+    pub addr: ListenAddr,
+    /// Protocol spoken by clients
+    pub protocol: ServerProtocol,
+    /// Serve over TLS. Clients must complete the handshake within
+    /// `ServerOptions::idle_timeout`.
+    pub tls: Option<ServerTls>,
+    /// Require clients to authenticate, and restrict them to the keys
+    /// granted to them
+    pub auth: Option<Auth>,
+    /// Permissions of the socket file of a Unix domain socket, e.g. 0o660.
+    /// Those left by the umask if unset.
+    pub unix_mode: Option<u32>,
+}
+
+impl Listener {
+    /// a listener on `addr` for the clients of `KvClient`, without TLS or
+    /// authentication
+    pub fn new(addr: impl Into<ListenAddr>) -> Self {
+        Listener {
+            addr: addr.into(),
+            protocol: ServerProtocol::Kvs,
+            tls: None,
+            auth: None,
+            unix_mode: None,
+        }
+    }
+}
+
+/// What `KvServer` does with connections beyond `ServerOptions::max_connections`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Answer `KvStoreError::ServerBusy` and close them
+    Reject,
+    /// Leave them in the listen backlog until a connection closes
+    #[default]
+    Queue,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(OverflowPolicy::Reject),
+            "queue" => Ok(OverflowPolicy::Queue),
+            _ => Err(format!("unknown overflow policy: {}", s)),
+        }
+    }
+}
+
+/// Protocol spoken by the clients of `KvServer`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ServerProtocol {
+    /// The binary and JSON protocols of `KvClient`, told apart on each
+    /// connection
+    #[default]
+    Kvs,
+    /// The Redis protocol, RESP2 and RESP3, for Redis clients. Supports GET,
+    /// SET, DEL, EXISTS, MGET, MSET, SCAN, EXPIRE, PING and INFO. Expiry
+    /// times are kept in memory, and lost on restart.
+    Resp,
+    /// A REST gateway over HTTP/1.1: `GET`, `PUT` and `DELETE` on
+    /// `/v1/keys/{key}`, `GET /v1/keys?prefix=` to list keys, `/health` and
+    /// `/metrics`
+    Http,
+    /// Only `/metrics` and `/health` of the HTTP gateway, for Prometheus
+    Metrics,
+}
+
+impl FromStr for ServerProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "kvs" => Ok(ServerProtocol::Kvs),
+            "resp" => Ok(ServerProtocol::Resp),
+            "http" => Ok(ServerProtocol::Http),
+            "metrics" => Ok(ServerProtocol::Metrics),
+            _ => Err(format!("unknown protocol: {}", s)),
+        }
+    }
+}
+
+// State shared by the accept loops, the connections and the `ServerHandle`
+#[derive(Debug, Default)]
+struct State {
+    shutdown: AtomicBool,
+    next_id: AtomicU64,
+    // the end of the shutdown, none to wait for every request in flight
+    deadline: Mutex<Option<Instant>>,
+    // live connections, so that shutdown can close them
+    connections: Mutex<HashMap<u64, Connection>>,
+    // notified when a connection closes, and at shutdown
+    closed: Condvar,
+    // expiry times set over RESP
+    expiries: Expiries,
+    limits: Arc<Limits>,
+    settings: Mutex<Settings>,
+}
+
+// The options that admin requests change while the server runs. A new write
+// timeout applies to new connections.
+#[derive(Clone, Copy, Debug, Default)]
+struct Settings {
+    max_connections: Option<usize>,
+    idle_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+}
+
+impl Settings {
+    fn new(options: &ServerOptions) -> Self {
+        Settings {
+            max_connections: options.max_connections,
+            idle_timeout: nonzero(options.idle_timeout),
+            read_timeout: nonzero(options.read_timeout),
+            write_timeout: nonzero(options.write_timeout),
+            request_timeout: nonzero(options.request_timeout),
+        }
+    }
+
+    // Names and values as `set` takes them, with timeouts in seconds
+    fn list(&self) -> Vec<(String, String)> {
+        let timeout =
+            |t: Option<Duration>| t.map_or("none".into(), |t| t.as_secs_f64().to_string());
+        vec![
+            (
+                "max-connections".into(),
+                self.max_connections
+                    .map_or("none".into(), |max| max.to_string()),
+            ),
+            ("idle-timeout".into(), timeout(self.idle_timeout)),
+            ("read-timeout".into(), timeout(self.read_timeout)),
+            ("write-timeout".into(), timeout(self.write_timeout)),
+            ("request-timeout".into(), timeout(self.request_timeout)),
+        ]
+    }
+
+    // Fails with `InvalidRequest` for an unknown name, a malformed value or
+    // a zero timeout. "none" lifts a limit.
+    fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        fn parse<T: FromStr>(value: &str) -> Result<Option<T>> {
+            match value {
+                "none" => Ok(None),
+                _ => value
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| KvStoreError::InvalidRequest),
+            }
+        }
+        let timeout = |value| match parse::<f64>(value)? {
+            Some(secs) => match Duration::try_from_secs_f64(secs) {
+                Ok(timeout) if !timeout.is_zero() => Ok(Some(timeout)),
+                _ => Err(KvStoreError::InvalidRequest),
+            },
+            None => Ok(None),
+        };
+        match name {
+            "max-connections" => self.max_connections = parse(value)?,
+            "idle-timeout" => self.idle_timeout = timeout(value)?,
+            "read-timeout" => self.read_timeout = timeout(value)?,
+            "write-timeout" => self.write_timeout = timeout(value)?,
+            "request-timeout" => self.request_timeout = timeout(value)?,
+            _ => return Err(KvStoreError::InvalidRequest),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Connection {
+    stream: Socket,
+    // requests being received or not answered yet
+    in_flight: usize,
+}
+
+/// A running `KvServer`, returned by `KvServer::start`.
+pub struct ServerHandle {
+    addrs: Vec<ListenAddr>,
+    state: Arc<State>,
+    server_thread: JoinHandle<Result<()>>,
 }
 
 impl<E: KvsEngine> KvServer<E> {
-    ///
-    pub fn serve(engine: E, thread_pool: impl ThreadPool, addr: SocketAddr) -> Result<()> {
-        let server = KvServer { engine };
-        let listener = TcpListener::bind(addr).unwrap();
-        for stream in listener.incoming() {
-            let stream = stream.unwrap();
-            let mut server = server.clone();
-            thread_pool.spawn(move || {
-                server.handle_connection(stream).unwrap();
+    /// serve requests on `addr`, handling each connection in `thread_pool`.
+    /// Blocks until the server is shut down.
+    pub fn serve(
+        engine: E,
+        thread_pool: impl ThreadPool + Send + 'static,
+        addr: SocketAddr,
+    ) -> Result<()> {
+        Self::start(engine, thread_pool, addr)?.wait()
+    }
+
+    /// start serving requests on `addr` in the background, handling each
+    /// connection in `thread_pool`. Fails with `KvStoreError::BindError` if
+    /// `addr` cannot be listened on.
+    pub fn start(
+        engine: E,
+        thread_pool: impl ThreadPool + Send + 'static,
+        addr: SocketAddr,
+    ) -> Result<ServerHandle> {
+        Self::start_with(engine, thread_pool, addr, ServerOptions::default())
+    }
+
+    /// start serving requests on `addr` in the background, with the given
+    /// limits. With quotas, the store is scanned for their usage first.
+    pub fn start_with(
+        engine: E,
+        thread_pool: impl ThreadPool + Send + 'static,
+        addr: SocketAddr,
+        options: ServerOptions,
+    ) -> Result<ServerHandle> {
+        let listener = Self::listener(addr.into(), &options);
+        Self::start_listeners(engine, thread_pool, vec![listener], options)
+    }
+
+    /// start serving requests on the Unix domain socket at `path` in the
+    /// background, with the given limits. The socket file is replaced if
+    /// left by a server that is gone, and removed at shutdown. Fails with
+    /// `KvStoreError::BindError` if `path` cannot be listened on.
+    pub fn start_unix(
+        engine: E,
+        thread_pool: impl ThreadPool + Send + 'static,
+        path: impl AsRef<Path>,
+        options: ServerOptions,
+    ) -> Result<ServerHandle> {
+        let listener = Self::listener(ListenAddr::Unix(path.as_ref().to_owned()), &options);
+        Self::start_listeners(engine, thread_pool, vec![listener], options)
+    }
+
+    /// start serving requests on each of `listeners` in the background, with
+    /// the given limits, and the protocol, TLS and authentication of each
+    /// listener. Their connections share `thread_pool`, and count towards
+    /// the same connection limit. Fails with `KvStoreError::BindError` if
+    /// one of them cannot be listened on.
+    pub fn start_listeners(
+        engine: E,
+        thread_pool: impl ThreadPool + Send + 'static,
+        listeners: Vec<Listener>,
+        options: ServerOptions,
+    ) -> Result<ServerHandle> {
+        let mut sockets = Vec::new();
+        for listener in &listeners {
+            let socket = match &listener.addr {
+                ListenAddr::Tcp(addr) => socket::Listener::bind_tcp(*addr),
+                ListenAddr::Unix(path) => socket::Listener::bind_unix(path, listener.unix_mode),
+            };
+            sockets.push(socket.map_err(|err| {
+                warn!("failed to bind {}: {}", listener.addr, err);
+                KvStoreError::BindError
+            })?);
+        }
+        let addrs = sockets
+            .iter()
+            .map(socket::Listener::local_addr)
+            .collect::<io::Result<_>>()?;
+        let limits = Limits::new(
+            &engine,
+            options.connection_rate,
+            options.principal_rate,
+            &options.quotas,
+        )?;
+        let state = Arc::new(State {
+            limits: Arc::new(limits),
+            settings: Mutex::new(Settings::new(&options)),
+            ..State::default()
+        });
+        let mut servers = Vec::new();
+        for (listener, socket) in listeners.into_iter().zip(sockets) {
+            let server = KvServer {
+                engine: engine.clone(),
+                options: options.clone(),
+                listener: Arc::new(listener),
+                state: state.clone(),
+            };
+            servers.push((server, socket));
+        }
+        let server_state = state.clone();
+        let server_thread = thread::spawn(move || run(engine, &server_state, servers, thread_pool));
+
+        Ok(ServerHandle {
+            addrs,
+            state,
+            server_thread,
+        })
+    }
+
+    // the listener of `start_with` and `start_unix`
+    fn listener(addr: ListenAddr, options: &ServerOptions) -> Listener {
+        Listener {
+            addr,
+            protocol: options.protocol,
+            tls: options.tls.clone(),
+            auth: options.auth.clone(),
+            unix_mode: options.unix_mode,
+        }
+    }
+
+    // Accept connections until shutdown, handing them to the pool
+    fn accept_loop<P: ThreadPool>(self, listener: socket::Listener, thread_pool: &Mutex<P>) {
+        let mut backoff = MIN_ACCEPT_BACKOFF;
+        // ends with the loop, once `rejected` is dropped
+        let (rejected, closing) = mpsc::sync_channel::<Socket>(REJECTED_BACKLOG);
+        thread::spawn(move || closing.into_iter().for_each(close_rejected));
+        loop {
+            if self.options.overflow == OverflowPolicy::Queue {
+                self.wait_for_slot();
+            }
+            if self.state.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = match listener.accept() {
+                Ok(stream) => {
+                    backoff = MIN_ACCEPT_BACKOFF;
+                    stream
+                }
+                Err(err) => {
+                    warn!("accept failed: {}, retrying in {:?}", err, backoff);
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                    continue;
+                }
+            };
+            let id = self.state.next_id.fetch_add(1, Ordering::SeqCst);
+            let connection = match stream.try_clone() {
+                Ok(stream) => Connection {
+                    stream,
+                    in_flight: 0,
+                },
+                Err(err) => {
+                    warn!("dropping connection: {}", err);
+                    continue;
+                }
+            };
+            if !self.register(id, connection) {
+                self.options.metrics.count_error(KvStoreError::ServerBusy);
+                // Over TLS, the connection is closed without an answer
+                if self.listener.tls.is_some() {
+                    continue;
+                }
+                let _ = match self.listener.protocol {
+                    ServerProtocol::Kvs => {
+                        let resp = ErrorResponse::Err(KvStoreError::ServerBusy);
+                        serde_json::to_writer(&stream, &resp).map_err(KvStoreError::from)
+                    }
+                    ServerProtocol::Resp => {
+                        Reply::Error("ERR max number of clients reached".into())
+                            .write(&mut &stream, &Session::new())
+                    }
+                    ServerProtocol::Http | ServerProtocol::Metrics => {
+                        HttpResponse::error(KvStoreError::ServerBusy).write(&mut &stream, false)
+                    }
+                };
+                // dropped right away if too many are waiting
+                let _ = rejected.try_send(stream);
+                continue;
+            }
+            self.options.metrics.connection_opened();
+
+            let server = self.clone();
+            self.options.metrics.job_queued();
+            thread_pool.lock().unwrap().spawn(move || {
+                server.options.metrics.job_started();
+                let _guard = ConnectionGuard {
+                    id,
+                    state: server.state.clone(),
+                    metrics: server.options.metrics.clone(),
+                };
+                let peer = stream.peer_addr().map(field::display);
+                let protocol = server.listener.protocol;
+                let _enter = info_span!("connection", id, peer, ?protocol).entered();
+                if let Err(err) = server.handle_connection(id, stream) {
+                    debug!("connection closed: {}", err);
+                }
             })
         }
+    }
 
-        Ok(())
+    fn settings(&self) -> Settings {
+        *self.state.settings.lock().unwrap()
+    }
+
+    // Register a connection, unless there are `max_connections` already.
+    // Checked and inserted at once, as the accept loops of all the listeners
+    // register theirs.
+    fn register(&self, id: u64, connection: Connection) -> bool {
+        let mut connections = self.state.connections.lock().unwrap();
+        match self.settings().max_connections {
+            Some(max) if connections.len() >= max => false,
+            _ => {
+                connections.insert(id, connection);
+                true
+            }
+        }
+    }
+
+    // block until there are less than `max_connections`, or shutdown starts
+    fn wait_for_slot(&self) {
+        let mut connections = self.state.connections.lock().unwrap();
+        while let Some(max) = self.settings().max_connections {
+            if connections.len() < max || self.state.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            connections = self.state.closed.wait(connections).unwrap();
+        }
     }
 
-    fn handle_connection(&mut self, stream: TcpStream) -> Result<()> {
-        let reader = BufReader::new(&stream);
-        let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
-        let mut resp_writer = BufWriter::new(&stream);
+    fn handle_connection(&self, id: u64, stream: Socket) -> Result<()> {
+        let settings = self.settings();
+        stream.set_write_timeout(settings.write_timeout)?;
+        let stream = match &self.listener.tls {
+            Some(tls) => {
+                let stream = tls.accept(stream)?;
+                stream.socket().set_read_timeout(settings.idle_timeout)?;
+                stream.handshake()?;
+                Stream::Tls(Arc::new(stream))
+            }
+            None => Stream::Plain(stream),
+        };
+        let mut reader = BufReader::new(TimedReader {
+            stream: &stream,
+            timeout: settings.idle_timeout,
+            deadline: None,
+        });
+        let writer = Mutex::new(BufWriter::new(&stream));
+        let limits = self.state.limits.connection();
+        match self.listener.protocol {
+            ServerProtocol::Kvs => {}
+            ServerProtocol::Resp => {
+                return self.serve_resp(id, &limits, &mut reader, &mut *writer.lock().unwrap())
+            }
+            ServerProtocol::Http | ServerProtocol::Metrics => {
+                return self.serve_http(id, &limits, &mut reader, &mut *writer.lock().unwrap())
+            }
+        }
+
+        // The first bytes of a connection tell its protocol
+        let first = match reader.fill_buf()?.first() {
+            Some(first) => *first,
+            None => return Ok(()),
+        };
+        let mut pipelining = false;
+        let protocol = if first != protocol::MAGIC[0] {
+            Protocol::Json
+        } else {
+            self.state.begin_request(id);
+            reader.get_mut().timeout = settings.read_timeout;
+            reader.get_mut().deadline = deadline(settings.request_timeout);
+            let welcome = protocol::accept(&mut reader, &mut *writer.lock().unwrap())?;
+            self.state.end_request(id);
+            pipelining = welcome.features.iter().any(|f| f == protocol::PIPELINING);
+            Protocol::Binary
+        };
 
-        for req in req_reader {
-            let req = req?;
-            println!("req: {:?}", req);
+        let principal = match &self.listener.auth {
+            Some(auth) => Some(self.authenticate(auth, id, protocol, &mut reader, &writer)?),
+            None => None,
+        };
+        let engine = limits.engine(self.engine.clone(), principal.as_deref());
+        let engine = Measured::new(Authorized::new(engine, principal), &self.options.metrics);
+        let metrics = &self.options.metrics;
+        if !pipelining {
+            return self.read_requests(id, protocol, &mut reader, &writer, |job| {
+                answer(&engine, &self.state, metrics, id, protocol, job, &writer)
+            });
+        }
 
-            match req {
-                Request::Get { key } => {
-                    let resp = match self.engine.get(key) {
-                        Ok(res) => GetResponse::Ok(res),
-                        Err(err) => GetResponse::Err(err),
+        // Answer requests on `PIPELINE_WORKERS` threads, in any order. The
+        // last request on each key is kept, and the next one on the key
+        // waits for it, so that they apply in the order they were sent. The
+        // sender is dropped when the request is answered.
+        let (tx, rx) = mpsc::sync_channel::<Pipelined>(PIPELINE_DEPTH);
+        let rx = Mutex::new(rx);
+        let span = Span::current();
+        let mut last: HashMap<String, mpsc::Receiver<()>> = HashMap::new();
+        thread::scope(|scope| {
+            // dropped when reading stops, which stops the workers
+            let tx = tx;
+            for _ in 0..PIPELINE_WORKERS {
+                let engine = engine.clone();
+                let (state, rx, writer, span) = (&self.state, &rx, &writer, &span);
+                scope.spawn(move || loop {
+                    let _enter = span.enter();
+                    // not `while let`, which would hold the lock while answering
+                    let (job, previous, done) = match rx.lock().unwrap().recv() {
+                        Ok(pipelined) => pipelined,
+                        Err(_) => break,
                     };
-                    println!("resp: {:?}", resp);
-                    serde_json::to_writer(&mut resp_writer, &resp)
+                    if let Some(previous) = previous {
+                        let _ = previous.recv();
+                    }
+                    let result = answer(&engine, state, metrics, id, Protocol::Binary, job, writer);
+                    drop(done);
+                    if let Err(err) = result {
+                        debug!("failed to answer: {}", err);
+                    }
+                });
+            }
+            self.read_requests(id, Protocol::Binary, &mut reader, &writer, |job| {
+                let (done, previous) = match job.req.key() {
+                    Some(key) => {
+                        // forget the keys whose requests are all answered
+                        if last.len() >= PIPELINE_DEPTH {
+                            last.retain(|_, answered| {
+                                matches!(answered.try_recv(), Err(mpsc::TryRecvError::Empty))
+                            });
+                        }
+                        let (done, answered) = mpsc::channel::<()>();
+                        (Some(done), last.insert(key.to_owned(), answered))
+                    }
+                    None => (None, None),
+                };
+                tx.send((job, previous, done))
+                    .map_err(|_| KvStoreError::IoError)
+            })
+        })
+    }
+
+    // Read the credentials of a client and answer them. The connection
+    // closes if they are missing or wrong.
+    fn authenticate<W: Write>(
+        &self,
+        auth: &Auth,
+        id: u64,
+        protocol: Protocol,
+        reader: &mut BufReader<TimedReader>,
+        writer: &Mutex<W>,
+    ) -> Result<Arc<Principal>> {
+        let settings = self.settings();
+        reader.get_mut().timeout = settings.idle_timeout;
+        reader.get_mut().deadline = None;
+        if reader.fill_buf()?.is_empty() {
+            return Err(KvStoreError::IoError);
+        }
+
+        self.state.begin_request(id);
+        reader.get_mut().timeout = settings.read_timeout;
+        reader.get_mut().deadline = deadline(settings.request_timeout);
+        let (req_id, credentials) = protocol::read_auth(protocol, reader)?;
+        let result = match credentials {
+            Some(credentials) => auth.authenticate(&credentials),
+            None => Err(KvStoreError::PermissionDenied),
+        };
+        self.state.end_request(id);
+        if let Err(err) = result {
+            self.options.metrics.count_error(err);
+        }
+
+        let mut writer = writer.lock().unwrap();
+        match &result {
+            Ok(principal) => {
+                debug!("authenticated as {}", principal.name());
+                let resp = Response::Set(SetResponse::Ok);
+                protocol::write_response(protocol, &mut *writer, req_id, resp)?;
+            }
+            Err(err) => protocol::write_error(protocol, &mut *writer, req_id, *err)?,
+        }
+        writer.flush()?;
+        result
+    }
+
+    // Read requests until the connection closes or the server shuts down,
+    // passing each to `dispatch`
+    fn read_requests<W, F>(
+        &self,
+        id: u64,
+        protocol: Protocol,
+        reader: &mut BufReader<TimedReader>,
+        writer: &Mutex<W>,
+        mut dispatch: F,
+    ) -> Result<()>
+    where
+        W: Write,
+        F: FnMut(Job) -> Result<()>,
+    {
+        loop {
+            // Wait for the first bytes of a request before marking the
+            // connection busy. At shutdown, idle connections see EOF here.
+            let settings = self.settings();
+            reader.get_mut().timeout = settings.idle_timeout;
+            reader.get_mut().deadline = None;
+            if reader.fill_buf()?.is_empty() {
+                break;
+            }
+
+            self.state.begin_request(id);
+            let deadline = deadline(settings.request_timeout);
+            reader.get_mut().timeout = settings.read_timeout;
+            reader.get_mut().deadline = deadline;
+            let (req_id, req) = match protocol::read_request(protocol, reader) {
+                Ok(req) => req,
+                Err(
+                    err @ (KvStoreError::InvalidRequest
+                    | KvStoreError::InvalidFrame
+                    | KvStoreError::Timeout),
+                ) => {
+                    // The rest of the stream cannot be parsed: answer with an
+                    // error and close the connection
+                    warn!("invalid request: {}", err);
+                    self.options.metrics.count_error(err);
+                    let mut writer = writer.lock().unwrap();
+                    protocol::write_error(protocol, &mut *writer, 0, err)?;
+                    writer.flush()?;
+                    return Err(err);
                 }
-                Request::Set { key, value } => {
-                    let resp = match self.engine.set(key, value) {
-                        Ok(()) => SetResponse::Ok,
-                        Err(err) => SetResponse::Err(err),
-                    };
-                    println!("resp: {:?}", resp);
-                    serde_json::to_writer(&mut resp_writer, &resp)
+                Err(err) => return Err(err),
+            };
+
+            dispatch(Job {
+                id: req_id,
+                req,
+                deadline,
+            })?;
+            if self.state.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Answer RESP commands until the connection closes, the client quits or
+    // the server shuts down
+    fn serve_resp<W: Write>(
+        &self,
+        id: u64,
+        limits: &ConnectionLimits,
+        reader: &mut BufReader<TimedReader>,
+        writer: &mut W,
+    ) -> Result<()> {
+        let mut session = Session::new();
+        loop {
+            let settings = self.settings();
+            reader.get_mut().timeout = settings.idle_timeout;
+            reader.get_mut().deadline = None;
+            if reader.fill_buf()?.is_empty() {
+                break;
+            }
+
+            self.state.begin_request(id);
+            let deadline = deadline(settings.request_timeout);
+            reader.get_mut().timeout = settings.read_timeout;
+            reader.get_mut().deadline = deadline;
+            let reply = match resp::read_command(reader) {
+                Ok(args) => resp::execute(
+                    &self.engine,
+                    self.listener.auth.as_ref(),
+                    limits,
+                    &self.options.metrics,
+                    &self.state.expiries,
+                    &mut session,
+                    args,
+                ),
+                Err(err @ (KvStoreError::InvalidRequest | KvStoreError::Timeout)) => {
+                    warn!("invalid request: {}", err);
+                    self.options.metrics.count_error(err);
+                    Reply::Error(format!("ERR Protocol error: {}", err)).write(writer, &session)?;
+                    writer.flush()?;
+                    return Err(err);
                 }
-                Request::Remove { key } => {
-                    let resp = match self.engine.remove(key) {
-                        Ok(()) => RemoveResponse::Ok,
-                        Err(err) => RemoveResponse::Err(err),
-                    };
-                    println!("resp: {:?}", resp);
-                    serde_json::to_writer(&mut resp_writer, &resp)
+                Err(err) => return Err(err),
+            };
+            self.state.end_request(id);
+            reply.write(writer, &session)?;
+            writer.flush()?;
+            if session.closing || self.state.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Answer HTTP requests until the connection closes, the client asks to
+    // close it or the server shuts down
+    fn serve_http<W: Write>(
+        &self,
+        id: u64,
+        limits: &ConnectionLimits,
+        reader: &mut BufReader<TimedReader>,
+        writer: &mut W,
+    ) -> Result<()> {
+        let mut verified = None;
+        loop {
+            let settings = self.settings();
+            reader.get_mut().timeout = settings.idle_timeout;
+            reader.get_mut().deadline = None;
+            if reader.fill_buf()?.is_empty() {
+                break;
+            }
+
+            self.state.begin_request(id);
+            reader.get_mut().timeout = settings.read_timeout;
+            reader.get_mut().deadline = deadline(settings.request_timeout);
+            let req = match http::read_request(reader) {
+                Ok(req) => req,
+                Err(err @ (KvStoreError::InvalidRequest | KvStoreError::Timeout)) => {
+                    warn!("invalid request: {}", err);
+                    self.options.metrics.count_error(err);
+                    let resp = HttpResponse::error(err);
+                    self.options.metrics.count_http(resp.status);
+                    resp.write(writer, false)?;
+                    writer.flush()?;
+                    return Err(err);
+                }
+                Err(err) => return Err(err),
+            };
+            let shutdown = self.state.shutdown.load(Ordering::SeqCst);
+            let resp = match (self.listener.protocol, req.path.as_str()) {
+                (ServerProtocol::Metrics, "/metrics" | "/health") | (ServerProtocol::Http, _) => {
+                    let auth = self.listener.auth.as_ref();
+                    let metrics = &self.options.metrics;
+                    http::handle(
+                        &self.engine,
+                        auth,
+                        &mut verified,
+                        limits,
+                        metrics,
+                        &req,
+                        !shutdown,
+                    )
                 }
-            }?;
-            resp_writer.flush()?;
+                _ => HttpResponse::not_found(),
+            };
+            self.state.end_request(id);
+            self.options.metrics.count_http(resp.status);
+            let keep_alive = req.keep_alive && !shutdown;
+            resp.write(writer, keep_alive)?;
+            writer.flush()?;
+            if !keep_alive {
+                break;
+            }
         }
 
         Ok(())
     }
 }
+
+// Accept connections on each listener until shutdown, then let the
+// connections finish, and flush the engine
+fn run<E: KvsEngine, P: ThreadPool + Send>(
+    engine: E,
+    state: &State,
+    servers: Vec<(KvServer<E>, socket::Listener)>,
+    thread_pool: P,
+) -> Result<()> {
+    let thread_pool = Mutex::new(thread_pool);
+    thread::scope(|scope| {
+        for (server, listener) in servers {
+            let thread_pool = &thread_pool;
+            scope.spawn(move || server.accept_loop(listener, thread_pool));
+        }
+    });
+    // returns once every connection has, so that all their writes are
+    // flushed
+    state.drain();
+    engine.flush()?;
+    Ok(())
+}
+
+// Zero timeouts, which sockets refuse, count as none
+fn nonzero(timeout: Option<Duration>) -> Option<Duration> {
+    timeout.filter(|timeout| !timeout.is_zero())
+}
+
+// Close a rejected connection after its client has closed its end, or
+// stopped sending. Closing it with the request unread would reset it, and
+// the client could lose the answer.
+fn close_rejected(stream: Socket) {
+    let _ = stream.shutdown(Shutdown::Write);
+    let _ = stream.set_read_timeout(Some(REJECTED_LINGER));
+    let deadline = Instant::now() + REJECTED_LINGER;
+    let mut buf = [0; 1024];
+    while Instant::now() < deadline {
+        match (&stream).read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+    }
+}
+
+// The deadline of a request starting now. There is none if the timeout is
+// too long for `Instant`.
+fn deadline(timeout: Option<Duration>) -> Option<Instant> {
+    timeout.and_then(|timeout| Instant::now().checked_add(timeout))
+}
+
+// A request read from a connection
+struct Job {
+    id: u64,
+    req: Request,
+    deadline: Option<Instant>,
+}
+
+// A job of a pipelining connection, the previous request on its key to wait
+// for, and the sender to drop once it is answered
+type Pipelined = (Job, Option<mpsc::Receiver<()>>, Option<mpsc::Sender<()>>);
+
+// Apply a request and write its response
+fn answer<E: KvsEngine, W: Write>(
+    engine: &Measured<Authorized<E>>,
+    state: &State,
+    metrics: &Metrics,
+    conn_id: u64,
+    protocol: Protocol,
+    job: Job,
+    writer: &Mutex<W>,
+) -> Result<()> {
+    let resp = match job.deadline {
+        Some(deadline) if Instant::now() >= deadline => None,
+        _ => Some(match job.req {
+            Request::Admin(req) => Response::Admin(admin(engine, state, metrics, req)),
+            req => req.apply(engine),
+        }),
+    };
+    // Done before writing the response, after which the client may start
+    // a shutdown expecting this connection to be idle
+    state.end_request(conn_id);
+    let mut writer = writer.lock().unwrap();
+    match resp {
+        Some(resp) => protocol::write_response(protocol, &mut *writer, job.id, resp)?,
+        None => {
+            metrics.count_error(KvStoreError::Timeout);
+            protocol::write_error(protocol, &mut *writer, job.id, KvStoreError::Timeout)?
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// Answer an admin request. Only pings are allowed to principals that are not
+// administrators.
+fn admin<E: KvsEngine>(
+    engine: &Measured<Authorized<E>>,
+    state: &State,
+    metrics: &Metrics,
+    req: AdminRequest,
+) -> AdminResponse {
+    if !matches!(req, AdminRequest::Ping) {
+        if let Err(err) = engine.inner().check_admin() {
+            metrics.count_error(err);
+            return AdminResponse::Err(err);
+        }
+    }
+    let result = match req {
+        AdminRequest::Ping => Ok(AdminReply::Pong),
+        AdminRequest::Stats => Ok(AdminReply::Stats(ServerStats {
+            connections: metrics.connections(),
+            engine: engine.stats(),
+        })),
+        AdminRequest::Compact => engine.compact().map(|()| AdminReply::Done),
+        AdminRequest::Flush => engine.flush().map(|()| AdminReply::Done),
+        AdminRequest::GetConfig => Ok(AdminReply::Config(state.settings.lock().unwrap().list())),
+        AdminRequest::SetConfig { name, value } => {
+            let result = state.settings.lock().unwrap().set(&name, &value);
+            state.wake_accept_loop();
+            result
+                .map(|()| AdminReply::Done)
+                .inspect_err(|err| metrics.count_error(*err))
+        }
+    };
+    match result {
+        Ok(reply) => AdminResponse::Ok(reply),
+        Err(err) => AdminResponse::Err(err),
+    }
+}
+
+impl State {
+    // Close idle connections, and wait until the deadline for the requests
+    // in flight, after which their connections close. Then close the
+    // connections that are left, and wait a moment for their handlers to
+    // return.
+    fn drain(&self) {
+        let deadline = *self.deadline.lock().unwrap();
+        let mut connections = self.connections.lock().unwrap();
+        for connection in connections.values().filter(|c| c.in_flight == 0) {
+            let _ = connection.stream.shutdown(Shutdown::Read);
+        }
+        while !connections.is_empty() {
+            connections = match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if timeout.is_zero() {
+                        break;
+                    }
+                    self.closed.wait_timeout(connections, timeout).unwrap().0
+                }
+                None => self.closed.wait(connections).unwrap(),
+            };
+        }
+        for connection in connections.values() {
+            let _ = connection.stream.shutdown(Shutdown::Both);
+        }
+        // Their handlers return once their sockets are closed, unless they
+        // are stuck in the engine. Not every pool joins its threads when
+        // dropped, so they are waited for here.
+        let grace = Instant::now() + CLOSE_GRACE;
+        while !connections.is_empty() {
+            let timeout = grace.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                let mut ids: Vec<_> = connections.keys().collect();
+                ids.sort();
+                warn!("handlers of connections {:?} did not return", ids);
+                break;
+            }
+            connections = self.closed.wait_timeout(connections, timeout).unwrap().0;
+        }
+    }
+
+    // Wake up an accept loop waiting for a connection to close, to check the
+    // limit and shutdown again
+    fn wake_accept_loop(&self) {
+        let _connections = self.connections.lock().unwrap();
+        self.closed.notify_all();
+    }
+
+    fn begin_request(&self, id: u64) {
+        if let Some(connection) = self.connections.lock().unwrap().get_mut(&id) {
+            connection.in_flight += 1;
+        }
+    }
+
+    // At shutdown, close a connection once it is idle
+    fn end_request(&self, id: u64) {
+        if let Some(connection) = self.connections.lock().unwrap().get_mut(&id) {
+            connection.in_flight -= 1;
+            if connection.in_flight == 0 && self.shutdown.load(Ordering::SeqCst) {
+                let _ = connection.stream.shutdown(Shutdown::Read);
+            }
+        }
+    }
+}
+
+// Reads from a connection, blocking for at most `timeout` at a time, and
+// failing with `TimedOut` once `deadline` has passed
+struct TimedReader<'a> {
+    stream: &'a Stream,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+}
+
+impl Read for TimedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut timeout = self.timeout;
+        if let Some(deadline) = self.deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            timeout = Some(timeout.map_or(left, |timeout| timeout.min(left)));
+        }
+        self.stream.socket().set_read_timeout(timeout)?;
+        self.stream.read(buf)
+    }
+}
+
+// Unregisters a connection when its handler returns or panics
+struct ConnectionGuard {
+    id: u64,
+    state: Arc<State>,
+    metrics: Metrics,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = self.state.connections.lock().unwrap();
+        connections.remove(&self.id);
+        self.metrics.connection_closed();
+        self.state.closed.notify_all();
+    }
+}
+
+impl ServerHandle {
+    /// the address the server listens on, the first one with several
+    /// listeners. Panics if it is a Unix domain socket.
+    pub fn local_addr(&self) -> SocketAddr {
+        match &self.addrs[0] {
+            ListenAddr::Tcp(addr) => *addr,
+            ListenAddr::Unix(path) => panic!("listening on {}", path.display()),
+        }
+    }
+
+    /// the TCP addresses and Unix domain sockets the server listens on, in
+    /// the order of its listeners
+    pub fn listen_addrs(&self) -> &[ListenAddr] {
+        &self.addrs
+    }
+
+    /// Apply the connection limit, timeouts and rate limits of `options`,
+    /// ignoring the others. The new write timeout and connection rate apply
+    /// to the connections accepted from then on.
+    pub fn reload(&self, options: &ServerOptions) {
+        *self.state.settings.lock().unwrap() = Settings::new(options);
+        self.state
+            .limits
+            .set_rates(options.connection_rate, options.principal_rate);
+        self.state.wake_accept_loop();
+    }
+
+    /// Stop accepting connections, and let the requests in flight finish for
+    /// up to `timeout`, or for as long as they take if `timeout` is too long
+    /// for `Instant`. Then close the remaining connections, wait a moment
+    /// for their handlers to return, and flush the engine. The threads of the pool may
+    /// outlive the call, idle.
+    pub fn shutdown(self, timeout: Duration) -> Result<()> {
+        *self.state.deadline.lock().unwrap() = Instant::now().checked_add(timeout);
+        self.state.shutdown.store(true, Ordering::SeqCst);
+        self.state.wake_accept_loop();
+
+        // wake up the accept loops
+        for addr in &self.addrs {
+            match addr {
+                ListenAddr::Tcp(addr) => {
+                    let mut wake_addr = *addr;
+                    if wake_addr.ip().is_unspecified() {
+                        wake_addr.set_ip(match wake_addr {
+                            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                        });
+                    }
+                    let _ = TcpStream::connect(wake_addr);
+                }
+                ListenAddr::Unix(path) => {
+                    let _ = UnixStream::connect(path);
+                }
+            }
+        }
+
+        self.wait()
+    }
+
+    /// block until the server is shut down
+    pub fn wait(self) -> Result<()> {
+        self.server_thread.join().expect("accept loop panicked")
+    }
+}
@@ -4,20 +4,22 @@ use crate::Result;
 pub use self::naive::NaiveThreadPool;
 pub use self::rayon::RayonThreadPool;
 pub use self::shared_queue::SharedQueueThreadPool;
+pub use self::work_stealing::WorkStealingThreadPool;
 
-///
+/// A pool of threads that runs jobs.
 pub trait ThreadPool {
-    ///
+    /// creates a pool with `threads` threads
     fn new(threads: u32) -> Result<Self>
     where
         Self: Sized;
 
-    ///
+    /// runs `job` on one of the threads
     fn spawn<F>(&self, job: F)
     where
-        F: FnOnce() + Send +  'static;
+        F: FnOnce() + Send + 'static;
 }
 
 mod naive;
 mod rayon;
 mod shared_queue;
+mod work_stealing;
@@ -2,10 +2,9 @@ use std::thread;
 
 use crate::thread_pool::ThreadPool;
 
-///
+/// Spawns a new thread for every job.
 pub struct NaiveThreadPool {}
 
-///
 impl ThreadPool for NaiveThreadPool {
     fn new(_threads: u32) -> crate::Result<Self>
     where
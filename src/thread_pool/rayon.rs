@@ -1,12 +1,11 @@
 use crate::thread_pool::ThreadPool;
 use crate::Result;
 
-///
+/// A wrapper around `rayon::ThreadPool`.
 pub struct RayonThreadPool {
     pool: rayon::ThreadPool,
 }
 
-///
 impl ThreadPool for RayonThreadPool {
     fn new(threads: u32) -> Result<Self>
     where
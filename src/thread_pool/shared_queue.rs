@@ -5,7 +5,7 @@ use std::{
 
 use crate::thread_pool::ThreadPool;
 
-///
+/// A fixed number of threads pulling jobs from a shared queue.
 pub struct SharedQueueThreadPool {
     handles: Vec<Option<JoinHandle<()>>>,
     tx: mpsc::Sender<ThreadPoolMessage>,
@@ -45,7 +45,6 @@ fn run_tasks(sentinel: ThreadSentinel) {
     }
 }
 
-///
 impl ThreadPool for SharedQueueThreadPool {
     fn new(threads: u32) -> crate::Result<Self>
     where
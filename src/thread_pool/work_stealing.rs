@@ -0,0 +1,123 @@
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+use std::{
+    iter,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use crate::thread_pool::ThreadPool;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed number of threads, each taking jobs from its own queue. A thread
+/// refills its queue with a batch of the jobs spawned, and steals from the
+/// other queues when there are none left. A job that panics does not take its
+/// thread down.
+pub struct WorkStealingThreadPool {
+    shared: Arc<Shared>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+struct Shared {
+    injector: Injector<Job>,
+    stealers: Vec<Stealer<Job>>,
+    shutdown: AtomicBool,
+    // held while checking for jobs before sleeping, and while waking up
+    // sleepers, so that no wakeup is missed
+    sleep: Mutex<()>,
+    wake: Condvar,
+}
+
+impl Shared {
+    fn find_job(&self, local: &Worker<Job>) -> Option<Job> {
+        local.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.injector
+                    .steal_batch_and_pop(local)
+                    .or_else(|| self.stealers.iter().map(|s| s.steal()).collect())
+            })
+            .find(|steal| !steal.is_retry())
+            .and_then(Steal::success)
+        })
+    }
+
+    fn wake_one(&self) {
+        let _sleep = self.sleep.lock().unwrap();
+        self.wake.notify_one();
+    }
+
+    fn has_jobs(&self) -> bool {
+        !self.injector.is_empty() || self.stealers.iter().any(|s| !s.is_empty())
+    }
+}
+
+fn run_jobs(shared: &Shared, local: Worker<Job>) {
+    loop {
+        if let Some(job) = shared.find_job(&local) {
+            // the rest of a batch may wait behind a long job, unless stolen
+            if !local.is_empty() {
+                shared.wake_one();
+            }
+            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            continue;
+        }
+        let sleep = shared.sleep.lock().unwrap();
+        if shared.has_jobs() {
+            continue;
+        }
+        // queued jobs are run before shutting down
+        if shared.shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        drop(shared.wake.wait(sleep).unwrap());
+    }
+}
+
+impl ThreadPool for WorkStealingThreadPool {
+    fn new(threads: u32) -> crate::Result<Self>
+    where
+        Self: Sized,
+    {
+        let workers: Vec<Worker<Job>> = (0..threads).map(|_| Worker::new_fifo()).collect();
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: workers.iter().map(Worker::stealer).collect(),
+            shutdown: AtomicBool::new(false),
+            sleep: Mutex::new(()),
+            wake: Condvar::new(),
+        });
+        let handles = workers
+            .into_iter()
+            .map(|local| {
+                let shared = shared.clone();
+                thread::spawn(move || run_jobs(&shared, local))
+            })
+            .collect();
+        Ok(Self { shared, handles })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.injector.push(Box::new(job));
+        self.shared.wake_one();
+    }
+}
+
+impl Drop for WorkStealingThreadPool {
+    fn drop(&mut self) {
+        {
+            let _sleep = self.shared.sleep.lock().unwrap();
+            self.shared.shutdown.store(true, Ordering::SeqCst);
+            self.shared.wake.notify_all();
+        }
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
@@ -0,0 +1,312 @@
+// TLS for `KvServer` and `KvClient`, with rustls.
+//
+// A rustls connection needs `&mut` access to read and to write, while a
+// pipelining connection reads requests on one thread and writes responses on
+// others. So `TlsStream` keeps the connection state behind a lock, which is
+// never held while blocking on a socket read.
+
+use crate::{socket::Socket, KvStoreError, Result};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+// TLS records read from the socket at once
+const READ_BUF_LEN: usize = 16 << 10;
+
+/// TLS settings of `KvServer`
+#[derive(Clone, Debug)]
+pub struct ServerTls {
+    config: Arc<ServerConfig>,
+}
+
+/// TLS settings of `KvClient`
+#[derive(Clone, Debug)]
+pub struct ClientTls {
+    config: Arc<ClientConfig>,
+    server_name: Option<ServerName<'static>>,
+}
+
+impl ServerTls {
+    /// Serve with the PEM certificate chain `cert` and its private key `key`.
+    /// With `client_ca`, clients must present a certificate signed by one of
+    /// its PEM certificates (mutual TLS).
+    pub fn from_pem(cert: &[u8], key: &[u8], client_ca: Option<&[u8]>) -> Result<Self> {
+        let builder = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?;
+        let builder = match client_ca {
+            Some(client_ca) => {
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(roots(client_ca)?, provider())
+                        .build()
+                        .map_err(tls_error)?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(certs(cert)?, private_key(key)?)
+            .map_err(tls_error)?;
+        Ok(ServerTls {
+            config: Arc::new(config),
+        })
+    }
+
+    // Wrap an accepted connection. The handshake happens on first use.
+    pub(crate) fn accept(&self, stream: Socket) -> Result<TlsStream> {
+        let conn = ServerConnection::new(self.config.clone()).map_err(tls_error)?;
+        Ok(TlsStream::new(conn.into(), stream))
+    }
+}
+
+impl ClientTls {
+    /// Trust the servers whose certificate is signed by one of the PEM
+    /// certificates in `ca`. With `identity`, a PEM certificate chain and
+    /// its private key, authenticate to servers requiring it.
+    pub fn from_pem(ca: &[u8], identity: Option<(&[u8], &[u8])>) -> Result<Self> {
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_root_certificates(roots(ca)?);
+        let config = match identity {
+            Some((cert, key)) => builder
+                .with_client_auth_cert(certs(cert)?, private_key(key)?)
+                .map_err(tls_error)?,
+            None => builder.with_no_client_auth(),
+        };
+        Ok(ClientTls {
+            config: Arc::new(config),
+            server_name: None,
+        })
+    }
+
+    /// Expect the server certificate to be for `name`, a DNS name or an IP
+    /// address. By default, it must be for the IP address connected to.
+    pub fn with_server_name(mut self, name: &str) -> Result<Self> {
+        let name = ServerName::try_from(name.to_owned()).map_err(|_| KvStoreError::TlsError)?;
+        self.server_name = Some(name);
+        Ok(self)
+    }
+
+    // Run the handshake over a connection to `ip`
+    pub(crate) fn connect(&self, ip: IpAddr, stream: Socket) -> Result<TlsStream> {
+        let name = self.server_name.clone().unwrap_or(ServerName::from(ip));
+        let conn = ClientConnection::new(self.config.clone(), name).map_err(tls_error)?;
+        let stream = TlsStream::new(conn.into(), stream);
+        stream.handshake()?;
+        Ok(stream)
+    }
+}
+
+/// A TLS connection, which can be read and written from different threads
+#[derive(Debug)]
+pub(crate) struct TlsStream {
+    conn: Mutex<Inner>,
+    sock: Socket,
+}
+
+#[derive(Debug)]
+struct Inner {
+    conn: rustls::Connection,
+    // records read from the socket but not processed yet, as rustls buffers
+    // a limited amount of decrypted data
+    records: Vec<u8>,
+}
+
+impl TlsStream {
+    fn new(conn: rustls::Connection, sock: Socket) -> Self {
+        TlsStream {
+            conn: Mutex::new(Inner {
+                conn,
+                records: Vec::new(),
+            }),
+            sock,
+        }
+    }
+
+    pub fn socket(&self) -> &Socket {
+        &self.sock
+    }
+
+    // Complete the handshake. Fails with `TlsError` if the peer is not
+    // trusted, or does not speak TLS.
+    pub fn handshake(&self) -> Result<()> {
+        let conn = &mut self.conn.lock().unwrap().conn;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut &self.sock)
+                .map_err(|err| match err.kind() {
+                    io::ErrorKind::InvalidData => KvStoreError::TlsError,
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => KvStoreError::Timeout,
+                    _ => KvStoreError::IoError,
+                })?;
+        }
+        Ok(())
+    }
+
+    // Send the pending TLS records
+    fn write_tls(&self, conn: &mut rustls::Connection) -> io::Result<()> {
+        while conn.wants_write() {
+            conn.write_tls(&mut &self.sock)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for &TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut eof = false;
+        loop {
+            let mut inner = self.conn.lock().unwrap();
+            let Inner { conn, records } = &mut *inner;
+            match conn.reader().read(buf) {
+                // The protocols on top delimit their messages, so a peer
+                // closing without close_notify is a plain EOF
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+
+            if !records.is_empty() || eof {
+                // an empty read marks the end of the stream
+                let n = conn.read_tls(&mut records.as_slice())?;
+                records.drain(..n);
+                if let Err(err) = conn.process_new_packets() {
+                    // send the alert
+                    let _ = self.write_tls(conn);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                }
+                self.write_tls(conn)?;
+                continue;
+            }
+            drop(inner);
+
+            // Wait for more records without holding the lock, so that
+            // responses can be written meanwhile
+            let mut buf = [0u8; READ_BUF_LEN];
+            let n = (&self.sock).read(&mut buf)?;
+            eof = n == 0;
+            self.conn
+                .lock()
+                .unwrap()
+                .records
+                .extend_from_slice(&buf[..n]);
+        }
+    }
+}
+
+impl Write for &TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let conn = &mut self.conn.lock().unwrap().conn;
+        let n = conn.writer().write(buf)?;
+        self.write_tls(conn)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let conn = &mut self.conn.lock().unwrap().conn;
+        conn.writer().flush()?;
+        self.write_tls(conn)
+    }
+}
+
+/// A connection in plaintext or over TLS. References to it can read and
+/// write from different threads.
+#[derive(Debug)]
+pub(crate) enum Stream {
+    Plain(Socket),
+    Tls(Arc<TlsStream>),
+}
+
+impl Stream {
+    pub fn socket(&self) -> &Socket {
+        match self {
+            Stream::Plain(stream) => stream,
+            Stream::Tls(stream) => stream.socket(),
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Stream::Plain(stream) => stream.try_clone().map(Stream::Plain),
+            Stream::Tls(stream) => Ok(Stream::Tls(stream.clone())),
+        }
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => (&*stream).read(buf),
+            Stream::Tls(stream) => (&**stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => (&*stream).write(buf),
+            Stream::Tls(stream) => (&**stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => (&*stream).flush(),
+            Stream::Tls(stream) => (&**stream).flush(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn certs(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_slice_iter(pem)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(tls_error)?;
+    if certs.is_empty() {
+        warn!("no certificate found");
+        return Err(KvStoreError::TlsError);
+    }
+    Ok(certs)
+}
+
+fn private_key(pem: &[u8]) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_slice(pem).map_err(tls_error)
+}
+
+fn roots(pem: &[u8]) -> Result<Arc<RootCertStore>> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(pem)? {
+        roots.add(cert).map_err(tls_error)?;
+    }
+    Ok(Arc::new(roots))
+}
+
+fn tls_error(err: impl std::fmt::Display) -> KvStoreError {
+    warn!("TLS error: {}", err);
+    KvStoreError::TlsError
+}
@@ -0,0 +1,176 @@
+#![cfg(feature = "blocking")]
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    Auth, Credentials, KvClient, KvServer, KvStore, KvStoreError, KvsEngine, LsmKvsStore,
+    MemoryKvsStore, OverflowPolicy, Protocol, Result, ServerHandle, ServerOptions,
+};
+use std::time::Duration;
+use tempfile::TempDir;
+
+const CONFIG: &str = r#"
+[[token]]
+principal = "operator"
+token = "operator-token"
+admin = true
+
+[[token]]
+principal = "app"
+token = "app-token"
+
+[[grant]]
+principal = "app"
+prefix = ""
+access = "read-write"
+"#;
+
+fn start<E: KvsEngine>(engine: E, options: ServerOptions) -> Result<ServerHandle> {
+    KvServer::start_with(
+        engine,
+        SharedQueueThreadPool::new(4)?,
+        "127.0.0.1:0".parse().unwrap(),
+        options,
+    )
+}
+
+#[test]
+fn admin_stats_and_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let handle = start(KvStore::open(temp_dir.path())?, ServerOptions::default())?;
+
+    for protocol in [Protocol::Binary, Protocol::Json] {
+        let mut client = KvClient::with_protocol(handle.local_addr(), protocol)?;
+        client.ping()?;
+        for i in 0..100 {
+            client.set("key1".to_owned(), format!("value{}", i))?;
+        }
+        client.set("key2".to_owned(), "value".to_owned())?;
+
+        let stats = client.stats()?;
+        assert_eq!(stats.connections, 1);
+        assert_eq!(stats.engine.keys, Some(2));
+        let live = stats.engine.live_bytes.unwrap();
+        assert!(live < stats.engine.size);
+        assert_eq!(stats.engine.segments.len(), 1);
+        assert_eq!(stats.engine.segments[0].size, stats.engine.size);
+
+        client.compact()?;
+        client.flush()?;
+        let stats = client.stats()?;
+        assert_eq!(stats.engine.size, live);
+        assert_eq!(stats.engine.live_bytes, Some(live));
+        assert_eq!(client.get("key1".to_owned())?, Some("value99".to_owned()));
+    }
+    Ok(())
+}
+
+#[test]
+fn admin_compact_lsm() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let handle = start(
+        LsmKvsStore::open(temp_dir.path())?,
+        ServerOptions::default(),
+    )?;
+
+    let mut client = KvClient::new(handle.local_addr())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.compact()?;
+    client.set("key1".to_owned(), "value2".to_owned())?;
+    client.compact()?;
+
+    let stats = client.stats()?;
+    assert_eq!(stats.engine.keys, None);
+    assert!(stats.engine.compactions >= 1);
+    // one table, and the write-ahead log
+    assert_eq!(stats.engine.segments.len(), 2);
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn admin_config() -> Result<()> {
+    let options = ServerOptions {
+        overflow: OverflowPolicy::Reject,
+        ..ServerOptions::default()
+    };
+    let handle = start(MemoryKvsStore::new(), options)?;
+
+    let mut client = KvClient::new(handle.local_addr())?;
+    let config = client.config()?;
+    assert!(config.contains(&("max-connections".to_owned(), "none".to_owned())));
+    assert!(config.contains(&("idle-timeout".to_owned(), "none".to_owned())));
+
+    client.set_config("max-connections".to_owned(), "1".to_owned())?;
+    client.set_config("idle-timeout".to_owned(), "2.5".to_owned())?;
+    let config = client.config()?;
+    assert!(config.contains(&("max-connections".to_owned(), "1".to_owned())));
+    assert!(config.contains(&("idle-timeout".to_owned(), "2.5".to_owned())));
+
+    // the limit applies to new connections
+    let busy = KvClient::new(handle.local_addr()).and_then(|mut client| client.ping());
+    assert!(busy.is_err());
+    client.set_config("max-connections".to_owned(), "none".to_owned())?;
+    KvClient::new(handle.local_addr())?.ping()?;
+
+    for (name, value) in [
+        ("max-connections", "-1"),
+        ("idle-timeout", "soon"),
+        ("idle-timeout", "-1"),
+        ("read-timeout", "0"),
+        ("threads", "4"),
+    ] {
+        assert_eq!(
+            client.set_config(name.to_owned(), value.to_owned()),
+            Err(KvStoreError::InvalidRequest)
+        );
+    }
+    Ok(())
+}
+
+// Timeouts too long for `Instant` are no timeouts
+#[test]
+fn admin_config_long_timeouts() -> Result<()> {
+    let handle = start(MemoryKvsStore::new(), ServerOptions::default())?;
+
+    let mut client = KvClient::new(handle.local_addr())?;
+    client.set_config("request-timeout".to_owned(), "1e18".to_owned())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.flush()?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+    handle.shutdown(Duration::MAX)
+}
+
+#[test]
+fn admin_requires_admin_principal() -> Result<()> {
+    let options = ServerOptions {
+        auth: Some(Auth::from_toml(CONFIG)?),
+        ..ServerOptions::default()
+    };
+    let handle = start(MemoryKvsStore::new(), options)?;
+
+    for protocol in [Protocol::Binary, Protocol::Json] {
+        let mut app = KvClient::with_protocol(handle.local_addr(), protocol)?;
+        app.authenticate(Credentials::Token("app-token".to_owned()))?;
+        app.set("key1".to_owned(), "value1".to_owned())?;
+        app.ping()?;
+        assert_eq!(app.stats().err(), Some(KvStoreError::PermissionDenied));
+        assert_eq!(app.compact(), Err(KvStoreError::PermissionDenied));
+        assert_eq!(app.config().err(), Some(KvStoreError::PermissionDenied));
+        assert_eq!(
+            app.set_config("max-connections".to_owned(), "1".to_owned()),
+            Err(KvStoreError::PermissionDenied)
+        );
+
+        let mut operator = KvClient::with_protocol(handle.local_addr(), protocol)?;
+        operator.authenticate(Credentials::Token("operator-token".to_owned()))?;
+        assert_eq!(operator.stats()?.engine.keys, Some(1));
+        operator.flush()?;
+        // administrators are granted no keys
+        assert_eq!(
+            operator.get("key1".to_owned()),
+            Err(KvStoreError::PermissionDenied)
+        );
+    }
+    Ok(())
+}
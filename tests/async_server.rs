@@ -0,0 +1,246 @@
+#![cfg(feature = "async")]
+
+use kvs::{AsyncKvClient, AsyncKvServer, KvStore, KvStoreError, KvsEngine, MemoryKvsStore, Result};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+async fn start_server(addr: SocketAddr) -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    tokio::spawn(AsyncKvServer::serve(store, addr));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    temp_dir
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_client_server() -> Result<()> {
+    let addr = "127.0.0.1:4100".parse().unwrap();
+    let _temp_dir = start_server(addr).await;
+
+    let client = AsyncKvClient::new(addr).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(client.get("key2".to_owned()).await?, None);
+    client.remove("key1".to_owned()).await?;
+    assert!(matches!(
+        client.remove("key1".to_owned()).await,
+        Err(KvStoreError::RemoveNonexistingKey)
+    ));
+    Ok(())
+}
+
+// Idle connections hold no thread, so they cannot starve active clients
+#[tokio::test(flavor = "multi_thread")]
+async fn idle_connections() -> Result<()> {
+    let addr = "127.0.0.1:4101".parse().unwrap();
+    let _temp_dir = start_server(addr).await;
+
+    let mut idle = Vec::new();
+    for _ in 0..num_cpus::get() * 4 {
+        idle.push(AsyncKvClient::new(addr).await?);
+    }
+
+    let client = AsyncKvClient::new(addr).await?;
+    let set = client.set("key1".to_owned(), "value1".to_owned());
+    tokio::time::timeout(Duration::from_secs(5), set)
+        .await
+        .expect("server stopped serving")?;
+    Ok(())
+}
+
+// The blocking client speaks the same protocol
+#[cfg(feature = "blocking")]
+#[tokio::test(flavor = "multi_thread")]
+async fn blocking_client_async_server() -> Result<()> {
+    let addr = "127.0.0.1:4102".parse().unwrap();
+    let _temp_dir = start_server(addr).await;
+
+    tokio::task::spawn_blocking(move || {
+        let mut client = kvs::KvClient::new(addr)?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+// A request that cannot be parsed gets an error response, and the server
+// keeps serving
+#[tokio::test(flavor = "multi_thread")]
+async fn invalid_request() -> Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let addr = "127.0.0.1:4103".parse().unwrap();
+    let _temp_dir = start_server(addr).await;
+
+    let mut stream = tokio::net::TcpStream::connect(addr).await?;
+    stream.write_all(b"{\"Frobnicate\":{}}").await?;
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).await?;
+    assert_eq!(resp, "{\"Err\":\"InvalidRequest\"}");
+
+    let client = AsyncKvClient::new(addr).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+    Ok(())
+}
+
+// Sleeps before getting the key "slow", and before setting the value "slow"
+#[derive(Clone)]
+struct SlowStore(MemoryKvsStore);
+
+impl KvsEngine for SlowStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        if value == "slow" {
+            std::thread::sleep(Duration::from_millis(200));
+        }
+        self.0.set(key, value)
+    }
+    fn get(&self, key: String) -> Result<Option<String>> {
+        if key == "slow" {
+            std::thread::sleep(Duration::from_millis(500));
+        }
+        if key == "panic" {
+            panic!("engine failure");
+        }
+        self.0.get(key)
+    }
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+    fn flush(&self) -> Result<()> {
+        self.0.flush()
+    }
+    fn scan<F>(&self, f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        self.0.scan(f)
+    }
+}
+
+// Concurrent requests on one client are pipelined, and a slow one does not
+// hold up the others
+#[tokio::test(flavor = "multi_thread")]
+async fn pipelined_requests() -> Result<()> {
+    let addr = "127.0.0.1:4104".parse().unwrap();
+    tokio::spawn(AsyncKvServer::serve(SlowStore(MemoryKvsStore::new()), addr));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let client = AsyncKvClient::new(addr).await?;
+    client.set("slow".to_owned(), "value1".to_owned()).await?;
+    client.set("fast".to_owned(), "value2".to_owned()).await?;
+
+    let start = Instant::now();
+    let (slow, fast) = tokio::join!(client.get("slow".to_owned()), async {
+        let value = client.get("fast".to_owned()).await;
+        (value, start.elapsed())
+    });
+    assert_eq!(slow?, Some("value1".to_owned()));
+    assert_eq!(fast.0?, Some("value2".to_owned()));
+    assert!(fast.1 < Duration::from_millis(400));
+    Ok(())
+}
+
+// Pipelined requests on the same key apply in the order they were sent,
+// even when the first one is slow
+#[tokio::test(flavor = "multi_thread")]
+async fn pipelined_same_key() -> Result<()> {
+    let addr = "127.0.0.1:4105".parse().unwrap();
+    tokio::spawn(AsyncKvServer::serve(SlowStore(MemoryKvsStore::new()), addr));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let client = AsyncKvClient::new(addr).await?;
+    let (first, second) = tokio::join!(
+        client.set("key1".to_owned(), "slow".to_owned()),
+        client.set("key1".to_owned(), "fast".to_owned()),
+    );
+    first?;
+    second?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("fast".to_owned())
+    );
+    Ok(())
+}
+
+// At shutdown, idle connections close, and a request in flight is answered
+#[tokio::test(flavor = "multi_thread")]
+async fn shutdown_answers_request_in_flight() -> Result<()> {
+    let addr = "127.0.0.1:4106".parse().unwrap();
+    let store = SlowStore(MemoryKvsStore::new());
+    store.set("slow".to_owned(), "value1".to_owned())?;
+    let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(AsyncKvServer::serve_with_shutdown(
+        store,
+        addr,
+        async {
+            let _ = rx.await;
+        },
+        Duration::from_secs(5),
+    ));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let _idle = AsyncKvClient::new(addr).await?;
+    let client = AsyncKvClient::new(addr).await?;
+    let slow = tokio::spawn(async move { client.get("slow".to_owned()).await });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let start = Instant::now();
+    tx.send(()).unwrap();
+    server.await.unwrap()?;
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert_eq!(slow.await.unwrap()?, Some("value1".to_owned()));
+    assert!(AsyncKvClient::new(addr).await.is_err());
+    Ok(())
+}
+
+// An engine call that panics is answered with an error, and the connection
+// goes on serving
+#[tokio::test(flavor = "multi_thread")]
+async fn engine_panic() -> Result<()> {
+    let addr = "127.0.0.1:4107".parse().unwrap();
+    tokio::spawn(AsyncKvServer::serve(SlowStore(MemoryKvsStore::new()), addr));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let client = AsyncKvClient::new(addr).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert!(matches!(
+        client.get("panic".to_owned()).await,
+        Err(KvStoreError::IoError)
+    ));
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
+
+// A large JSON request is parsed once, rather than after every read
+#[tokio::test(flavor = "multi_thread")]
+async fn large_json_request() -> Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let addr = "127.0.0.1:4108".parse().unwrap();
+    tokio::spawn(AsyncKvServer::serve(MemoryKvsStore::new(), addr));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let value = "x".repeat(8 << 20);
+    let start = Instant::now();
+    let mut stream = tokio::net::TcpStream::connect(addr).await?;
+    let req = format!("{{\"Set\":{{\"key\":\"key1\",\"value\":\"{}\"}}}}", value);
+    stream.write_all(req.as_bytes()).await?;
+    stream.shutdown().await?;
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).await?;
+    assert_eq!(resp, "\"Ok\"");
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    let client = AsyncKvClient::new(addr).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, Some(value));
+    Ok(())
+}
@@ -0,0 +1,299 @@
+#![cfg(feature = "blocking")]
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    Auth, Credentials, KvClient, KvServer, KvStoreError, MemoryKvsStore, Protocol, Result,
+    ServerHandle, ServerOptions, ServerProtocol,
+};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+const CONFIG: &str = r#"
+[[token]]
+principal = "admin"
+token = "admin-token"
+
+[[token]]
+principal = "reader"
+token = "reader-token"
+
+[[grant]]
+principal = "admin"
+prefix = ""
+access = "read-write"
+
+[[grant]]
+principal = "reader"
+prefix = "public/"
+access = "read"
+"#;
+
+fn start(protocol: ServerProtocol, auth: Auth) -> Result<ServerHandle> {
+    let options = ServerOptions {
+        protocol,
+        auth: Some(auth),
+        ..ServerOptions::default()
+    };
+    KvServer::start_with(
+        MemoryKvsStore::new(),
+        SharedQueueThreadPool::new(2)?,
+        "127.0.0.1:0".parse().unwrap(),
+        options,
+    )
+}
+
+fn token(token: &str) -> Credentials {
+    Credentials::Token(token.to_owned())
+}
+
+#[test]
+fn auth_tokens_and_grants() -> Result<()> {
+    let handle = start(ServerProtocol::Kvs, Auth::from_toml(CONFIG)?)?;
+
+    for protocol in [Protocol::Binary, Protocol::Json] {
+        let mut admin = KvClient::with_protocol(handle.local_addr(), protocol)?;
+        admin.authenticate(token("admin-token"))?;
+        admin.set("public/key1".to_owned(), "value1".to_owned())?;
+        admin.set("private/key1".to_owned(), "value1".to_owned())?;
+
+        let mut reader = KvClient::with_protocol(handle.local_addr(), protocol)?;
+        reader.authenticate(token("reader-token"))?;
+        assert_eq!(
+            reader.get("public/key1".to_owned())?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(
+            reader.get("private/key1".to_owned()),
+            Err(KvStoreError::PermissionDenied)
+        );
+        assert_eq!(
+            reader.set("public/key1".to_owned(), "value2".to_owned()),
+            Err(KvStoreError::PermissionDenied)
+        );
+        assert_eq!(
+            reader.remove("public/key1".to_owned()),
+            Err(KvStoreError::PermissionDenied)
+        );
+        admin.remove("public/key1".to_owned())?;
+        admin.remove("private/key1".to_owned())?;
+    }
+    Ok(())
+}
+
+#[test]
+fn auth_rejects_unauthenticated() -> Result<()> {
+    let handle = start(ServerProtocol::Kvs, Auth::from_toml(CONFIG)?)?;
+
+    for protocol in [Protocol::Binary, Protocol::Json] {
+        let mut client = KvClient::with_protocol(handle.local_addr(), protocol)?;
+        assert_eq!(
+            client.authenticate(token("wrong-token")),
+            Err(KvStoreError::PermissionDenied)
+        );
+
+        let mut client = KvClient::with_protocol(handle.local_addr(), protocol)?;
+        assert_eq!(
+            client.get("public/key1".to_owned()),
+            Err(KvStoreError::PermissionDenied)
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn auth_passwords() -> Result<()> {
+    let config = format!(
+        "[[user]]\nname = \"alice\"\npassword = \"{}\"\n\n\
+         [[grant]]\nprincipal = \"alice\"\nprefix = \"alice/\"\naccess = \"read-write\"\n",
+        Auth::hash_password("secret")?
+    );
+    let handle = start(ServerProtocol::Kvs, Auth::from_toml(&config)?)?;
+
+    let mut client = KvClient::new(handle.local_addr())?;
+    client.authenticate(Credentials::Password {
+        user: "alice".to_owned(),
+        password: "secret".to_owned(),
+    })?;
+    client.set("alice/key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(
+        client.set("bob/key1".to_owned(), "value1".to_owned()),
+        Err(KvStoreError::PermissionDenied)
+    );
+
+    // An unknown user takes as long to fail as a wrong password, so that
+    // timing does not tell which users exist
+    let fail = |user: &str| -> Result<Duration> {
+        let mut client = KvClient::new(handle.local_addr())?;
+        let start = Instant::now();
+        assert_eq!(
+            client.authenticate(Credentials::Password {
+                user: user.to_owned(),
+                password: "wrong".to_owned(),
+            }),
+            Err(KvStoreError::PermissionDenied)
+        );
+        Ok(start.elapsed())
+    };
+    let known = fail("alice")?;
+    let unknown = fail("bob")?;
+    assert!(unknown > known / 2, "{:?} vs {:?}", unknown, known);
+    Ok(())
+}
+
+#[test]
+fn auth_invalid_config() {
+    assert_eq!(
+        Auth::from_toml("[[token]]\nprincipal = \"admin\"\n").unwrap_err(),
+        KvStoreError::SerdeError
+    );
+    assert_eq!(
+        Auth::from_toml("[[user]]\nname = \"alice\"\npassword = \"secret\"\n").unwrap_err(),
+        KvStoreError::SerdeError
+    );
+}
+
+fn resp_call(stream: &mut TcpStream, args: &[&str], reply: &str) {
+    let mut buf = format!("*{}\r\n", args.len());
+    for arg in args {
+        buf += &format!("${}\r\n{}\r\n", arg.len(), arg);
+    }
+    stream.write_all(buf.as_bytes()).unwrap();
+    let mut buf = vec![0u8; reply.len()];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(String::from_utf8_lossy(&buf), reply);
+}
+
+#[test]
+fn auth_resp() -> Result<()> {
+    let handle = start(ServerProtocol::Resp, Auth::from_toml(CONFIG)?)?;
+    let mut stream = TcpStream::connect(handle.local_addr())?;
+
+    resp_call(
+        &mut stream,
+        &["GET", "public/key1"],
+        "-NOAUTH Authentication required.\r\n",
+    );
+    resp_call(
+        &mut stream,
+        &["AUTH", "wrong-token"],
+        "-WRONGPASS invalid username-password pair or user is disabled.\r\n",
+    );
+    resp_call(&mut stream, &["AUTH", "reader-token"], "+OK\r\n");
+    resp_call(&mut stream, &["GET", "public/key1"], "$-1\r\n");
+    resp_call(
+        &mut stream,
+        &["SET", "public/key1", "value1"],
+        "-NOPERM this user has no permissions to access one of the keys used as arguments\r\n",
+    );
+
+    let mut stream = TcpStream::connect(handle.local_addr())?;
+    resp_call(&mut stream, &["AUTH", "default", "admin-token"], "+OK\r\n");
+    resp_call(&mut stream, &["SET", "private/key1", "value1"], "+OK\r\n");
+    Ok(())
+}
+
+fn http_request(addr: SocketAddr, method: &str, path: &str, authorization: &str) -> u16 {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}Content-Length: 18\r\n\r\n{{\"value\":\"value1\"}}",
+        method, path, authorization
+    )
+    .unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    resp[9..12].parse().unwrap()
+}
+
+#[test]
+fn auth_http() -> Result<()> {
+    let handle = start(ServerProtocol::Http, Auth::from_toml(CONFIG)?)?;
+    let addr = handle.local_addr();
+    let reader = "Authorization: Bearer reader-token\r\n";
+    let admin = "Authorization: Bearer admin-token\r\n";
+
+    assert_eq!(http_request(addr, "GET", "/health", ""), 200);
+    assert_eq!(http_request(addr, "GET", "/v1/keys/public%2Fkey1", ""), 401);
+    assert_eq!(
+        http_request(
+            addr,
+            "GET",
+            "/v1/keys/public%2Fkey1",
+            "Authorization: Bearer wrong-token\r\n"
+        ),
+        401
+    );
+    assert_eq!(
+        http_request(addr, "PUT", "/v1/keys/public%2Fkey1", reader),
+        403
+    );
+    assert_eq!(
+        http_request(addr, "PUT", "/v1/keys/public%2Fkey1", admin),
+        204
+    );
+    assert_eq!(
+        http_request(addr, "GET", "/v1/keys/public%2Fkey1", reader),
+        200
+    );
+    Ok(())
+}
+
+// the status of a request on a kept-alive connection
+fn http_keep_alive(reader: &mut BufReader<TcpStream>, authorization: &str) -> u16 {
+    write!(
+        reader.get_mut(),
+        "GET /v1/keys/alice%2Fkey1 HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+        authorization
+    )
+    .unwrap();
+    let mut status = String::new();
+    reader.read_line(&mut status).unwrap();
+    let mut len = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).unwrap();
+        if header == "\r\n" {
+            break;
+        }
+        if let Some(value) = header.to_ascii_lowercase().strip_prefix("content-length:") {
+            len = value.trim().parse().unwrap();
+        }
+    }
+    reader.read_exact(&mut vec![0; len]).unwrap();
+    status[9..12].parse().unwrap()
+}
+
+// A Basic password is verified once per connection, not on every request
+#[test]
+fn auth_http_basic() -> Result<()> {
+    let config = format!(
+        "[[user]]\nname = \"alice\"\npassword = \"{}\"\n\n\
+         [[grant]]\nprincipal = \"alice\"\nprefix = \"alice/\"\naccess = \"read-write\"\n",
+        Auth::hash_password("secret")?
+    );
+    let handle = start(ServerProtocol::Http, Auth::from_toml(&config)?)?;
+    let alice = "Authorization: Basic YWxpY2U6c2VjcmV0\r\n";
+    let wrong = "Authorization: Basic YWxpY2U6d3Jvbmc=\r\n";
+
+    let mut reader = BufReader::new(TcpStream::connect(handle.local_addr())?);
+    let start = Instant::now();
+    assert_eq!(http_keep_alive(&mut reader, alice), 404);
+    let first = start.elapsed();
+    let start = Instant::now();
+    for _ in 0..4 {
+        assert_eq!(http_keep_alive(&mut reader, alice), 404);
+    }
+    assert!(
+        start.elapsed() < first,
+        "{:?} vs {:?}",
+        start.elapsed(),
+        first
+    );
+
+    // Other credentials are verified
+    assert_eq!(http_keep_alive(&mut reader, wrong), 401);
+    assert_eq!(http_keep_alive(&mut reader, ""), 401);
+    assert_eq!(http_keep_alive(&mut reader, alice), 404);
+    Ok(())
+}
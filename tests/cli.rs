@@ -1,10 +1,14 @@
+#![cfg(feature = "blocking")]
+
 use assert_cmd::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 
 // `kvs-client` with no args should exit with a non-zero code.
@@ -20,28 +24,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +56,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +95,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +127,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +138,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,7 +149,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -157,13 +161,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -171,6 +176,66 @@ fn cli_log_configuration() {
     assert!(content.contains("127.0.0.1:4001"));
 }
 
+#[test]
+fn cli_request_tracing() {
+    let temp_dir = TempDir::new().unwrap();
+    let stdout_path = temp_dir.path().join("stdout");
+    let stderr_path = temp_dir.path().join("stderr");
+    let addr = "127.0.0.1:4021";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--addr",
+            addr,
+            "--log-level",
+            "debug",
+            "--log-format",
+            "json",
+        ])
+        .current_dir(&temp_dir)
+        .stdout(File::create(&stdout_path).unwrap())
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "secret-value", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    // One JSON object per line, with the request span
+    let content = fs::read_to_string(&stderr_path).unwrap();
+    let requests: Vec<serde_json::Value> = content
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .filter(|log: &serde_json::Value| log["span"]["name"] == "request")
+        .collect();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0]["span"]["op"], "set");
+    assert_eq!(requests[0]["span"]["key"], "key1");
+    assert_eq!(requests[0]["span"]["result"], "ok");
+    assert!(requests[0]["span"]["latency_us"].is_u64());
+    assert_eq!(requests[0]["spans"][0]["name"], "connection");
+    assert!(requests[0]["spans"][0]["peer"].is_string());
+    assert_eq!(requests[1]["span"]["op"], "remove");
+    assert_eq!(requests[1]["span"]["result"], "RemoveNonexistingKey");
+
+    // Values are never logged, and nothing goes to stdout
+    assert!(!content.contains("secret-value"));
+    assert_eq!(fs::read_to_string(&stdout_path).unwrap(), "");
+}
+
 #[test]
 fn cli_wrong_engine() {
     // sled first, kvs second
@@ -178,15 +243,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,15 +263,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -217,19 +284,20 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +305,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +313,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +321,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +329,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +337,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +345,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,7 +353,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -298,26 +366,27 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -335,3 +404,1034 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// `kvs-admin dump` and `kvs-admin load` should move data between engines
+#[test]
+fn admin_cli_dump_load() {
+    let kvs_dir = TempDir::new().unwrap();
+    let sled_dir = TempDir::new().unwrap();
+    let dump_path = kvs_dir.path().join("dump.bin");
+
+    let store = kvs::KvStore::open(kvs_dir.path()).unwrap();
+    kvs::KvsEngine::set(&store, "key1".to_owned(), "value1".to_owned()).unwrap();
+    drop(store);
+
+    // a directory without an engine marker is not dumped
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["dump", "--engine", "kvs"])
+        .current_dir(&kvs_dir)
+        .assert()
+        .failure()
+        .stderr(contains("WrongEngine"));
+    kvs::EngineKind::Kvs.write_marker(kvs_dir.path()).unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["dump", "--engine", "kvs", "--format", "binary", "-o"])
+        .arg(&dump_path)
+        .current_dir(&kvs_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["load", "--engine", "sled", "--format", "binary", "-i"])
+        .arg(&dump_path)
+        .current_dir(&sled_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["dump"])
+        .current_dir(&sled_dir)
+        .assert()
+        .success()
+        .stdout("{\"key\":\"key1\",\"value\":\"value1\"}\n");
+
+    // the directory now belongs to sled
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["dump", "--engine", "kvs"])
+        .current_dir(&sled_dir)
+        .assert()
+        .failure();
+    // dumping an empty directory leaves it empty
+    let empty_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["dump", "--engine", "lsm"])
+        .current_dir(&empty_dir)
+        .assert()
+        .success()
+        .stdout("");
+    assert_eq!(fs::read_dir(empty_dir.path()).unwrap().count(), 0);
+
+    // a non-empty directory without a marker is refused, and left as it was
+    let other_dir = TempDir::new().unwrap();
+    fs::write(other_dir.path().join("notes.txt"), "notes").unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["dump"])
+        .current_dir(&other_dir)
+        .assert()
+        .failure()
+        .stderr(contains("WrongEngine"));
+    assert_eq!(fs::read_dir(other_dir.path()).unwrap().count(), 1);
+
+    // a directory marked for the memory engine holds nothing to dump or load
+    fs::write(other_dir.path().join("engine"), "memory").unwrap();
+    for command in ["dump", "load"] {
+        Command::cargo_bin("kvs-admin")
+            .unwrap()
+            .arg(command)
+            .current_dir(&other_dir)
+            .stdin(Stdio::null())
+            .assert()
+            .failure()
+            .stderr(contains("WrongEngine"));
+    }
+}
+
+// `kvs-server --migrate-from` should hand a kvs directory over to sled
+#[test]
+fn cli_migrate_from() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+    kvs::EngineKind::Kvs.write_marker(temp_dir.path()).unwrap();
+    let store = kvs::KvStore::open(temp_dir.path()).unwrap();
+    kvs::KvsEngine::set(&store, "key1".to_owned(), "value1".to_owned()).unwrap();
+    drop(store);
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "sled", "--migrate-from", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(2));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let content = fs::read_to_string(temp_dir.path().join("engine")).unwrap();
+    assert_eq!(content, "sled");
+}
+
+// `kvs-server --async` should serve the same protocol
+#[test]
+fn cli_access_server_async() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4007";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--async", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    // and shut down cleanly on SIGTERM
+    Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    assert!(child.wait().unwrap().success());
+}
+
+// `kvs-server` should shut down cleanly on SIGTERM
+#[test]
+fn cli_graceful_shutdown() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4008";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    assert!(child.wait().unwrap().success());
+
+    let store = kvs::KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        kvs::KvsEngine::get(&store, "key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// Connections beyond `--max-connections` are rejected with `--overflow reject`
+#[test]
+fn cli_max_connections_reject() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4009";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args([
+            "--addr",
+            addr,
+            "--max-connections",
+            "1",
+            "--overflow",
+            "reject",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let idle = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("ServerBusy"));
+
+    drop(idle);
+    thread::sleep(Duration::from_millis(100));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// Connections beyond `--max-connections` wait for a connection to close
+#[test]
+fn cli_max_connections_queue() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4010";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr, "--max-connections", "1"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let idle = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let (tx, rx) = mpsc::channel();
+    let client_dir = temp_dir.path().to_owned();
+    let client = thread::spawn(move || {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", "value1", "--addr", addr])
+            .current_dir(client_dir)
+            .assert()
+            .success();
+        tx.send(()).unwrap();
+    });
+    assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+
+    drop(idle);
+    rx.recv_timeout(Duration::from_secs(5))
+        .expect("queued connection was not served");
+    client.join().unwrap();
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// An idle connection is closed, and frees its slot
+#[test]
+fn cli_idle_timeout() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4011";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args([
+            "--addr",
+            addr,
+            "--max-connections",
+            "1",
+            "--idle-timeout",
+            "0.5",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let start = Instant::now();
+    let mut idle = TcpStream::connect(addr).unwrap();
+    idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!(idle.read(&mut [0u8; 16]).unwrap(), 0);
+    assert!(start.elapsed() < Duration::from_secs(3));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    // sockets refuse zero timeouts
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--idle-timeout", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("must be more than 0 seconds"));
+    fs::write(
+        temp_dir.path().join("kvs.toml"),
+        "[limits]\nread-timeout = 0\n",
+    )
+    .unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "kvs.toml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("must be more than 0 seconds"));
+}
+
+// A request not fully received in time is answered with an error
+#[test]
+fn cli_request_timeout() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4012";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args([
+            "--addr",
+            addr,
+            "--read-timeout",
+            "1",
+            "--request-timeout",
+            "0.5",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    // The request deadline passes before the read timeout would
+    for chunk in [&b"{\"Get\""[..], b":{", b"\"key\"", b":"] {
+        stream.write_all(chunk).unwrap();
+        thread::sleep(Duration::from_millis(100));
+    }
+    let start = Instant::now();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    assert_eq!(resp, "{\"Err\":\"Timeout\"}");
+    assert!(start.elapsed() < Duration::from_millis(500));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// `kvs-client --protocol json` speaks the debugging protocol
+#[test]
+fn cli_access_server_json() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4013";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "set",
+            "key1",
+            "value1",
+            "--addr",
+            addr,
+            "--protocol",
+            "json",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--protocol", "json", "get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// The RESP and HTTP listeners share the engine of `--addr`
+#[test]
+fn cli_resp_and_http_listeners() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4014";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr, "--resp-addr", "127.0.0.1:4015"])
+        .args(["--http-addr", "127.0.0.1:4016"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let mut resp = TcpStream::connect("127.0.0.1:4015").unwrap();
+    resp.write_all(b"GET key1\r\nSET key2 value2\r\n").unwrap();
+    let mut buf = [0u8; 17];
+    resp.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"$6\r\nvalue1\r\n+OK\r\n");
+
+    let mut http = TcpStream::connect("127.0.0.1:4016").unwrap();
+    http.write_all(b"GET /v1/keys/key2 HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut body = String::new();
+    http.read_to_string(&mut body).unwrap();
+    assert!(body.ends_with(r#"{"key":"key2","value":"value2"}"#));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// gRPC watches see the changes made over the native protocol
+#[cfg(feature = "grpc")]
+#[test]
+fn cli_grpc_listener() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4017";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr, "--grpc-addr", "127.0.0.1:4018"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let client = runtime
+        .block_on(kvs::grpc::GrpcKvClient::connect(
+            "127.0.0.1:4018".parse().unwrap(),
+        ))
+        .unwrap();
+    let mut watch = runtime.block_on(client.watch("key".to_owned())).unwrap();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let change = runtime.block_on(watch.next()).unwrap().unwrap();
+    assert_eq!(change.key, "key1");
+    assert_eq!(change.value.as_deref(), Some("value1"));
+    assert_eq!(
+        runtime.block_on(client.get("key1".to_owned())).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// Mutual TLS, with a CA and certificates generated for the test
+#[test]
+fn cli_mutual_tls() {
+    use rcgen::{BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+
+    let temp_dir = TempDir::new().unwrap();
+    let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca_key = KeyPair::generate().unwrap();
+    let ca = params.self_signed(&ca_key).unwrap();
+    fs::write(temp_dir.path().join("ca.pem"), ca.pem()).unwrap();
+    for (name, san, usage) in [
+        ("server", "127.0.0.1", ExtendedKeyUsagePurpose::ServerAuth),
+        ("client", "client", ExtendedKeyUsagePurpose::ClientAuth),
+    ] {
+        let mut params = CertificateParams::new(vec![san.to_owned()]).unwrap();
+        params.extended_key_usages = vec![usage];
+        let key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+        fs::write(temp_dir.path().join(format!("{}.pem", name)), cert.pem()).unwrap();
+        fs::write(
+            temp_dir.path().join(format!("{}.key", name)),
+            key.serialize_pem(),
+        )
+        .unwrap();
+    }
+
+    let addr = "127.0.0.1:4019";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr, "--tls-cert", "server.pem", "--tls-key"])
+        .args(["server.key", "--client-ca", "ca.pem"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr, "--ca", "ca.pem"])
+        .args(["--cert", "client.pem", "--key", "client.key"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr, "--ca", "ca.pem"])
+        .args(["--cert", "client.pem", "--key", "client.key"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    // without a client certificate, or without TLS
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr, "--ca", "ca.pem"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_token() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("auth.toml"),
+        "[[token]]\nprincipal = \"admin\"\ntoken = \"secret\"\n\n\
+         [[grant]]\nprincipal = \"admin\"\nprefix = \"\"\naccess = \"read-write\"\n",
+    )
+    .unwrap();
+
+    let addr = "127.0.0.1:4020";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr, "--auth", "auth.toml"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr, "--token", "secret"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr, "--token", "secret"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    // with a wrong token, or without one
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr, "--token", "wrong"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// `kvs-client admin` inspects and reconfigures a running server
+#[test]
+fn cli_admin() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4022";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for value in ["value1", "value2"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", value, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["admin", "ping", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("pong\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["admin", "stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys: 1\n"))
+        .stdout(contains("connections: 1\n"))
+        .stdout(contains("segment data.json: "));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["admin", "compact", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["admin", "stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("dead bytes: 0\n"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["admin", "config", "idle-timeout", "30", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["admin", "config", "idle-timeout", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("idle-timeout = 30\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["admin", "config", "threads", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("unknown setting: threads"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// `--config` reads the settings missing from the command line and the
+// environment from a TOML file
+#[test]
+fn cli_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("kvs.toml");
+    fs::write(
+        &config,
+        r#"
+data-dir = "data"
+engine = "lsm"
+
+[listen]
+addr = "127.0.0.1:4099"
+
+[limits]
+max-connections = 10
+idle-timeout = 30
+"#,
+    )
+    .unwrap();
+    let addr = "127.0.0.1:4023";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--config", "kvs.toml", "--idle-timeout", "60"])
+        .env("KVS_ADDR", addr)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["admin", "config", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(contains("max-connections = 10\n"))
+        .stdout(contains("idle-timeout = 60\n"));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    // relative to the config file
+    let marker = fs::read_to_string(temp_dir.path().join("data").join("engine")).unwrap();
+    assert_eq!(marker.trim(), "lsm");
+
+    fs::write(&config, "[limits]\nmax-connection = 10\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "kvs.toml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("unknown field `max-connection`"));
+}
+
+// SIGHUP reloads the limits of the config file
+#[test]
+fn cli_config_reload() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("kvs.toml");
+    fs::write(&config, "[limits]\nmax-connections = 10\n").unwrap();
+    let addr = "127.0.0.1:4024";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--config", "kvs.toml", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let max_connections = || {
+        let output = Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["admin", "config", "max-connections", "--addr", addr])
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    };
+    assert_eq!(max_connections(), "max-connections = 10\n");
+
+    fs::write(&config, "[limits]\nmax-connections = 20\n").unwrap();
+    Command::new("kill")
+        .args(["-HUP", &child.id().to_string()])
+        .assert()
+        .success();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while max_connections() != "max-connections = 20\n" {
+        assert!(Instant::now() < deadline, "configuration not reloaded");
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    // the server keeps running with an invalid file
+    fs::write(&config, "[limits]\nmax-connections = \"many\"\n").unwrap();
+    Command::new("kill")
+        .args(["-HUP", &child.id().to_string()])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(max_connections(), "max-connections = 20\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// `--thread-pool` and `--threads` choose the pool serving the connections
+#[test]
+fn cli_thread_pool() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4025";
+    for pool in ["naive", "shared-queue", "rayon", "work-stealing"] {
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(["--addr", addr, "--thread-pool", pool, "--threads", "2"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        // more connections at once than threads, one after the other
+        let idle = TcpStream::connect(addr).unwrap();
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", pool, "--addr", addr])
+            .assert()
+            .success();
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key1", "--addr", addr])
+            .assert()
+            .success()
+            .stdout(format!("{}\n", pool));
+        drop(idle);
+
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    }
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--thread-pool", "fifo"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("unknown thread pool: fifo"));
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--threads", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("0 is not in 1.."));
+    fs::write(temp_dir.path().join("kvs.toml"), "threads = 0\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "kvs.toml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("expected a nonzero u32"));
+}
+
+// `--unix` alone serves no TCP, next to `--addr` both
+#[test]
+fn cli_unix_socket() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4026";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--unix", "kvs.sock", "--unix-mode", "660"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let path = temp_dir.path().join("kvs.sock");
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o660);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--unix"])
+        .arg(&path)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--protocol", "json", "--unix"])
+        .arg(&path)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    // the socket file left by the killed server is replaced
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--unix", "kvs.sock", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value2", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--unix"])
+        .arg(&path)
+        .assert()
+        .success()
+        .stdout("value2\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--unix", "kvs.sock", "--unix-mode", "999"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid mode: 999"));
+}
+
+// The `[[listener]]` tables of the config file, each with its own
+// protocol and authentication
+#[test]
+fn cli_config_listeners() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("auth.toml"),
+        "[[token]]\nprincipal = \"app\"\ntoken = \"secret\"\n\n\
+         [[grant]]\nprincipal = \"app\"\nprefix = \"\"\naccess = \"read-write\"\n",
+    )
+    .unwrap();
+    let config = temp_dir.path().join("kvs.toml");
+    fs::write(
+        &config,
+        r#"
+[listen]
+addr = "127.0.0.1:4027"
+auth = "auth.toml"
+
+[[listener]]
+addr = "unix:kvs.sock"
+unix-mode = "600"
+
+[[listener]]
+addr = "127.0.0.1:4028"
+protocol = "metrics"
+"#,
+    )
+    .unwrap();
+    let addr = "127.0.0.1:4027";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--config", "kvs.toml"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // no authentication on the Unix socket
+    let path = temp_dir.path().join("kvs.sock");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--unix"])
+        .arg(&path)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr, "--token", "secret"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    let mut metrics = TcpStream::connect("127.0.0.1:4028").unwrap();
+    metrics
+        .write_all(b"GET /metrics HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut body = String::new();
+    metrics.read_to_string(&mut body).unwrap();
+    assert!(body.starts_with("HTTP/1.1 200"));
+    assert!(body.contains("kvs_connections"));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    fs::write(&config, "[[listener]]\naddr = \"nowhere\"\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "kvs.toml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid listen address: nowhere"));
+    fs::write(
+        &config,
+        "[[listener]]\naddr = \"127.0.0.1:4028\"\ntls-cert = \"cert.pem\"\n",
+    )
+    .unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "kvs.toml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(
+            "listener 127.0.0.1:4028: tls-cert and tls-key go together",
+        ));
+}
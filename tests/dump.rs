@@ -0,0 +1,75 @@
+use kvs::dump::{self, DumpFormat};
+use kvs::{KvStore, KvStoreError, KvsEngine, Result, SledKvsStore};
+use std::collections::HashMap;
+use tempfile::TempDir;
+
+fn collect<E: KvsEngine>(engine: &E) -> Result<HashMap<String, String>> {
+    let mut pairs = HashMap::new();
+    engine.scan(|key, value| {
+        pairs.insert(key, value);
+        Ok(())
+    })?;
+    Ok(pairs)
+}
+
+fn migrate_kvs_to_sled(format: DumpFormat) -> Result<()> {
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = KvStore::open(kvs_dir.path())?;
+    let sled = SledKvsStore::open(sled_dir.path())?;
+
+    for i in 0..100 {
+        kvs.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    kvs.remove("key0".to_owned())?;
+    kvs.set("key1".to_owned(), "new value\nwith \"quotes\"".to_owned())?;
+
+    let mut buf = Vec::new();
+    assert_eq!(dump::dump(&kvs, &mut buf, format)?, 99);
+    assert_eq!(dump::load(&sled, buf.as_slice(), format)?, 99);
+
+    assert_eq!(collect(&kvs)?, collect(&sled)?);
+    assert_eq!(sled.get("key0".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn dump_load_json() -> Result<()> {
+    migrate_kvs_to_sled(DumpFormat::Json)
+}
+
+#[test]
+fn dump_load_binary() -> Result<()> {
+    migrate_kvs_to_sled(DumpFormat::Binary)
+}
+
+#[test]
+fn load_invalid_dump() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert!(matches!(
+        dump::load(&store, &b"NOTADUMP"[..], DumpFormat::Binary),
+        Err(KvStoreError::InvalidDump)
+    ));
+    // truncated record
+    assert!(matches!(
+        dump::load(
+            &store,
+            &b"KVSDUMP1\x00\x00\x00\x04ke"[..],
+            DumpFormat::Binary
+        ),
+        Err(KvStoreError::InvalidDump)
+    ));
+    // a length beyond the end of the dump
+    assert!(matches!(
+        dump::load(
+            &store,
+            &b"KVSDUMP1\xff\xff\xff\xffke"[..],
+            DumpFormat::Binary
+        ),
+        Err(KvStoreError::InvalidDump)
+    ));
+    assert!(dump::load(&store, &b"{\"key\": 1}"[..], DumpFormat::Json).is_err());
+    Ok(())
+}
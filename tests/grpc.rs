@@ -0,0 +1,249 @@
+#![cfg(feature = "grpc")]
+
+use kvs::grpc::proto::{kvs_client::KvsClient, GetRequest};
+use kvs::grpc::{Change, GrpcKvClient, GrpcKvServer, Watched};
+use kvs::{KvStoreError, KvsEngine, MemoryKvsStore, Result};
+use prost::Message as _;
+use protobuf::Message as _;
+use std::fs;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tonic::Code;
+
+async fn start() -> Result<SocketAddr> {
+    let engine = Watched::new(MemoryKvsStore::new());
+    let server = GrpcKvServer::bind(engine, "127.0.0.1:0".parse().unwrap()).await?;
+    let addr = server.local_addr()?;
+    tokio::spawn(server.serve_with_shutdown(std::future::pending()));
+    Ok(addr)
+}
+
+fn set(key: &str, value: &str) -> Change {
+    Change {
+        key: key.to_owned(),
+        value: Some(value.to_owned()),
+    }
+}
+
+fn remove(key: &str) -> Change {
+    Change {
+        key: key.to_owned(),
+        value: None,
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn grpc_get_set_remove() -> Result<()> {
+    let client = GrpcKvClient::connect(start().await?).await?;
+
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    client.remove("key1".to_owned()).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+    assert_eq!(
+        client.remove("key1".to_owned()).await,
+        Err(KvStoreError::RemoveNonexistingKey)
+    );
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn grpc_scan_and_batch() -> Result<()> {
+    let client = GrpcKvClient::connect(start().await?).await?;
+
+    client
+        .batch(vec![
+            set("b1", "1"),
+            set("a2", "2"),
+            set("a1", "3"),
+            set("c1", "4"),
+        ])
+        .await?;
+    assert_eq!(
+        client.scan("a".to_owned()).await?,
+        vec![
+            ("a1".to_owned(), "3".to_owned()),
+            ("a2".to_owned(), "2".to_owned())
+        ]
+    );
+    assert_eq!(client.scan(String::new()).await?.len(), 4);
+
+    // A batch stops at its first failure, keeping the changes before it
+    assert_eq!(
+        client
+            .batch(vec![remove("a1"), remove("a1"), remove("a2")])
+            .await,
+        Err(KvStoreError::RemoveNonexistingKey)
+    );
+    assert_eq!(client.get("a1".to_owned()).await?, None);
+    assert_eq!(client.get("a2".to_owned()).await?, Some("2".to_owned()));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn grpc_watch() -> Result<()> {
+    let client = GrpcKvClient::connect(start().await?).await?;
+
+    let mut watch = client.watch("a".to_owned()).await?;
+    client.set("b1".to_owned(), "1".to_owned()).await?;
+    client.set("a1".to_owned(), "2".to_owned()).await?;
+    client.batch(vec![set("a2", "3"), remove("a1")]).await?;
+
+    assert_eq!(watch.next().await?, Some(set("a1", "2")));
+    assert_eq!(watch.next().await?, Some(set("a2", "3")));
+    assert_eq!(watch.next().await?, Some(remove("a1")));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn grpc_bind_error() -> Result<()> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let engine = Watched::new(MemoryKvsStore::new());
+    assert!(matches!(
+        GrpcKvServer::bind(engine, listener.local_addr()?).await,
+        Err(KvStoreError::BindError)
+    ));
+    Ok(())
+}
+
+// src/grpc/kvs.v1.rs is generated from proto/kvs.proto, with pure Rust
+// tooling. Set UPDATE_PROTO to regenerate it.
+#[test]
+fn generated_code_is_up_to_date() {
+    let fds = protobuf_parse::Parser::new()
+        .pure()
+        .include("proto")
+        .input("proto/kvs.proto")
+        .file_descriptor_set()
+        .unwrap();
+    let fds = prost_types::FileDescriptorSet::decode(&*fds.write_to_bytes().unwrap()).unwrap();
+    let out_dir = TempDir::new().unwrap();
+    tonic_prost_build::configure()
+        .out_dir(out_dir.path())
+        .compile_fds(fds)
+        .unwrap();
+
+    let generated = fs::read_to_string(out_dir.path().join("kvs.v1.rs")).unwrap();
+    if std::env::var_os("UPDATE_PROTO").is_some() {
+        fs::write("src/grpc/kvs.v1.rs", generated).unwrap();
+    } else {
+        let vendored = fs::read_to_string("src/grpc/kvs.v1.rs").unwrap();
+        assert!(
+            generated == vendored,
+            "src/grpc/kvs.v1.rs is out of date, run the test with UPDATE_PROTO=1"
+        );
+    }
+}
+
+// An engine that panics on every read
+#[derive(Clone)]
+struct PanicStore(MemoryKvsStore);
+
+impl KvsEngine for PanicStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+    fn get(&self, _key: String) -> Result<Option<String>> {
+        panic!("engine failure");
+    }
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+    fn flush(&self) -> Result<()> {
+        self.0.flush()
+    }
+    fn scan<F>(&self, f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        self.0.scan(f)
+    }
+}
+
+// An engine call that panics is answered with an internal error, and the
+// server goes on serving
+#[tokio::test(flavor = "multi_thread")]
+async fn grpc_engine_panic() -> Result<()> {
+    let engine = Watched::new(PanicStore(MemoryKvsStore::new()));
+    let server = GrpcKvServer::bind(engine, "127.0.0.1:0".parse().unwrap()).await?;
+    let addr = server.local_addr()?;
+    tokio::spawn(server.serve_with_shutdown(std::future::pending()));
+
+    let mut raw = KvsClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+    let status = raw
+        .get(GetRequest {
+            key: "key1".to_owned(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::Internal);
+
+    let client = GrpcKvClient::connect(addr).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await,
+        Err(KvStoreError::IoError)
+    );
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    Ok(())
+}
+
+// An engine that pauses after writing the value "slow"
+#[derive(Clone)]
+struct SlowStore(MemoryKvsStore);
+
+impl KvsEngine for SlowStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let slow = value == "slow";
+        self.0.set(key, value)?;
+        if slow {
+            thread::sleep(Duration::from_millis(200));
+        }
+        Ok(())
+    }
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.0.get(key)
+    }
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+    fn flush(&self) -> Result<()> {
+        self.0.flush()
+    }
+    fn scan<F>(&self, f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        self.0.scan(f)
+    }
+}
+
+// Watchers see concurrent writes to a key in the order they were applied
+#[test]
+fn watch_concurrent_writes() -> Result<()> {
+    let engine = Watched::new(SlowStore(MemoryKvsStore::new()));
+    let mut changes = engine.subscribe();
+
+    let slow = {
+        let engine = engine.clone();
+        thread::spawn(move || engine.set("key1".to_owned(), "slow".to_owned()))
+    };
+    thread::sleep(Duration::from_millis(50));
+    engine.set("key1".to_owned(), "fast".to_owned())?;
+    slow.join().unwrap()?;
+
+    let mut last = None;
+    while let Ok(change) = changes.try_recv() {
+        last = Some(change);
+    }
+    let value = engine.get("key1".to_owned())?;
+    assert_eq!(last.map(|change| change.value), Some(value));
+    Ok(())
+}
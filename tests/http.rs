@@ -0,0 +1,187 @@
+#![cfg(feature = "blocking")]
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvServer, MemoryKvsStore, Result, ServerHandle, ServerOptions, ServerProtocol};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+fn start() -> Result<ServerHandle> {
+    let options = ServerOptions {
+        protocol: ServerProtocol::Http,
+        ..ServerOptions::default()
+    };
+    KvServer::start_with(
+        MemoryKvsStore::new(),
+        SharedQueueThreadPool::new(2)?,
+        "127.0.0.1:0".parse().unwrap(),
+        options,
+    )
+}
+
+// Send a request on its own connection, returning the status and the body
+fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    let (head, body) = resp.split_once("\r\n\r\n").unwrap();
+    let status = head[9..12].parse().unwrap();
+    (status, body.to_owned())
+}
+
+#[test]
+fn http_keys() -> Result<()> {
+    let handle = start()?;
+    let addr = handle.local_addr();
+
+    assert_eq!(
+        request(addr, "GET", "/v1/keys/key1", ""),
+        (404, r#"{"error":"KeyNotFound"}"#.to_owned())
+    );
+    assert_eq!(
+        request(addr, "PUT", "/v1/keys/key1", r#"{"value":"value1"}"#),
+        (204, String::new())
+    );
+    assert_eq!(
+        request(addr, "GET", "/v1/keys/key1", ""),
+        (200, r#"{"key":"key1","value":"value1"}"#.to_owned())
+    );
+    assert_eq!(
+        request(addr, "PUT", "/v1/keys/a%20b%2Fc", r#"{"value":"value2"}"#),
+        (204, String::new())
+    );
+    assert_eq!(
+        request(addr, "GET", "/v1/keys/a%20b%2Fc", ""),
+        (200, r#"{"key":"a b/c","value":"value2"}"#.to_owned())
+    );
+    assert_eq!(
+        request(addr, "DELETE", "/v1/keys/key1", ""),
+        (204, String::new())
+    );
+    assert_eq!(
+        request(addr, "DELETE", "/v1/keys/key1", ""),
+        (404, r#"{"error":"RemoveNonexistingKey"}"#.to_owned())
+    );
+    handle.shutdown(std::time::Duration::from_secs(1))
+}
+
+#[test]
+fn http_list() -> Result<()> {
+    let handle = start()?;
+    let addr = handle.local_addr();
+
+    for key in ["b1", "a2", "a1", "a b"] {
+        let (status, _) = request(
+            addr,
+            "PUT",
+            &format!("/v1/keys/{}", key.replace(' ', "%20")),
+            r#"{"value":"v"}"#,
+        );
+        assert_eq!(status, 204);
+    }
+    assert_eq!(
+        request(addr, "GET", "/v1/keys", ""),
+        (200, r#"{"keys":["a b","a1","a2","b1"]}"#.to_owned())
+    );
+    assert_eq!(
+        request(addr, "GET", "/v1/keys?prefix=a", ""),
+        (200, r#"{"keys":["a b","a1","a2"]}"#.to_owned())
+    );
+    assert_eq!(
+        request(addr, "GET", "/v1/keys?limit=1&prefix=a+", ""),
+        (200, r#"{"keys":["a b"]}"#.to_owned())
+    );
+    assert_eq!(
+        request(addr, "GET", "/v1/keys?prefix=c", ""),
+        (200, r#"{"keys":[]}"#.to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn http_errors() -> Result<()> {
+    let handle = start()?;
+    let addr = handle.local_addr();
+
+    assert_eq!(
+        request(addr, "PUT", "/v1/keys/key1", "value1"),
+        (400, r#"{"error":"InvalidRequest"}"#.to_owned())
+    );
+    assert_eq!(
+        request(addr, "GET", "/v1/keys/%FF", ""),
+        (400, r#"{"error":"InvalidRequest"}"#.to_owned())
+    );
+    assert_eq!(
+        request(addr, "POST", "/v1/keys/key1", ""),
+        (405, r#"{"error":"MethodNotAllowed"}"#.to_owned())
+    );
+    assert_eq!(
+        request(addr, "GET", "/v2/keys/key1", ""),
+        (404, r#"{"error":"NotFound"}"#.to_owned())
+    );
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"NOT HTTP\r\n\r\n")?;
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp)?;
+    assert!(resp.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    Ok(())
+}
+
+#[test]
+fn http_health_and_metrics() -> Result<()> {
+    let handle = start()?;
+    let addr = handle.local_addr();
+
+    assert_eq!(
+        request(addr, "GET", "/health", ""),
+        (200, r#"{"status":"ok"}"#.to_owned())
+    );
+    request(addr, "GET", "/v1/keys/key1", "");
+    let (status, metrics) = request(addr, "GET", "/metrics", "");
+    assert_eq!(status, 200);
+    assert!(metrics.contains("kvs_connections 1\n"));
+    assert!(metrics.contains("kvs_http_responses_total{status=\"200\"} 1\n"));
+    assert!(metrics.contains("kvs_http_responses_total{status=\"404\"} 1\n"));
+    Ok(())
+}
+
+// Requests on a kept-alive connection are answered in order
+#[test]
+fn http_keep_alive() -> Result<()> {
+    let handle = start()?;
+    let stream = TcpStream::connect(handle.local_addr())?;
+    let mut reader = BufReader::new(&stream);
+
+    (&stream).write_all(
+        b"PUT /v1/keys/key1 HTTP/1.1\r\nContent-Length: 18\r\n\r\n{\"value\":\"value1\"}\
+          GET /v1/keys/key1 HTTP/1.1\r\n\r\n",
+    )?;
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    assert_eq!(line, "HTTP/1.1 204 No Content\r\n");
+    line.clear();
+    reader.read_line(&mut line)?;
+    assert_eq!(line, "\r\n");
+
+    let mut head = String::new();
+    while !head.ends_with("\r\n\r\n") {
+        reader.read_line(&mut head)?;
+    }
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(!head.contains("Connection: close"));
+    let body = r#"{"key":"key1","value":"value1"}"#;
+    assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+    let mut buf = vec![0u8; body.len()];
+    reader.read_exact(&mut buf)?;
+    assert_eq!(buf, body.as_bytes());
+    Ok(())
+}
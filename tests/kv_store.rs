@@ -1,4 +1,4 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result};
 use std::sync::{Arc, Barrier};
 use std::thread;
 use tempfile::TempDir;
@@ -127,6 +127,25 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// The compaction threshold decides when the log is compacted on its own
+#[test]
+fn compaction_threshold() -> Result<()> {
+    for (threshold, compacts) in [(0.0, false), (0.9, true)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            compaction_threshold: threshold,
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        for i in 0..100 {
+            store.set("key1".to_owned(), format!("value{}", i))?;
+        }
+        assert_eq!(store.stats().compactions > 0, compacts);
+        assert_eq!(store.get("key1".to_owned())?, Some("value99".to_owned()));
+    }
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -209,3 +228,121 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    let stats = store.cache_stats();
+    assert_eq!((stats.hits, stats.misses, stats.entries), (1, 2, 1));
+
+    // writes and removes invalidate
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.cache_stats().entries, 0);
+
+    Ok(())
+}
+
+#[test]
+fn value_cache_capacity() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_cache(temp_dir.path(), 100)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        store.get(format!("key{}", i))?;
+    }
+    let stats = store.cache_stats();
+    assert!(stats.bytes <= 100);
+    assert!(stats.entries > 0);
+
+    // the most recently used key is still cached
+    store.get("key99".to_owned())?;
+    assert_eq!(store.cache_stats().hits, 1);
+
+    drop(store);
+    let store = KvStore::open_with_cache(temp_dir.path(), 0)?;
+    store.get("key99".to_owned())?;
+    store.get("key99".to_owned())?;
+    assert_eq!(store.cache_stats(), Default::default());
+
+    Ok(())
+}
+
+// Reads must see entries in the sealed, memory-mapped prefix of the log as
+// well as entries appended after it, across remaps.
+#[test]
+fn read_sealed_and_appended_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_cache(temp_dir.path(), 0)?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    drop(store);
+    let store = KvStore::open_with_cache(temp_dir.path(), 0)?;
+    let large = "x".repeat(4096);
+    for i in (0..1000).step_by(2) {
+        store.set(format!("key{}", i), format!("{}{}", large, i))?;
+    }
+    for i in 0..1000 {
+        let key = format!("key{}", i);
+        assert_eq!(store.get(key.clone())?, Some(store_value(&key, &large)));
+    }
+
+    let mut count = 0;
+    store.scan(|key, value| {
+        assert_eq!(store_value(&key, &large), value);
+        count += 1;
+        Ok(())
+    })?;
+    assert_eq!(count, 1000);
+
+    Ok(())
+}
+
+// value of `key` in `read_sealed_and_appended_entries`
+fn store_value(key: &str, large: &str) -> String {
+    let i: usize = key["key".len()..].parse().unwrap();
+    match i % 2 {
+        0 => format!("{}{}", large, i),
+        _ => format!("value{}", i),
+    }
+}
+
+// A scan visits the pairs as they were when it started, and the callback
+// may write to the store, even through a compaction.
+#[test]
+fn write_during_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_threshold: 0.5,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let mut count = 0;
+    store.scan(|key, value| {
+        let i: usize = key["key".len()..].parse().unwrap();
+        assert_eq!(value, format!("value{}", i));
+        store.set(key.clone(), "x".repeat(100))?;
+        store.set(key, format!("scanned{}", i))?;
+        count += 1;
+        Ok(())
+    })?;
+    assert_eq!(count, 100);
+    assert!(store.stats().compactions > 0);
+    assert_eq!(store.get("key7".to_owned())?, Some("scanned7".to_owned()));
+
+    Ok(())
+}
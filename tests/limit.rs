@@ -0,0 +1,273 @@
+#![cfg(feature = "blocking")]
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    Auth, Credentials, KvClient, KvServer, KvStoreError, KvsEngine, MemoryKvsStore, Quota,
+    RateLimit, Result, ServerHandle, ServerOptions, ServerProtocol,
+};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn start(engine: MemoryKvsStore, options: ServerOptions) -> Result<ServerHandle> {
+    KvServer::start_with(
+        engine,
+        SharedQueueThreadPool::new(4)?,
+        "127.0.0.1:0".parse().unwrap(),
+        options,
+    )
+}
+
+fn ops(ops_per_sec: u32) -> Option<RateLimit> {
+    Some(RateLimit {
+        ops_per_sec: Some(ops_per_sec),
+        bytes_per_sec: None,
+    })
+}
+
+#[test]
+fn limit_connection_ops() -> Result<()> {
+    let options = ServerOptions {
+        connection_rate: ops(5),
+        ..ServerOptions::default()
+    };
+    let handle = start(MemoryKvsStore::new(), options)?;
+
+    let mut client = KvClient::new(handle.local_addr())?;
+    for _ in 0..5 {
+        client.set("key1".to_owned(), "value1".to_owned())?;
+    }
+    assert_eq!(client.get("key1".to_owned()), Err(KvStoreError::Throttled));
+
+    // Other connections have their own bucket
+    let mut other = KvClient::new(handle.local_addr())?;
+    assert_eq!(other.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // and the bucket refills
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn limit_connection_bytes() -> Result<()> {
+    let options = ServerOptions {
+        connection_rate: Some(RateLimit {
+            ops_per_sec: None,
+            bytes_per_sec: Some(1000),
+        }),
+        ..ServerOptions::default()
+    };
+    let handle = start(MemoryKvsStore::new(), options)?;
+
+    let mut client = KvClient::new(handle.local_addr())?;
+    client.set("key1".to_owned(), "x".repeat(2000))?;
+    // in debt until the bucket refills
+    assert_eq!(client.get("key1".to_owned()), Err(KvStoreError::Throttled));
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(client.get("key1".to_owned())?, Some("x".repeat(2000)));
+    assert_eq!(client.get("key1".to_owned()), Err(KvStoreError::Throttled));
+    Ok(())
+}
+
+#[test]
+fn limit_principal_ops() -> Result<()> {
+    let auth = Auth::from_toml(
+        "[[token]]\nprincipal = \"batch\"\ntoken = \"batch-token\"\n\n\
+         [[token]]\nprincipal = \"web\"\ntoken = \"web-token\"\n\n\
+         [[grant]]\nprincipal = \"batch\"\nprefix = \"\"\naccess = \"read-write\"\n\n\
+         [[grant]]\nprincipal = \"web\"\nprefix = \"\"\naccess = \"read-write\"\n",
+    )?;
+    let options = ServerOptions {
+        auth: Some(auth),
+        principal_rate: ops(4),
+        ..ServerOptions::default()
+    };
+    let handle = start(MemoryKvsStore::new(), options)?;
+
+    // The connections of a principal share its bucket
+    let mut batch = Vec::new();
+    for _ in 0..2 {
+        let mut client = KvClient::new(handle.local_addr())?;
+        client.authenticate(Credentials::Token("batch-token".to_owned()))?;
+        batch.push(client);
+    }
+    for client in batch.iter_mut() {
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+    }
+    assert_eq!(
+        batch[0].get("key1".to_owned()),
+        Err(KvStoreError::Throttled)
+    );
+    assert_eq!(
+        batch[1].get("key1".to_owned()),
+        Err(KvStoreError::Throttled)
+    );
+
+    // Other principals are not throttled
+    let mut web = KvClient::new(handle.local_addr())?;
+    web.authenticate(Credentials::Token("web-token".to_owned()))?;
+    assert_eq!(web.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn limit_quotas() -> Result<()> {
+    let engine = MemoryKvsStore::new();
+    engine.set("tenant/a".to_owned(), "12345678".to_owned())?;
+    assert_eq!(
+        "tenant/=40".parse(),
+        Ok(Quota {
+            prefix: "tenant/".to_owned(),
+            max_bytes: 40,
+        })
+    );
+    assert!("tenant/".parse::<Quota>().is_err());
+    let options = ServerOptions {
+        // 16 bytes are used by "tenant/a"
+        quotas: vec!["tenant/=40".parse().unwrap()],
+        ..ServerOptions::default()
+    };
+    let handle = start(engine, options)?;
+
+    let mut client = KvClient::new(handle.local_addr())?;
+    client.set("tenant/b".to_owned(), "x".repeat(16))?;
+    assert_eq!(
+        client.set("tenant/c".to_owned(), "x".repeat(8)),
+        Err(KvStoreError::QuotaExceeded)
+    );
+    assert_eq!(
+        client.set("tenant/b".to_owned(), "x".repeat(17)),
+        Err(KvStoreError::QuotaExceeded)
+    );
+    // Keys outside of the prefix are not counted
+    client.set("other".to_owned(), "x".repeat(100))?;
+
+    // Shrinking and removing frees space
+    client.set("tenant/b".to_owned(), "x".repeat(8))?;
+    client.set("tenant/c".to_owned(), "x".repeat(0))?;
+    client.remove("tenant/a".to_owned())?;
+    client.set("tenant/d".to_owned(), "x".repeat(8))?;
+    Ok(())
+}
+
+// An engine whose sets of "slow" take 200 ms
+#[derive(Clone)]
+struct SlowStore(MemoryKvsStore);
+
+impl KvsEngine for SlowStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        if value == "slow" {
+            thread::sleep(Duration::from_millis(200));
+        }
+        self.0.set(key, value)
+    }
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.0.get(key)
+    }
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+    fn flush(&self) -> Result<()> {
+        self.0.flush()
+    }
+    fn scan<F>(&self, f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        self.0.scan(f)
+    }
+}
+
+// Sets of different keys under a quota run concurrently, and concurrent sets
+// of a key are counted once
+#[test]
+fn limit_quotas_concurrent() -> Result<()> {
+    let options = ServerOptions {
+        quotas: vec!["tenant/=40".parse().unwrap()],
+        ..ServerOptions::default()
+    };
+    let handle = KvServer::start_with(
+        SlowStore(MemoryKvsStore::new()),
+        SharedQueueThreadPool::new(4)?,
+        "127.0.0.1:0".parse().unwrap(),
+        options,
+    )?;
+    let addr = handle.local_addr();
+
+    let start = Instant::now();
+    let slow: Vec<_> = (0..2)
+        .map(|i| {
+            thread::spawn(move || -> Result<()> {
+                KvClient::new(addr)?.set(format!("tenant/{}", i), "slow".to_owned())?;
+                KvClient::new(addr)?.remove(format!("tenant/{}", i))
+            })
+        })
+        .collect();
+    for thread in slow {
+        thread.join().unwrap()?;
+    }
+    assert!(start.elapsed() < Duration::from_millis(400));
+
+    let writers: Vec<_> = (0..4)
+        .map(|_| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvClient::new(addr)?;
+                for _ in 0..50 {
+                    client.set("tenant/a".to_owned(), "x".repeat(8))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in writers {
+        thread.join().unwrap()?;
+    }
+
+    // 16 bytes are used by "tenant/a"
+    let mut client = KvClient::new(addr)?;
+    client.set("tenant/b".to_owned(), "x".repeat(16))?;
+    assert_eq!(
+        client.set("tenant/c".to_owned(), String::new()),
+        Err(KvStoreError::QuotaExceeded)
+    );
+    Ok(())
+}
+
+#[test]
+fn limit_http_and_resp() -> Result<()> {
+    let options = ServerOptions {
+        protocol: ServerProtocol::Http,
+        connection_rate: ops(1),
+        ..ServerOptions::default()
+    };
+    let handle = start(MemoryKvsStore::new(), options)?;
+    let mut stream = TcpStream::connect(handle.local_addr())?;
+    let request = "GET /v1/keys/key1 HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(
+        request
+            .replace("\r\n\r\n", "\r\nConnection: close\r\n\r\n")
+            .as_bytes(),
+    )?;
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp)?;
+    assert!(resp.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    assert!(resp.contains("HTTP/1.1 429 Too Many Requests\r\n"));
+    assert!(resp.contains("Retry-After: 1\r\n"));
+
+    let options = ServerOptions {
+        protocol: ServerProtocol::Resp,
+        connection_rate: ops(1),
+        ..ServerOptions::default()
+    };
+    let handle = start(MemoryKvsStore::new(), options)?;
+    let mut stream = TcpStream::connect(handle.local_addr())?;
+    stream.write_all(b"GET key1\r\nGET key1\r\n")?;
+    let mut buf = [0u8; 21];
+    stream.read_exact(&mut buf)?;
+    assert_eq!(&buf, b"$-1\r\n-ERR Throttled\r\n");
+    Ok(())
+}
@@ -0,0 +1,290 @@
+use kvs::{KvStoreError, KvsEngine, LsmKvsStore, LsmOptions, Result};
+use std::fs;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use tempfile::TempDir;
+
+// Small enough to flush and compact every few hundred writes
+fn small_options() -> LsmOptions {
+    LsmOptions {
+        memtable_bytes: 1024,
+        compaction_trigger: 4,
+    }
+}
+
+fn count_tables(temp_dir: &TempDir) -> usize {
+    fs::read_dir(temp_dir.path())
+        .unwrap()
+        .filter(|entry| {
+            let path = entry.as_ref().unwrap().path();
+            path.extension().is_some_and(|ext| ext == "sst")
+        })
+        .count()
+}
+
+// Should get previously stored value
+#[test]
+fn get_stored_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = LsmKvsStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should overwrite existent value
+#[test]
+fn overwrite_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = LsmKvsStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should get `None` when getting a non-existent key
+#[test]
+fn get_non_existent_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = LsmKvsStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn remove_non_existent_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsStore::open(temp_dir.path())?;
+    assert!(store.remove("key1".to_owned()).is_err());
+    Ok(())
+}
+
+#[test]
+fn remove_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_ok());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsStore::open_with(temp_dir.path(), small_options())?;
+    let barrier = Arc::new(Barrier::new(1001));
+    for i in 0..1000 {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+            barrier.wait();
+        });
+    }
+    barrier.wait();
+
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = LsmKvsStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+// Tombstones in newer tables must hide values in older ones, until a
+// compaction drops both.
+#[test]
+fn flush_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsStore::open_with(temp_dir.path(), small_options())?;
+
+    for i in 0..200 {
+        store.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    assert!(count_tables(&temp_dir) > 0);
+    for i in (0..200).step_by(2) {
+        store.remove(format!("key{:03}", i))?;
+    }
+    for iter in 0..10 {
+        for i in (1..200).step_by(2) {
+            store.set(format!("key{:03}", i), format!("value{}-{}", i, iter))?;
+        }
+    }
+    assert!(count_tables(&temp_dir) < 4);
+
+    drop(store);
+    let store = LsmKvsStore::open_with(temp_dir.path(), small_options())?;
+    for i in 0..200 {
+        let expected = (i % 2 == 1).then(|| format!("value{}-9", i));
+        assert_eq!(store.get(format!("key{:03}", i))?, expected);
+    }
+    Ok(())
+}
+
+// Only tables of about the same size are merged, so the large old tables are
+// not rewritten by every compaction
+#[test]
+fn size_tiered_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsStore::open_with(temp_dir.path(), small_options())?;
+    let value = "v".repeat(100);
+
+    let mut i = 0;
+    let mut set = |n: usize| -> Result<()> {
+        for _ in 0..n {
+            store.set(format!("key{:05}", i), value.clone())?;
+            i += 1;
+        }
+        Ok(())
+    };
+    set(1000)?;
+    let oldest = store.stats().segments[0].name.clone();
+    let compactions = store.stats().compactions;
+    set(100)?;
+    let stats = store.stats();
+    assert!(stats.compactions > compactions);
+    assert_eq!(stats.segments[0].name, oldest);
+    // at most 3 tables in each tier, of which there are a few
+    assert!(count_tables(&temp_dir) < 10);
+
+    drop(store);
+    let store = LsmKvsStore::open_with(temp_dir.path(), small_options())?;
+    for i in 0..1100 {
+        assert_eq!(store.get(format!("key{:05}", i))?, Some(value.clone()));
+    }
+    Ok(())
+}
+
+#[test]
+fn scan_in_key_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsStore::open_with(temp_dir.path(), small_options())?;
+    for i in (0..300).rev() {
+        store.set(format!("key{:03}", i), "value".to_owned())?;
+    }
+    store.remove("key100".to_owned())?;
+
+    let mut keys = Vec::new();
+    store.scan(|key, _| {
+        keys.push(key);
+        Ok(())
+    })?;
+    let expected: Vec<String> = (0..300)
+        .filter(|i| *i != 100)
+        .map(|i| format!("key{:03}", i))
+        .collect();
+    assert_eq!(keys, expected);
+    Ok(())
+}
+
+// A table not listed in the manifest is left over from an interrupted flush
+#[test]
+fn ignore_unlisted_tables() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    fs::write(temp_dir.path().join("lsm-00000042.sst"), b"garbage")?;
+    let store = LsmKvsStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!temp_dir.path().join("lsm-00000042.sst").exists());
+    Ok(())
+}
+
+// A torn write at the end of the WAL is cut off, so that the writes after it
+// survive the next restart
+#[test]
+fn torn_wal_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // the torn write splits a two-byte character
+    let wal = temp_dir.path().join("lsm.wal");
+    let mut content = fs::read(&wal)?;
+    content.extend_from_slice(b"{\"key\":\"key2\",\"value\":\"\xc3");
+    fs::write(&wal, content)?;
+
+    let store = LsmKvsStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = LsmKvsStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A table whose footer points past the file, or at an empty bloom filter,
+// is corrupted
+#[test]
+fn corrupted_table_footer() -> Result<()> {
+    // offset in the footer of the index length, and of the bloom filter length
+    for field in [32, 16] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = LsmKvsStore::open_with(temp_dir.path(), small_options())?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        drop(store);
+
+        let table = fs::read_dir(temp_dir.path())?
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|ext| ext == "sst"))
+            .unwrap();
+        let mut content = fs::read(&table)?;
+        let pos = content.len() - field;
+        let len: u64 = if field == 32 { u64::MAX / 2 } else { 0 };
+        content[pos..pos + 8].copy_from_slice(&len.to_be_bytes());
+        fs::write(&table, content)?;
+
+        assert_eq!(
+            LsmKvsStore::open(temp_dir.path()).unwrap_err(),
+            KvStoreError::CorruptedTable
+        );
+    }
+    Ok(())
+}
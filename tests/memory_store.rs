@@ -0,0 +1,157 @@
+use kvs::{EvictionPolicy, KvsEngine, MemoryKvsStore, Result};
+use std::sync::{Arc, Barrier};
+use std::thread;
+
+// The tests of tests/kv_store.rs, without reopening the store
+
+// Should get previously stored value
+#[test]
+fn get_stored_value() -> Result<()> {
+    let store = MemoryKvsStore::new();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should overwrite existent value
+#[test]
+fn overwrite_value() -> Result<()> {
+    let store = MemoryKvsStore::new();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should get `None` when getting a non-existent key
+#[test]
+fn get_non_existent_value() -> Result<()> {
+    let store = MemoryKvsStore::new();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn remove_non_existent_key() -> Result<()> {
+    let store = MemoryKvsStore::new();
+    assert!(store.remove("key1".to_owned()).is_err());
+    Ok(())
+}
+
+#[test]
+fn remove_key() -> Result<()> {
+    let store = MemoryKvsStore::new();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_ok());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn concurrent_set() -> Result<()> {
+    let store = MemoryKvsStore::new();
+    let barrier = Arc::new(Barrier::new(1001));
+    for i in 0..1000 {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+            barrier.wait();
+        });
+    }
+    barrier.wait();
+
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_get() -> Result<()> {
+    let store = MemoryKvsStore::new();
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Ok(())
+}
+
+#[test]
+fn evict_least_recently_used() -> Result<()> {
+    let store = MemoryKvsStore::with_max_keys(3, EvictionPolicy::Lru);
+    for i in 0..3 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.get("key0".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // overwriting an existing key evicts nothing
+    store.set("key2".to_owned(), "new".to_owned())?;
+    for key in ["key0", "key2", "key3"] {
+        assert!(store.get(key.to_owned())?.is_some());
+    }
+    Ok(())
+}
+
+#[test]
+fn evict_least_frequently_used() -> Result<()> {
+    let store = MemoryKvsStore::with_max_keys(3, EvictionPolicy::Lfu);
+    for i in 0..3 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for _ in 0..3 {
+        store.get("key0".to_owned())?;
+        store.get("key2".to_owned())?;
+    }
+    store.get("key1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // key1 was used most recently, but least often
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    store.remove("key0".to_owned())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,134 @@
+#![cfg(feature = "blocking")]
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    KvClient, KvServer, KvStore, KvStoreError, KvsEngine, LsmKvsStore, LsmOptions, MemoryKvsStore,
+    Metrics, Result, ServerHandle, ServerOptions, ServerProtocol,
+};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use tempfile::TempDir;
+
+fn start<E: KvsEngine>(engine: E, options: ServerOptions) -> Result<ServerHandle> {
+    KvServer::start_with(
+        engine,
+        SharedQueueThreadPool::new(2)?,
+        "127.0.0.1:0".parse().unwrap(),
+        options,
+    )
+}
+
+// GET `path` on its own connection, returning the status and the body
+fn get(addr: SocketAddr, path: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        path
+    )
+    .unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    let (head, body) = resp.split_once("\r\n\r\n").unwrap();
+    (head[9..12].parse().unwrap(), body.to_owned())
+}
+
+#[test]
+fn metrics_listener() -> Result<()> {
+    let engine = MemoryKvsStore::new();
+    let options = ServerOptions {
+        metrics: Metrics::new(),
+        ..ServerOptions::default()
+    };
+    let handle = start(engine.clone(), options.clone())?;
+    let metrics = start(
+        engine,
+        ServerOptions {
+            protocol: ServerProtocol::Metrics,
+            ..options
+        },
+    )?;
+
+    let mut client = KvClient::new(handle.local_addr())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.get("key1".to_owned())?;
+    client.get("key2".to_owned())?;
+    assert_eq!(
+        client.remove("key2".to_owned()),
+        Err(KvStoreError::RemoveNonexistingKey)
+    );
+
+    let (status, body) = get(metrics.local_addr(), "/metrics");
+    assert_eq!(status, 200);
+    assert!(body.contains("# TYPE kvs_request_duration_seconds histogram\n"));
+    assert!(body.contains("kvs_request_duration_seconds_count{op=\"get\"} 2\n"));
+    assert!(body.contains("kvs_request_duration_seconds_bucket{op=\"set\",le=\"+Inf\"} 1\n"));
+    assert!(body.contains("kvs_request_duration_seconds_count{op=\"remove\"} 1\n"));
+    assert!(body.contains("kvs_errors_total{error=\"RemoveNonexistingKey\"} 1\n"));
+    // the keys and value sent, and "value1" read back
+    assert!(body.contains("kvs_bytes_received_total 22\n"));
+    assert!(body.contains("kvs_bytes_sent_total 6\n"));
+    assert!(body.contains("kvs_store_size_bytes 10\n"));
+    // the client, and the scrape itself
+    assert!(body.contains("kvs_connections 2\n"));
+    assert!(body.contains("kvs_thread_pool_queued 0\n"));
+
+    // Keys are not served on the metrics listener
+    assert_eq!(get(metrics.local_addr(), "/v1/keys/key1").0, 404);
+    assert_eq!(get(metrics.local_addr(), "/health").0, 200);
+    Ok(())
+}
+
+#[test]
+fn metrics_protocol_errors() -> Result<()> {
+    let options = ServerOptions::default();
+    let handle = start(MemoryKvsStore::new(), options.clone())?;
+    let http = start(
+        MemoryKvsStore::new(),
+        ServerOptions {
+            protocol: ServerProtocol::Http,
+            ..options
+        },
+    )?;
+
+    let mut stream = TcpStream::connect(handle.local_addr())?;
+    stream.write_all(b"{not json")?;
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf)?;
+
+    let (_, body) = get(http.local_addr(), "/metrics");
+    assert!(body.contains("kvs_errors_total{error=\"InvalidRequest\"} 1\n"));
+    Ok(())
+}
+
+#[test]
+fn metrics_engine_stats() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().compactions, 0);
+    for i in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    let stats = store.stats();
+    assert!(stats.compactions > 0);
+    assert!(stats.bytes_reclaimed > 0);
+    assert_eq!(
+        stats.size,
+        temp_dir.path().join("data.json").metadata()?.len()
+    );
+
+    let temp_dir = TempDir::new().unwrap();
+    let options = LsmOptions {
+        memtable_bytes: 64,
+        compaction_trigger: 2,
+    };
+    let store = LsmKvsStore::open_with(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    let stats = store.stats();
+    assert!(stats.compactions > 0);
+    assert!(stats.bytes_reclaimed > 0);
+    assert!(stats.size > 0);
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use kvs::migrate::{self, Summary};
+use kvs::{EngineKind, KvStore, KvsEngine, Result, SledKvsStore};
+use std::fs;
+use tempfile::TempDir;
+
+#[test]
+fn migrate_kvs_to_sled_and_back() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    EngineKind::Kvs.write_marker(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let summary = migrate::summarize(&store)?;
+    drop(store);
+
+    assert_eq!(
+        migrate::migrate(temp_dir.path(), EngineKind::Sled)?,
+        Some(summary)
+    );
+    assert_eq!(
+        EngineKind::current(temp_dir.path())?,
+        Some(EngineKind::Sled)
+    );
+    assert!(!temp_dir.path().join("migration").exists());
+
+    let db = SledKvsStore::open(temp_dir.path())?;
+    assert_eq!(db.get("key42".to_owned())?, Some("value42".to_owned()));
+    db.remove("key42".to_owned())?;
+    drop(db);
+
+    // migrating back must not resurrect the key left in the old kvs log
+    let summary = migrate::migrate(temp_dir.path(), EngineKind::Kvs)?.unwrap();
+    assert_eq!(summary.count, 99);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key42".to_owned())?, None);
+    assert_eq!(migrate::summarize(&store)?, summary);
+
+    // nothing to do
+    drop(store);
+    assert_eq!(migrate::migrate(temp_dir.path(), EngineKind::Kvs)?, None);
+    Ok(())
+}
+
+// An interrupted migration leaves a partial, possibly stale, copy behind
+#[test]
+fn resume_interrupted_migration() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    EngineKind::Kvs.write_marker(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let db = SledKvsStore::open(temp_dir.path())?;
+    db.set("key1".to_owned(), "value1".to_owned())?;
+    db.set("key2".to_owned(), "stale".to_owned())?;
+    db.set("gone".to_owned(), "stale".to_owned())?;
+    drop(db);
+    fs::write(temp_dir.path().join("migration"), "kvs->sled")?;
+
+    let summary = migrate::migrate(temp_dir.path(), EngineKind::Sled)?.unwrap();
+    assert_eq!(summary.count, 10);
+
+    let db = SledKvsStore::open(temp_dir.path())?;
+    assert_eq!(db.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(db.get("gone".to_owned())?, None);
+    assert_eq!(migrate::summarize(&db)?, summary);
+    Ok(())
+}
+
+#[test]
+fn copy_all_repairs_diverged_copy() -> Result<()> {
+    let src_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dst_dir = TempDir::new().expect("unable to create temporary working directory");
+    let src = KvStore::open(src_dir.path())?;
+    let dst = SledKvsStore::open(dst_dir.path())?;
+
+    assert_eq!(migrate::copy_all(&src, &dst)?, Summary::default());
+
+    src.set("key1".to_owned(), "value1".to_owned())?;
+    let summary = migrate::copy_all(&src, &dst)?;
+    assert_eq!(summary.count, 1);
+
+    // same count, different value
+    dst.set("key1".to_owned(), "value2".to_owned())?;
+    assert_ne!(migrate::summarize(&dst)?, summary);
+    assert_eq!(migrate::copy_all(&src, &dst)?, summary);
+    assert_eq!(dst.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,255 @@
+#![cfg(feature = "blocking")]
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvServer, MemoryKvsStore, Result, ServerHandle, ServerOptions, ServerProtocol};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+fn start() -> Result<ServerHandle> {
+    let options = ServerOptions {
+        protocol: ServerProtocol::Resp,
+        ..ServerOptions::default()
+    };
+    KvServer::start_with(
+        MemoryKvsStore::new(),
+        SharedQueueThreadPool::new(2)?,
+        "127.0.0.1:0".parse().unwrap(),
+        options,
+    )
+}
+
+// Send a command as an array of bulk strings
+fn send(stream: &mut TcpStream, args: &[&str]) {
+    let mut buf = format!("*{}\r\n", args.len());
+    for arg in args {
+        buf += &format!("${}\r\n{}\r\n", arg.len(), arg);
+    }
+    stream.write_all(buf.as_bytes()).unwrap();
+}
+
+fn expect(stream: &mut TcpStream, reply: &str) {
+    let mut buf = vec![0u8; reply.len()];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(String::from_utf8_lossy(&buf), reply);
+}
+
+fn call(stream: &mut TcpStream, args: &[&str], reply: &str) {
+    send(stream, args);
+    expect(stream, reply);
+}
+
+#[test]
+fn resp_commands() -> Result<()> {
+    let handle = start()?;
+    let mut stream = TcpStream::connect(handle.local_addr())?;
+
+    call(&mut stream, &["PING"], "+PONG\r\n");
+    call(&mut stream, &["ping", "hi"], "$2\r\nhi\r\n");
+    call(&mut stream, &["GET", "key1"], "$-1\r\n");
+    call(&mut stream, &["SET", "key1", "value1"], "+OK\r\n");
+    call(&mut stream, &["GET", "key1"], "$6\r\nvalue1\r\n");
+    call(&mut stream, &["SET", "key1", "value2", "NX"], "$-1\r\n");
+    call(&mut stream, &["SET", "key2", "value2", "XX"], "$-1\r\n");
+    call(
+        &mut stream,
+        &["MSET", "key2", "value2", "key3", "value3"],
+        "+OK\r\n",
+    );
+    call(
+        &mut stream,
+        &["MGET", "key1", "key2", "key4"],
+        "*3\r\n$6\r\nvalue1\r\n$6\r\nvalue2\r\n$-1\r\n",
+    );
+    call(&mut stream, &["EXISTS", "key1", "key3", "key4"], ":2\r\n");
+    call(&mut stream, &["DEL", "key1", "key4"], ":1\r\n");
+    call(&mut stream, &["EXISTS", "key1"], ":0\r\n");
+
+    // inline commands
+    stream.write_all(b"SET key5 value5\r\nGET key5\n")?;
+    expect(&mut stream, "+OK\r\n$6\r\nvalue5\r\n");
+    Ok(())
+}
+
+#[test]
+fn resp_errors() -> Result<()> {
+    let handle = start()?;
+    let mut stream = TcpStream::connect(handle.local_addr())?;
+
+    call(
+        &mut stream,
+        &["FLUSHALL"],
+        "-ERR unknown command 'flushall'\r\n",
+    );
+    call(
+        &mut stream,
+        &["GET"],
+        "-ERR wrong number of arguments for 'get' command\r\n",
+    );
+    call(
+        &mut stream,
+        &["SET", "k", "v", "EX"],
+        "-ERR invalid expire time in 'set' command\r\n",
+    );
+    call(
+        &mut stream,
+        &["SET", "k", "v", "KEEPTTL"],
+        "-ERR syntax error\r\n",
+    );
+    call(
+        &mut stream,
+        &["HELLO", "4"],
+        "-NOPROTO unsupported protocol version\r\n",
+    );
+    call(&mut stream, &["SELECT", "0"], "+OK\r\n");
+
+    // A malformed command closes the connection
+    stream.write_all(b"*1\r\n$abc\r\n")?;
+    expect(&mut stream, "-ERR Protocol error");
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest)?;
+    Ok(())
+}
+
+#[test]
+fn resp3_hello() -> Result<()> {
+    let handle = start()?;
+    let mut stream = TcpStream::connect(handle.local_addr())?;
+
+    send(&mut stream, &["HELLO", "3"]);
+    expect(&mut stream, "%6\r\n$6\r\nserver\r\n$3\r\nkvs\r\n");
+    let version = env!("CARGO_PKG_VERSION");
+    expect(
+        &mut stream,
+        &format!("$7\r\nversion\r\n${}\r\n{}\r\n", version.len(), version),
+    );
+    expect(
+        &mut stream,
+        "$5\r\nproto\r\n:3\r\n$4\r\nmode\r\n$10\r\nstandalone\r\n\
+         $4\r\nrole\r\n$6\r\nmaster\r\n$7\r\nmodules\r\n*0\r\n",
+    );
+    call(&mut stream, &["GET", "key1"], "_\r\n");
+    Ok(())
+}
+
+#[test]
+fn resp_expire() -> Result<()> {
+    let handle = start()?;
+    let mut stream = TcpStream::connect(handle.local_addr())?;
+
+    call(&mut stream, &["EXPIRE", "key1", "1"], ":0\r\n");
+    call(&mut stream, &["SET", "key1", "value1"], "+OK\r\n");
+    call(&mut stream, &["EXPIRE", "key1", "1"], ":1\r\n");
+    call(
+        &mut stream,
+        &["SET", "key2", "value2", "PX", "100"],
+        "+OK\r\n",
+    );
+    call(&mut stream, &["SET", "key3", "value3"], "+OK\r\n");
+    call(&mut stream, &["EXPIRE", "key3", "0"], ":1\r\n");
+    call(&mut stream, &["EXISTS", "key1", "key2", "key3"], ":2\r\n");
+
+    thread::sleep(Duration::from_millis(1100));
+    call(&mut stream, &["EXISTS", "key1", "key2"], ":0\r\n");
+    call(&mut stream, &["DEL", "key1"], ":0\r\n");
+
+    // a deadline past what the clock can hold is refused
+    call(&mut stream, &["SET", "key4", "value4"], "+OK\r\n");
+    call(
+        &mut stream,
+        &["EXPIRE", "key4", "9223372036854775807"],
+        "-ERR invalid expire time in 'expire' command\r\n",
+    );
+    call(
+        &mut stream,
+        &["SET", "key5", "value5", "EX", "18446744073709551615"],
+        "-ERR invalid expire time in 'set' command\r\n",
+    );
+    call(&mut stream, &["EXISTS", "key4", "key5"], ":1\r\n");
+    Ok(())
+}
+
+// Read a line without its CRLF
+fn read_line(stream: &mut TcpStream) -> String {
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+    while !line.ends_with(b"\r\n") {
+        stream.read_exact(&mut byte).unwrap();
+        line.push(byte[0]);
+    }
+    line.truncate(line.len() - 2);
+    String::from_utf8(line).unwrap()
+}
+
+// Send a SCAN and read the next cursor and the keys
+fn scan(stream: &mut TcpStream, args: &[&str]) -> (String, Vec<String>) {
+    send(stream, args);
+    assert_eq!(read_line(stream), "*2");
+    read_line(stream);
+    let cursor = read_line(stream);
+    let len = read_line(stream)[1..].parse().unwrap();
+    let keys = (0..len)
+        .map(|_| {
+            read_line(stream);
+            read_line(stream)
+        })
+        .collect();
+    (cursor, keys)
+}
+
+#[test]
+fn resp_scan() -> Result<()> {
+    let handle = start()?;
+    let mut stream = TcpStream::connect(handle.local_addr())?;
+
+    let all = ["a1", "a2", "b1", "b2", "c1"];
+    for key in all {
+        call(&mut stream, &["SET", key, "value"], "+OK\r\n");
+    }
+    let mut keys = Vec::new();
+    let mut cursor = "0".to_owned();
+    loop {
+        let (next, page) = scan(&mut stream, &["SCAN", &cursor, "COUNT", "2"]);
+        assert!(page.len() <= 2);
+        keys.extend(page);
+        if next == "0" {
+            break;
+        }
+        cursor = next;
+    }
+    keys.sort();
+    assert_eq!(keys, all);
+
+    let (cursor, mut keys) = scan(&mut stream, &["SCAN", "0", "MATCH", "?1"]);
+    keys.sort();
+    assert_eq!(cursor, "0");
+    assert_eq!(keys, ["a1", "b1", "c1"]);
+    let (_, mut keys) = scan(&mut stream, &["SCAN", "0", "MATCH", "b*"]);
+    keys.sort();
+    assert_eq!(keys, ["b1", "b2"]);
+    let (_, keys) = scan(&mut stream, &["SCAN", "0", "MATCH", "*\\*"]);
+    assert!(keys.is_empty());
+
+    // a pattern that takes exponential time to match by backtracking
+    let key = "a".repeat(100);
+    call(&mut stream, &["SET", &key, "value"], "+OK\r\n");
+    let pattern = "a*".repeat(30) + "b";
+    let (_, keys) = scan(&mut stream, &["SCAN", "0", "MATCH", &pattern]);
+    assert!(keys.is_empty());
+    let (_, keys) = scan(&mut stream, &["SCAN", "0", "MATCH", "*a?a*a\\a"]);
+    assert_eq!(keys, [key]);
+    Ok(())
+}
+
+#[test]
+fn resp_quit() -> Result<()> {
+    let handle = start()?;
+    let mut stream = TcpStream::connect(handle.local_addr())?;
+
+    call(&mut stream, &["QUIT"], "+OK\r\n");
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest)?;
+    assert!(rest.is_empty());
+    handle.shutdown(Duration::from_secs(1))
+}
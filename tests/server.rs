@@ -0,0 +1,548 @@
+#![cfg(feature = "blocking")]
+
+use kvs::thread_pool::{NaiveThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    Auth, Credentials, KvClient, KvServer, KvStore, KvStoreError, KvsEngine, ListenAddr, Listener,
+    MemoryKvsStore, OverflowPolicy, Protocol, Result, ServerOptions, ServerProtocol,
+};
+use rand::Rng;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn any_addr() -> SocketAddr {
+    "127.0.0.1:0".parse().unwrap()
+}
+
+#[test]
+fn shutdown_flushes_and_frees_port() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = KvServer::start(store, SharedQueueThreadPool::new(4)?, any_addr())?;
+    let addr = handle.local_addr();
+
+    let mut client = KvClient::new(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    handle.shutdown(Duration::from_secs(1))?;
+
+    assert!(TcpListener::bind(addr).is_ok());
+    assert!(client.get("key1".to_owned()).is_err());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Idle connections are closed right away, they do not hold up shutdown
+#[test]
+fn shutdown_with_idle_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = KvServer::start(store, SharedQueueThreadPool::new(2)?, any_addr())?;
+
+    let mut clients = Vec::new();
+    for _ in 0..2 {
+        let mut client = KvClient::new(handle.local_addr())?;
+        client.get("key1".to_owned())?;
+        clients.push(client);
+    }
+
+    let start = Instant::now();
+    handle.shutdown(Duration::from_secs(10))?;
+    assert!(start.elapsed() < Duration::from_secs(5));
+    Ok(())
+}
+
+// A request in flight when shutdown starts is still answered
+#[test]
+fn shutdown_answers_request_in_flight() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = KvServer::start(store, SharedQueueThreadPool::new(2)?, any_addr())?;
+
+    let mut stream = TcpStream::connect(handle.local_addr())?;
+    stream.write_all(b"{\"Get\":{\"key\":")?;
+    std::thread::sleep(Duration::from_millis(100));
+
+    let start = Instant::now();
+    let shutdown = std::thread::spawn(move || handle.shutdown(Duration::from_millis(500)));
+    std::thread::sleep(Duration::from_millis(100));
+    stream.write_all(b"\"key1\"}}")?;
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp)?;
+    assert_eq!(resp, "{\"Ok\":null}");
+    assert!(start.elapsed() < Duration::from_millis(500));
+    shutdown.join().unwrap()?;
+    Ok(())
+}
+
+// A connection stuck in the middle of a request is cut at the deadline
+#[test]
+fn shutdown_deadline() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = KvServer::start(store, SharedQueueThreadPool::new(2)?, any_addr())?;
+
+    let mut stream = TcpStream::connect(handle.local_addr())?;
+    stream.write_all(b"{\"Get\":{\"key\":")?;
+    std::thread::sleep(Duration::from_millis(100));
+
+    let start = Instant::now();
+    handle.shutdown(Duration::from_millis(500))?;
+    assert!(start.elapsed() >= Duration::from_millis(500));
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp)?;
+    assert!(resp.is_empty());
+    Ok(())
+}
+
+// An engine call outliving the deadline is waited for, even on a pool that
+// does not join its threads
+#[test]
+fn shutdown_waits_for_engine_call() -> Result<()> {
+    let store = MemoryKvsStore::new();
+    let handle = KvServer::start(
+        SlowStore(store.clone()),
+        NaiveThreadPool::new(1)?,
+        any_addr(),
+    )?;
+
+    let mut client = KvClient::new(handle.local_addr())?;
+    let set = std::thread::spawn(move || client.set("key1".to_owned(), "slow".to_owned()));
+    std::thread::sleep(Duration::from_millis(50));
+    handle.shutdown(Duration::from_millis(10))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("slow".to_owned()));
+    assert!(set.join().unwrap().is_err());
+    Ok(())
+}
+
+// A handler stuck in the engine does not hold up the shutdown for long
+#[test]
+fn shutdown_gives_up_on_stuck_handler() -> Result<()> {
+    let handle = KvServer::start(
+        SlowStore(MemoryKvsStore::new()),
+        NaiveThreadPool::new(1)?,
+        any_addr(),
+    )?;
+
+    let mut client = KvClient::new(handle.local_addr())?;
+    let get = std::thread::spawn(move || client.get("stuck".to_owned()));
+    std::thread::sleep(Duration::from_millis(50));
+    let start = Instant::now();
+    handle.shutdown(Duration::from_millis(10))?;
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(get.join().unwrap().is_err());
+    Ok(())
+}
+
+#[test]
+fn bind_error() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let listener = TcpListener::bind(any_addr())?;
+    let store = KvStore::open(temp_dir.path())?;
+    let res = KvServer::start(
+        store,
+        SharedQueueThreadPool::new(1)?,
+        listener.local_addr()?,
+    );
+    assert!(matches!(res, Err(KvStoreError::BindError)));
+    Ok(())
+}
+
+#[test]
+fn unix_socket() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.sock");
+    let options = ServerOptions {
+        unix_mode: Some(0o600),
+        ..ServerOptions::default()
+    };
+    let handle = KvServer::start_unix(
+        MemoryKvsStore::new(),
+        SharedQueueThreadPool::new(2)?,
+        &path,
+        options,
+    )?;
+    assert_eq!(handle.listen_addrs(), [ListenAddr::Unix(path.clone())]);
+    assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+
+    for protocol in [Protocol::Binary, Protocol::Json] {
+        let mut client = KvClient::connect_unix(&path, protocol)?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+
+    // the socket file is in use until shutdown, and removed then
+    let res = KvServer::start_unix(
+        MemoryKvsStore::new(),
+        SharedQueueThreadPool::new(1)?,
+        &path,
+        ServerOptions::default(),
+    );
+    assert!(matches!(res, Err(KvStoreError::BindError)));
+    handle.shutdown(Duration::from_secs(1))?;
+    assert!(!path.exists());
+    Ok(())
+}
+
+// A socket file left behind is replaced, but not another file
+#[test]
+fn unix_socket_left_behind() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.sock");
+    drop(UnixListener::bind(&path)?);
+    assert!(path.exists());
+    let handle = KvServer::start_unix(
+        MemoryKvsStore::new(),
+        SharedQueueThreadPool::new(1)?,
+        &path,
+        ServerOptions::default(),
+    )?;
+    KvClient::connect_unix(&path, Protocol::Binary)?.ping()?;
+    handle.shutdown(Duration::from_secs(1))?;
+
+    fs::write(&path, "data")?;
+    let res = KvServer::start_unix(
+        MemoryKvsStore::new(),
+        SharedQueueThreadPool::new(1)?,
+        &path,
+        ServerOptions::default(),
+    );
+    assert!(matches!(res, Err(KvStoreError::BindError)));
+    assert_eq!(fs::read_to_string(&path)?, "data");
+    Ok(())
+}
+
+// Each listener has its own protocol and authentication, over one engine
+#[test]
+fn multiple_listeners() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.sock");
+    let auth = Auth::from_toml(
+        r#"
+        [[token]]
+        principal = "app"
+        token = "app-token"
+
+        [[grant]]
+        principal = "app"
+        prefix = ""
+        access = "read-write"
+        "#,
+    )?;
+    let listeners = vec![
+        Listener {
+            auth: Some(auth),
+            ..Listener::new(any_addr())
+        },
+        Listener::new(ListenAddr::Unix(path.clone())),
+        Listener {
+            protocol: ServerProtocol::Http,
+            ..Listener::new(any_addr())
+        },
+    ];
+    let handle = KvServer::start_listeners(
+        MemoryKvsStore::new(),
+        SharedQueueThreadPool::new(4)?,
+        listeners,
+        ServerOptions::default(),
+    )?;
+    let addrs = handle.listen_addrs().to_vec();
+    assert_eq!(addrs.len(), 3);
+    assert_eq!(addrs[0], ListenAddr::Tcp(handle.local_addr()));
+    assert_eq!(addrs[1], ListenAddr::Unix(path.clone()));
+
+    let mut local = KvClient::connect_unix(&path, Protocol::Binary)?;
+    local.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut remote = KvClient::new(handle.local_addr())?;
+    assert!(remote.get("key1".to_owned()).is_err());
+    let mut remote = KvClient::new(handle.local_addr())?;
+    remote.authenticate(Credentials::Token("app-token".to_owned()))?;
+    assert_eq!(remote.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let http_addr = match &addrs[2] {
+        ListenAddr::Tcp(addr) => *addr,
+        addr => panic!("unexpected listener {}", addr),
+    };
+    let mut http = TcpStream::connect(http_addr)?;
+    http.write_all(b"GET /v1/keys/key1 HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+    let mut body = String::new();
+    http.read_to_string(&mut body)?;
+    assert!(body.ends_with(r#"{"key":"key1","value":"value1"}"#));
+
+    handle.shutdown(Duration::from_secs(1))?;
+    assert!(!path.exists());
+    assert!(TcpListener::bind(http_addr).is_ok());
+    Ok(())
+}
+
+// The connection limit counts the connections of all the listeners
+#[test]
+fn listeners_share_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.sock");
+    let options = ServerOptions {
+        max_connections: Some(1),
+        overflow: OverflowPolicy::Reject,
+        ..ServerOptions::default()
+    };
+    let listeners = vec![
+        Listener::new(any_addr()),
+        Listener::new(ListenAddr::Unix(path.clone())),
+    ];
+    let handle = KvServer::start_listeners(
+        MemoryKvsStore::new(),
+        SharedQueueThreadPool::new(2)?,
+        listeners,
+        options,
+    )?;
+
+    let mut client = KvClient::new(handle.local_addr())?;
+    client.ping()?;
+    let busy = KvClient::connect_unix(&path, Protocol::Binary).and_then(|mut c| c.ping());
+    assert!(busy.is_err());
+    drop(client);
+    std::thread::sleep(Duration::from_millis(100));
+    KvClient::connect_unix(&path, Protocol::Binary)?.ping()?;
+    Ok(())
+}
+
+// A request that cannot be parsed gets an error response
+#[test]
+fn invalid_request() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = KvServer::start(store, SharedQueueThreadPool::new(1)?, any_addr())?;
+
+    for req in [&b"{\"Frobnicate\":{}}"[..], b"][", b"{\"Get\":{\"key\":1}}"] {
+        let mut stream = TcpStream::connect(handle.local_addr())?;
+        stream.write_all(req)?;
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp)?;
+        assert_eq!(resp, "{\"Err\":\"InvalidRequest\"}");
+    }
+
+    handle.shutdown(Duration::from_secs(1))
+}
+
+// Garbage thrown at the server does not stop it from serving
+#[test]
+fn garbage_input() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = KvServer::start(store, SharedQueueThreadPool::new(2)?, any_addr())?;
+
+    let mut rng = rand::thread_rng();
+    for i in 0..200 {
+        let mut garbage = vec![0u8; rng.gen_range(1, 1024)];
+        rng.fill(&mut garbage[..]);
+        if i % 4 == 0 {
+            // looks like a request at first
+            garbage.splice(0..0, b"{\"Set\":{\"key\":\"".iter().copied());
+        } else if i % 4 == 1 {
+            // looks like a binary connection at first
+            garbage.splice(0..0, b"KVSB".iter().copied());
+        }
+        let mut stream = TcpStream::connect(handle.local_addr())?;
+        // the server may close the connection before reading everything
+        let _ = stream.write_all(&garbage);
+        let _ = stream.shutdown(Shutdown::Write);
+        let _ = stream.read_to_end(&mut Vec::new());
+    }
+
+    let mut client = KvClient::new(handle.local_addr())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    handle.shutdown(Duration::from_secs(1))
+}
+
+// Clients of both protocols share one port
+#[test]
+fn binary_and_json_clients() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = KvServer::start(store, SharedQueueThreadPool::new(2)?, any_addr())?;
+
+    let mut binary = KvClient::with_protocol(handle.local_addr(), Protocol::Binary)?;
+    let mut json = KvClient::with_protocol(handle.local_addr(), Protocol::Json)?;
+    binary.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(json.get("key1".to_owned())?, Some("value1".to_owned()));
+    json.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(binary.get("key2".to_owned())?, Some("value2".to_owned()));
+    binary.remove("key1".to_owned())?;
+    assert_eq!(
+        binary.remove("key1".to_owned()),
+        Err(KvStoreError::RemoveNonexistingKey)
+    );
+    assert_eq!(json.get("key1".to_owned())?, None);
+
+    handle.shutdown(Duration::from_secs(1))
+}
+
+// A client whose protocol versions the server does not speak gets an error
+#[test]
+fn binary_unsupported_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = KvServer::start(store, SharedQueueThreadPool::new(1)?, any_addr())?;
+
+    let mut stream = TcpStream::connect(handle.local_addr())?;
+    stream.write_all(b"KVSB")?;
+    // Hello frame: versions 1000 to 1000, no features
+    stream.write_all(&[0, 0, 0, 21, 0x01, 0, 0, 0, 0, 0, 0, 0, 0])?;
+    stream.write_all(&[0xe8, 0x03, 0xe8, 0x03, 0, 0, 0, 0, 0, 0, 0, 0])?;
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp)?;
+    // Error frame
+    assert_eq!(&resp[..5], &[0, 0, 0, 13, 0x7f]);
+
+    handle.shutdown(Duration::from_secs(1))
+}
+
+// A frame with an unknown opcode gets an error
+#[test]
+fn binary_invalid_frame() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = KvServer::start(store, SharedQueueThreadPool::new(1)?, any_addr())?;
+
+    let mut stream = TcpStream::connect(handle.local_addr())?;
+    stream.write_all(b"KVSB")?;
+    stream.write_all(&[0, 0, 0, 9, 0xee, 0, 0, 0, 0, 0, 0, 0, 0])?;
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp)?;
+    assert_eq!(&resp[..5], &[0, 0, 0, 13, 0x7f]);
+
+    handle.shutdown(Duration::from_secs(1))
+}
+
+// Sleeps before getting the key "slow", and before setting the value "slow".
+// Getting the key "stuck" takes much longer.
+#[derive(Clone)]
+struct SlowStore(MemoryKvsStore);
+
+impl KvsEngine for SlowStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        if value == "slow" {
+            std::thread::sleep(Duration::from_millis(200));
+        }
+        self.0.set(key, value)
+    }
+    fn get(&self, key: String) -> Result<Option<String>> {
+        if key == "slow" {
+            std::thread::sleep(Duration::from_millis(500));
+        }
+        if key == "stuck" {
+            std::thread::sleep(Duration::from_secs(10));
+        }
+        self.0.get(key)
+    }
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+    fn flush(&self) -> Result<()> {
+        self.0.flush()
+    }
+    fn scan<F>(&self, f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        self.0.scan(f)
+    }
+}
+
+#[test]
+fn pipelined_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = KvServer::start(store, SharedQueueThreadPool::new(2)?, any_addr())?;
+
+    let mut client = KvClient::new(handle.local_addr())?;
+    let mut sets = Vec::new();
+    for i in 0..100 {
+        sets.push(client.send_set(format!("key{}", i), format!("value{}", i))?);
+    }
+    for set in sets {
+        client.wait(set)?;
+    }
+    let mut gets = Vec::new();
+    for i in 0..100 {
+        gets.push((i, client.send_get(format!("key{}", i))?));
+    }
+    let removed = client.send_remove("key100".to_owned())?;
+    // wait in reverse order
+    for (i, get) in gets.into_iter().rev() {
+        assert_eq!(client.wait(get)?, Some(format!("value{}", i)));
+    }
+    assert_eq!(
+        client.wait(removed),
+        Err(KvStoreError::RemoveNonexistingKey)
+    );
+
+    handle.shutdown(Duration::from_secs(1))
+}
+
+// A slow request does not hold up the responses of the next ones
+#[test]
+fn pipelined_out_of_order() -> Result<()> {
+    let handle = KvServer::start(
+        SlowStore(MemoryKvsStore::new()),
+        SharedQueueThreadPool::new(2)?,
+        any_addr(),
+    )?;
+
+    let mut client = KvClient::new(handle.local_addr())?;
+    client.set("slow".to_owned(), "value1".to_owned())?;
+    client.set("fast".to_owned(), "value2".to_owned())?;
+
+    let start = Instant::now();
+    let slow = client.send_get("slow".to_owned())?;
+    let fast = client.send_get("fast".to_owned())?;
+    assert_eq!(client.wait(fast)?, Some("value2".to_owned()));
+    assert!(start.elapsed() < Duration::from_millis(400));
+    assert_eq!(client.wait(slow)?, Some("value1".to_owned()));
+
+    handle.shutdown(Duration::from_secs(1))
+}
+
+// Pipelined requests on the same key apply in the order they were sent,
+// even when the first one is slow
+#[test]
+fn pipelined_same_key() -> Result<()> {
+    let handle = KvServer::start(
+        SlowStore(MemoryKvsStore::new()),
+        SharedQueueThreadPool::new(2)?,
+        any_addr(),
+    )?;
+
+    let mut client = KvClient::new(handle.local_addr())?;
+    let first = client.send_set("key1".to_owned(), "slow".to_owned())?;
+    let second = client.send_set("key1".to_owned(), "fast".to_owned())?;
+    let get = client.send_get("key1".to_owned())?;
+    assert_eq!(client.wait(get)?, Some("fast".to_owned()));
+    client.wait(second)?;
+    client.wait(first)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("fast".to_owned()));
+
+    handle.shutdown(Duration::from_secs(1))
+}
+
+// JSON has no request ids, but the same API works
+#[test]
+fn pipelined_json() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = KvServer::start(store, SharedQueueThreadPool::new(1)?, any_addr())?;
+
+    let mut client = KvClient::with_protocol(handle.local_addr(), Protocol::Json)?;
+    let set = client.send_set("key1".to_owned(), "value1".to_owned())?;
+    let get = client.send_get("key1".to_owned())?;
+    assert_eq!(client.wait(get)?, Some("value1".to_owned()));
+    client.wait(set)?;
+
+    handle.shutdown(Duration::from_secs(1))
+}
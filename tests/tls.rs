@@ -0,0 +1,257 @@
+#![cfg(feature = "blocking")]
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    ClientTls, KvClient, KvServer, KvStoreError, MemoryKvsStore, Protocol, Result, ServerHandle,
+    ServerOptions, ServerTls,
+};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+// A certificate and its private key, in PEM
+struct Identity {
+    cert: String,
+    key: String,
+}
+
+// A CA, with a server certificate for localhost and 127.0.0.1 and a client
+// certificate it signed. Generated for each test.
+struct Pki {
+    ca: String,
+    server: Identity,
+    client: Identity,
+}
+
+impl Pki {
+    fn new() -> Self {
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = params.self_signed(&ca_key).unwrap();
+
+        let server = issue(
+            &["localhost", "127.0.0.1"],
+            ExtendedKeyUsagePurpose::ServerAuth,
+            &ca,
+            &ca_key,
+        );
+        let client = issue(
+            &["client"],
+            ExtendedKeyUsagePurpose::ClientAuth,
+            &ca,
+            &ca_key,
+        );
+        Pki {
+            ca: ca.pem(),
+            server,
+            client,
+        }
+    }
+
+    fn server_tls(&self, mutual: bool) -> ServerTls {
+        let client_ca = if mutual {
+            Some(self.ca.as_bytes())
+        } else {
+            None
+        };
+        ServerTls::from_pem(
+            self.server.cert.as_bytes(),
+            self.server.key.as_bytes(),
+            client_ca,
+        )
+        .unwrap()
+    }
+
+    fn client_tls(&self, with_identity: bool) -> ClientTls {
+        let identity = if with_identity {
+            Some((self.client.cert.as_bytes(), self.client.key.as_bytes()))
+        } else {
+            None
+        };
+        ClientTls::from_pem(self.ca.as_bytes(), identity).unwrap()
+    }
+}
+
+fn issue(
+    names: &[&str],
+    usage: ExtendedKeyUsagePurpose,
+    ca: &Certificate,
+    ca_key: &KeyPair,
+) -> Identity {
+    let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+    let mut params = CertificateParams::new(names).unwrap();
+    params.extended_key_usages = vec![usage];
+    let key = KeyPair::generate().unwrap();
+    let cert = params.signed_by(&key, ca, ca_key).unwrap();
+    Identity {
+        cert: cert.pem(),
+        key: key.serialize_pem(),
+    }
+}
+
+fn start(tls: ServerTls) -> Result<ServerHandle> {
+    let options = ServerOptions {
+        tls: Some(tls),
+        ..ServerOptions::default()
+    };
+    KvServer::start_with(
+        MemoryKvsStore::new(),
+        SharedQueueThreadPool::new(4)?,
+        "127.0.0.1:0".parse().unwrap(),
+        options,
+    )
+}
+
+#[test]
+fn tls_binary_and_json() -> Result<()> {
+    let pki = Pki::new();
+    let handle = start(pki.server_tls(false))?;
+    let tls = pki.client_tls(false);
+
+    let mut client = KvClient::with_tls(handle.local_addr(), Protocol::Binary, &tls)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let mut client = KvClient::with_tls(handle.local_addr(), Protocol::Json, &tls)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(
+        client.remove("key1".to_owned()),
+        Err(KvStoreError::RemoveNonexistingKey)
+    );
+
+    handle.shutdown(std::time::Duration::from_secs(1))
+}
+
+#[test]
+fn tls_pipelined_requests() -> Result<()> {
+    let pki = Pki::new();
+    let handle = start(pki.server_tls(false))?;
+    let mut client = KvClient::with_tls(
+        handle.local_addr(),
+        Protocol::Binary,
+        &pki.client_tls(false),
+    )?;
+
+    // Responses are written while the next requests are read
+    let sets = (0..200)
+        .map(|i| client.send_set(format!("key{}", i), "x".repeat(i * 100)))
+        .collect::<Result<Vec<_>>>()?;
+    for set in sets {
+        client.wait(set)?;
+    }
+    let gets = (0..200)
+        .map(|i| client.send_get(format!("key{}", i)))
+        .collect::<Result<Vec<_>>>()?;
+    for (i, get) in gets.into_iter().enumerate() {
+        assert_eq!(client.wait(get)?, Some("x".repeat(i * 100)));
+    }
+    Ok(())
+}
+
+#[test]
+fn tls_mutual() -> Result<()> {
+    let pki = Pki::new();
+    let handle = start(pki.server_tls(true))?;
+
+    let mut client =
+        KvClient::with_tls(handle.local_addr(), Protocol::Binary, &pki.client_tls(true))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // The server rejects clients without a certificate. With TLS 1.3, the
+    // client learns it after its side of the handshake.
+    let result = KvClient::with_tls(
+        handle.local_addr(),
+        Protocol::Binary,
+        &pki.client_tls(false),
+    )
+    .and_then(|mut client| client.get("key1".to_owned()));
+    assert!(result.is_err());
+
+    // and clients whose certificate another CA signed
+    let other = Pki::new();
+    let tls = ClientTls::from_pem(
+        pki.ca.as_bytes(),
+        Some((other.client.cert.as_bytes(), other.client.key.as_bytes())),
+    )?;
+    let result = KvClient::with_tls(handle.local_addr(), Protocol::Binary, &tls)
+        .and_then(|mut client| client.get("key1".to_owned()));
+    assert!(result.is_err());
+    Ok(())
+}
+
+#[test]
+fn tls_untrusted_server() -> Result<()> {
+    let pki = Pki::new();
+    let handle = start(pki.server_tls(false))?;
+
+    let other = Pki::new();
+    assert!(matches!(
+        KvClient::with_tls(
+            handle.local_addr(),
+            Protocol::Binary,
+            &other.client_tls(false)
+        ),
+        Err(KvStoreError::TlsError)
+    ));
+    Ok(())
+}
+
+#[test]
+fn tls_server_name() -> Result<()> {
+    let pki = Pki::new();
+    let handle = start(pki.server_tls(false))?;
+
+    let tls = pki.client_tls(false).with_server_name("localhost")?;
+    let mut client = KvClient::with_tls(handle.local_addr(), Protocol::Binary, &tls)?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    let tls = pki.client_tls(false).with_server_name("kvs.example.com")?;
+    assert!(matches!(
+        KvClient::with_tls(handle.local_addr(), Protocol::Binary, &tls),
+        Err(KvStoreError::TlsError)
+    ));
+    Ok(())
+}
+
+#[test]
+fn tls_rejects_plaintext() -> Result<()> {
+    let pki = Pki::new();
+    let handle = start(pki.server_tls(false))?;
+
+    let mut stream = TcpStream::connect(handle.local_addr())?;
+    stream.write_all(br#"{"Get":{"key":"key1"}}"#)?;
+    let mut buf = Vec::new();
+    let _ = stream.read_to_end(&mut buf);
+    assert!(!buf.starts_with(b"{"));
+
+    // The server keeps serving TLS clients
+    let mut client = KvClient::with_tls(
+        handle.local_addr(),
+        Protocol::Binary,
+        &pki.client_tls(false),
+    )?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn tls_invalid_pem() {
+    let pki = Pki::new();
+    assert!(matches!(
+        ServerTls::from_pem(b"not a certificate", pki.server.key.as_bytes(), None),
+        Err(KvStoreError::TlsError)
+    ));
+    assert!(matches!(
+        ServerTls::from_pem(pki.server.cert.as_bytes(), b"", None),
+        Err(KvStoreError::TlsError)
+    ));
+    assert!(matches!(
+        ClientTls::from_pem(b"", None),
+        Err(KvStoreError::TlsError)
+    ));
+}